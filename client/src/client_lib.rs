@@ -1,97 +1,952 @@
-use std::{net::SocketAddr, path::Path};
+use std::{
+    collections::{hash_map::Entry, HashMap, HashSet},
+    fmt::Display,
+    io::{self, SeekFrom},
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::{Duration, Instant},
+};
 
-use actix::{Actor, Addr, WrapFuture};
-use common::Transfer;
-use log::info;
+use bytes::Bytes;
+use common::{
+    codec::{CodecError, FrameDecoder},
+    crypto::{self, CryptoError, KeyBundle, Keys},
+    is_room,
+    protocol::{AckStatus, ErrorCode, Frame},
+    Error, FileOffer, HistoryEntry, Presence, Signal, SignalKind, Transfer,
+};
+use futures::Stream;
+use log::{info, warn};
 use s2n_quic::{
     client::Connect,
+    connection::Handle,
+    provider::tls,
     stream::{BidirectionalStream, SendStream},
     Client, Connection,
 };
+use tokio::{
+    io::{AsyncReadExt, AsyncSeekExt},
+    sync::{mpsc, oneshot, Mutex},
+};
+use ulid::Ulid;
+
+use crate::client_listen::{ClientListen, Command};
+
+/// how long `ChatClient` waits for the server to answer a request
+pub const REPLY_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// reconnecting gives up after this many failed attempts
+pub const RECONNECT_ATTEMPTS: u32 = 6;
+/// wait before the first reconnect attempt, doubled after every failed one
+pub const RECONNECT_BACKOFF: Duration = Duration::from_millis(500);
+pub const MAX_RECONNECT_BACKOFF: Duration = Duration::from_secs(30);
 
-use crate::client_listen::ClientListen;
+/// the keys of an email are asked for again after this, so new devices of theirs are sealed to
+pub const KEYS_TTL: Duration = Duration::from_secs(60);
+
+/// files are sent in pieces of this size
+pub const FILE_CHUNK: usize = 64 * 1024;
+/// where received files are saved unless `InitClient::download_to` says otherwise
+pub const DEFAULT_DOWNLOAD_DIR: &str = "downloads";
 
 /// representing a Client that connected but not logged in
 pub struct InitClient {
+    connector: Connector,
     _client: Client,
     _connection: Connection,
     stream: BidirectionalStream,
     email: String,
+    /// `None` when reconnecting, the events go on to the receiver of the lost connection
+    events: Option<mpsc::UnboundedReceiver<ClientEvent>>,
+}
+
+/// what the server sends without being asked, see `ChatClient::events`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ClientEvent {
+    /// a message to us, to a room we are in, or sent from another of our devices,
+    /// `text` is opened already if it was sealed
+    Message {
+        id: Ulid,
+        from: String,
+        to: String,
+        text: String,
+    },
+    /// an ack that came after `REPLY_TIMEOUT`, nobody waited for it any more
+    Ack {
+        id: Ulid,
+        status: AckStatus,
+    },
+    Presence {
+        email: String,
+        presence: Presence,
+    },
+    /// `from` started or stopped typing in `conversation`, a room or our email
+    Typing {
+        from: String,
+        conversation: String,
+        typing: bool,
+    },
+    /// `from` was shown the message `id`
+    Read {
+        from: String,
+        id: Ulid,
+    },
+    /// `email` sealed a message with an identity key it did not use before,
+    /// a new device of theirs, or someone else
+    IdentityChanged {
+        email: String,
+        fingerprint: String,
+    },
+    /// someone sends us a file, it is accepted and downloaded on its own
+    FileOffered(FileOffer),
+    /// told every tenth of the file
+    FileProgress {
+        id: Ulid,
+        received: u64,
+        size: u64,
+    },
+    FileSaved {
+        id: Ulid,
+        path: PathBuf,
+    },
+    FileFailed {
+        id: Ulid,
+        reason: String,
+    },
+    /// the server's operators say something to everyone
+    Notice {
+        text: String,
+    },
+    /// the server reported something not answering a request
+    Error {
+        code: ErrorCode,
+        msg: String,
+    },
+    /// the connection was lost, `reason` is given when the server shut down.
+    /// the next request reconnects
+    Disconnected {
+        reason: Option<String>,
+    },
+}
+
+/// certificate and private key the client authenticates itself with
+#[derive(Clone)]
+pub struct ClientIdentity {
+    pub certificate: String,
+    pub key: String,
+}
+
+/// everything needed to connect to the server again
+#[derive(Clone)]
+pub(crate) struct Connector {
+    certificate: String,
+    server_addr: SocketAddr,
+    identity: Option<ClientIdentity>,
+    pub download_dir: PathBuf,
+    pub events: mpsc::UnboundedSender<ClientEvent>,
+    /// messages to others who published keys are sealed with these, see `common::crypto`
+    pub keys: Option<Arc<Keys>>,
+}
+
+impl Connector {
+    async fn connect(
+        &self,
+    ) -> Result<(Client, Connection, BidirectionalStream), Box<dyn std::error::Error>> {
+        let builder = Client::builder().with_io("0.0.0.0:0")?;
+        let client = match &self.identity {
+            Some(identity) => {
+                let tls = tls::default::Client::builder()
+                    .with_certificate(Path::new(&self.certificate))?
+                    .with_client_identity(
+                        Path::new(&identity.certificate),
+                        Path::new(&identity.key),
+                    )?
+                    .build()?;
+                builder.with_tls(tls)?.start()?
+            }
+            None => builder.with_tls(Path::new(&self.certificate))?.start()?,
+        };
+
+        let connect = Connect::new(self.server_addr).with_server_name("localhost");
+        let mut connection = client.connect(connect).await?;
+
+        connection.keep_alive(true)?;
+
+        let stream = connection.open_bidirectional_stream().await?;
+
+        Ok((client, connection, stream))
+    }
 }
 
 impl InitClient {
+    /// `certificate` is the one the server is trusted by, `identity` is needed
+    /// when the server requires client certificates
     pub async fn new(
         certificate: String,
         server_addr: SocketAddr,
+        identity: Option<ClientIdentity>,
     ) -> Result<Self, Box<dyn std::error::Error>> {
-        let client = Client::builder()
-            .with_tls(Path::new(&certificate))?
-            .with_io("0.0.0.0:0")?
-            .start()?;
+        let (events, events_rx) = mpsc::unbounded_channel();
+        let mut client = Self::connect(Connector {
+            certificate,
+            server_addr,
+            identity,
+            download_dir: DEFAULT_DOWNLOAD_DIR.into(),
+            events,
+            keys: None,
+        })
+        .await?;
+        client.events = Some(events_rx);
 
-        let connect = Connect::new(server_addr).with_server_name("localhost");
-        let mut connection = client.connect(connect).await?;
+        Ok(client)
+    }
 
-        connection.keep_alive(true)?;
+    /// encrypt messages end-to-end with `keys`, they are published when logging in
+    pub fn encrypt_with(mut self, keys: Keys) -> Self {
+        self.connector.keys = Some(Arc::new(keys));
+        self
+    }
 
-        let stream = connection.open_bidirectional_stream().await?;
+    /// save the files other clients send to `dir`
+    pub fn download_to(mut self, dir: impl Into<PathBuf>) -> Self {
+        self.connector.download_dir = dir.into();
+        self
+    }
+
+    async fn connect(connector: Connector) -> Result<Self, Box<dyn std::error::Error>> {
+        let (client, connection, stream) = connector.connect().await?;
 
-        // stream.send("data".into()).await.expect("send data failed");
         Ok(Self {
+            connector,
             _client: client,
             _connection: connection,
             stream,
             email: Default::default(),
+            events: None,
         })
     }
 
     pub async fn login(
-        mut self,
+        self,
+        email: String,
+        password: String,
+    ) -> Result<ChatClient, Box<dyn std::error::Error>> {
+        self.authenticate(Frame::Login { email, password }).await
+    }
+
+    /// create the account, and log in with it
+    pub async fn register(
+        self,
         email: String,
-    ) -> Result<LoggedInClient, Box<dyn std::error::Error>> {
-        self.stream.send(email.clone().into()).await?;
-        self.stream.flush().await.unwrap();
-        info!("sent email change");
-        self.email = email;
+        password: String,
+    ) -> Result<ChatClient, Box<dyn std::error::Error>> {
+        self.authenticate(Frame::Register { email, password }).await
+    }
+
+    /// log in as the identity of the client certificate given to `new`
+    pub async fn login_with_certificate(self) -> Result<ChatClient, Box<dyn std::error::Error>> {
+        self.authenticate(Frame::CertificateLogin).await
+    }
+
+    /// continue the identity a lost connection was logged in as, with the token
+    /// `ChatClient::resume_token` returned, messages sent to it meanwhile follow
+    pub async fn resume(self, token: String) -> Result<ChatClient, Box<dyn std::error::Error>> {
+        self.authenticate(Frame::Resume { token }).await
+    }
+
+    async fn authenticate(
+        mut self,
+        frame: Frame,
+    ) -> Result<ChatClient, Box<dyn std::error::Error>> {
+        self.stream.send(frame.to_bytes()?).await?;
+        self.stream.flush().await?;
+        info!("sent credentials");
+
+        let mut decoder = FrameDecoder::new();
+        let token;
+        let reply = loop {
+            if let Some(frame) = decoder.next_frame()? {
+                break Frame::try_from(frame)?;
+            }
+            match self.stream.receive().await? {
+                Some(bytes) => decoder.extend(&bytes),
+                None => return Err(ClientError::ConnectionClosed.into()),
+            }
+        };
+
+        match reply {
+            Frame::LoginOk { email, token: t } => {
+                info!("login accepted as {}", email);
+                self.email = email;
+                token = t;
+            }
+            Frame::LoginRejected { reason } => {
+                return Err(ClientError::LoginRejected(reason).into())
+            }
+            // refused before the credentials were looked at, like an email no account can have
+            Frame::Error { msg, .. } => return Err(ClientError::LoginRejected(msg).into()),
+            frame => return Err(Error::UnexpectedFrame(frame.name()).into()),
+        }
 
         let (receiver, sender) = self.stream.split();
+        let (handle, acceptor) = self._connection.split();
 
-        let client_listen = ClientListen::new(receiver, self.email.clone()).start();
+        let control = Arc::new(Mutex::new(sender));
 
-        Ok(LoggedInClient {
+        let listen = ClientListen::start(
+            receiver,
+            decoder,
+            acceptor,
+            control.clone(),
+            self.email.clone(),
+            &self.connector,
+        );
+
+        let mut client = ChatClient {
+            connector: self.connector,
             _client: self._client,
-            _connection: self._connection,
+            handle,
+            conversations: HashMap::new(),
             email: self.email,
-            send_stream: sender,
-            _session_addr: client_listen,
-        })
+            token,
+            control,
+            listen,
+            contacts: vec![],
+            presence: Presence::Online,
+            signals: None,
+            events: self.events,
+            bundles: HashMap::new(),
+            nicks: HashMap::new(),
+        };
+
+        if let Some(keys) = client.connector.keys.clone() {
+            client.publish_keys(keys.bundle()).await?;
+        }
+        Ok(client)
     }
 }
 
-pub struct LoggedInClient {
+/// a logged in client, it needs a tokio runtime but no actix system.
+/// what the server sends without being asked comes as `events`
+pub struct ChatClient {
+    connector: Connector,
     _client: Client,
-    _connection: Connection,
-    send_stream: SendStream,
+    /// opens the conversation streams
+    handle: Handle,
+    /// the control stream, `ClientListen` answers file offers on it
+    control: Arc<Mutex<SendStream>>,
+    /// chat is sent on a stream per conversation, opened on first use
+    conversations: HashMap<String, SendStream>,
     email: String,
-    _session_addr: Addr<ClientListen>,
+    /// resumes this identity once, replaced on every reconnect
+    token: String,
+    /// the task reading what the server sends, it ends when this is dropped
+    listen: mpsc::UnboundedSender<Command>,
+    /// whose presence is followed, followed again after reconnecting
+    contacts: Vec<String>,
+    /// what the user set, set again after reconnecting
+    presence: Presence,
+    /// typing and read receipts are sent on a stream of their own, opened on first use
+    signals: Option<SendStream>,
+    events: Option<mpsc::UnboundedReceiver<ClientEvent>>,
+    /// the keys of every device of the emails messages were sealed to, and when they were asked for
+    bundles: HashMap<String, (Instant, Vec<KeyBundle>)>,
+    /// what the user calls others, by email, only ever known here
+    nicks: HashMap<String, String>,
 }
 
-impl LoggedInClient {
-    pub async fn say(
+impl ChatClient {
+    /// the identity the server logged us in as
+    pub fn email(&self) -> &str {
+        &self.email
+    }
+
+    /// everything the server sends without being asked, across reconnects, can be taken once
+    pub fn events(&mut self) -> Option<impl Stream<Item = ClientEvent>> {
+        let mut events = self.events.take()?;
+        Some(async_stream::stream! {
+            while let Some(event) = events.recv().await {
+                yield event;
+            }
+        })
+    }
+
+    /// call `email` `nick` from now on, an empty `nick` forgets it.
+    /// nobody but this client knows of it
+    pub fn set_nick(&mut self, email: String, nick: String) {
+        match nick.is_empty() {
+            true => self.nicks.remove(&email),
+            false => self.nicks.insert(email, nick),
+        };
+    }
+
+    /// what the user calls `email`, the email itself unless it was given a nick
+    pub fn nick<'a>(&'a self, email: &'a str) -> &'a str {
+        self.nicks.get(email).map_or(email, String::as_str)
+    }
+
+    /// the email of whoever is called `name`, or `name` itself if nobody is
+    pub fn resolve(&self, name: &str) -> String {
+        self.nicks
+            .iter()
+            .find(|(_, nick)| *nick == name)
+            .map_or(name, |(email, _)| email.as_str())
+            .to_string()
+    }
+
+    /// connect again and resume the identity, waiting longer after every failed attempt,
+    /// done by requests on their own when they find the connection lost
+    pub async fn reconnect(&mut self) -> Result<(), ClientError> {
+        let mut backoff = RECONNECT_BACKOFF;
+
+        for attempt in 1..=RECONNECT_ATTEMPTS {
+            tokio::time::sleep(backoff).await;
+            info!("reconnect attempt {} as {}", attempt, self.email);
+
+            let resumed = match InitClient::connect(self.connector.clone()).await {
+                Ok(client) => client.resume(self.token.clone()).await,
+                Err(e) => Err(e),
+            };
+
+            match resumed {
+                Ok(resumed) => {
+                    info!("resumed as {}", resumed.email);
+                    let contacts = std::mem::take(&mut self.contacts);
+                    let presence = self.presence;
+                    let events = self.events.take();
+                    let nicks = std::mem::take(&mut self.nicks);
+                    *self = resumed;
+                    self.events = events;
+                    self.nicks = nicks;
+                    self.restore_presence(contacts, presence).await;
+                    return Ok(());
+                }
+                Err(e) => match e.downcast::<ClientError>() {
+                    // the token is used up or expired, retrying will not help
+                    Ok(e) if matches!(*e, ClientError::LoginRejected(_)) => return Err(*e),
+                    Ok(e) => warn!("reconnect attempt {} failed: {}", attempt, e),
+                    Err(e) => warn!("reconnect attempt {} failed: {}", attempt, e),
+                },
+            }
+
+            backoff = (backoff * 2).min(MAX_RECONNECT_BACKOFF);
+        }
+
+        Err(ClientError::ConnectionClosed)
+    }
+
+    /// send a message to an email or a room, resolves once the server acked
+    /// its delivery or `REPLY_TIMEOUT` expired
+    /// the message is sealed end-to-end when encrypting, and not sent if someone has no keys
+    pub async fn send(&mut self, to: String, content: String) -> Result<Ulid, ClientError> {
+        let id = Ulid::new();
+        let conversation = to.clone();
+        let content = self.seal(id, &to, content.into_bytes()).await?;
+        let frame = Frame::Chat(Transfer {
+            id,
+            from: self.email.clone(),
+            to,
+            content,
+        });
+
+        match self.request_on(Some(&conversation), id, frame).await? {
+            Frame::Ack {
+                status: AckStatus::Delivered | AckStatus::Queued,
+                ..
+            } => Ok(id),
+            Frame::Ack {
+                status: AckStatus::Failed(code),
+                ..
+            } => Err(ClientError::NotDelivered(code)),
+            frame => Err(ClientError::from(Error::UnexpectedFrame(frame.name()))),
+        }
+    }
+
+    /// seal for every device of `to`, or of every member of the room `to`, and of ourselves,
+    /// so our own history can be read back on any of them. leave as is when not encrypting,
+    /// refuse when someone has no keys rather than send in plaintext
+    async fn seal(&mut self, id: Ulid, to: &str, content: Vec<u8>) -> Result<Bytes, ClientError> {
+        let Some(keys) = self.connector.keys.clone() else {
+            return Ok(content.into());
+        };
+        let mut peers = match is_room(to) {
+            true => self.members(to.to_string()).await?,
+            false => vec![to.to_string()],
+        };
+        peers.retain(|peer| *peer != self.email);
+
+        let mut recipients = vec![keys.bundle()];
+        for peer in peers {
+            let bundles = self.peer_keys(peer.clone()).await?;
+            if bundles.is_empty() {
+                return Err(ClientError::NoKeys(peer));
+            }
+            recipients.extend(bundles);
+        }
+        recipients.extend(self.peer_keys(self.email.clone()).await?);
+        // the keys of this device as they are now, and every device once
+        let mut devices = HashSet::new();
+        recipients.retain(|bundle| devices.insert(bundle.identity));
+
+        let aad = crypto::associated_data(id, &self.email, to);
+        crypto::seal(&keys, &recipients, &aad, &content).map_err(|e| Error::from(e).into())
+    }
+
+    /// the keys of every device of `email`, asked for again after `KEYS_TTL`
+    /// so new devices are sealed to, and every time while there are none
+    pub async fn peer_keys(&mut self, email: String) -> Result<Vec<KeyBundle>, ClientError> {
+        if let Some((asked, bundles)) = self.bundles.get(&email) {
+            if asked.elapsed() < KEYS_TTL {
+                return Ok(bundles.clone());
+            }
+        }
+
+        let id = Ulid::new();
+        match self
+            .request(
+                id,
+                Frame::KeysRequest {
+                    id,
+                    email: email.clone(),
+                },
+            )
+            .await?
+        {
+            Frame::Keys { bundles, .. } => {
+                if !bundles.is_empty() {
+                    self.bundles
+                        .insert(email, (Instant::now(), bundles.clone()));
+                }
+                Ok(bundles)
+            }
+            frame => Err(ClientError::from(Error::UnexpectedFrame(frame.name()))),
+        }
+    }
+
+    /// the text of a message, opened if it was sealed
+    pub fn read(&self, transfer: &Transfer) -> String {
+        match open_content(self.connector.keys.as_deref(), transfer) {
+            Ok((text, _)) => text,
+            Err(e) => format!("[encrypted, {}]", e),
+        }
+    }
+
+    async fn publish_keys(&mut self, bundle: KeyBundle) -> Result<(), ClientError> {
+        let id = Ulid::new();
+        // no reconnecting, it is part of logging in
+        match self
+            .try_request(None, id, &Frame::KeysPublish { id, bundle })
+            .await?
+        {
+            Frame::Ack {
+                status: AckStatus::Delivered | AckStatus::Queued,
+                ..
+            } => Ok(()),
+            Frame::Ack {
+                status: AckStatus::Failed(code),
+                ..
+            } => Err(ClientError::NotDelivered(code)),
+            frame => Err(ClientError::from(Error::UnexpectedFrame(frame.name()))),
+        }
+    }
+
+    /// up to `limit` messages with `peer` sent before the message `before`,
+    /// or the latest ones when `before` is `None`, oldest first
+    pub async fn history(
+        &mut self,
+        peer: String,
+        before: Option<Ulid>,
+        limit: u32,
+    ) -> Result<Vec<HistoryEntry>, ClientError> {
+        let id = Ulid::new();
+        let frame = Frame::HistoryRequest {
+            id,
+            peer,
+            before,
+            limit,
+        };
+
+        match self.request(id, frame).await? {
+            Frame::History { entries, .. } => Ok(entries),
+            frame => Err(ClientError::from(Error::UnexpectedFrame(frame.name()))),
+        }
+    }
+
+    /// join `room`, it is created if nobody is in it, resolves to its members
+    pub async fn join(&mut self, room: String) -> Result<Vec<String>, ClientError> {
+        let id = Ulid::new();
+        self.room_request(id, Frame::RoomJoin { id, room }).await
+    }
+
+    /// leave `room`, resolves to the members left
+    pub async fn leave(&mut self, room: String) -> Result<Vec<String>, ClientError> {
+        let id = Ulid::new();
+        self.room_request(id, Frame::RoomLeave { id, room }).await
+    }
+
+    pub async fn members(&mut self, room: String) -> Result<Vec<String>, ClientError> {
+        let id = Ulid::new();
+        self.room_request(id, Frame::RoomMembersRequest { id, room })
+            .await
+    }
+
+    /// tell an email or a room we are typing, or that we read a message of theirs.
+    /// signals are not worth waiting for, one that fails is not sent again
+    pub async fn signal(&mut self, to: String, kind: SignalKind) -> Result<(), ClientError> {
+        let frame = Frame::Signal(Signal {
+            from: self.email.clone(),
+            to,
+            kind,
+        });
+
+        let stream = match &mut self.signals {
+            Some(stream) => stream,
+            None => self.signals.insert(self.handle.open_send_stream().await?),
+        };
+        if let Err(e) = stream.send(frame.to_bytes()?).await {
+            self.signals = None;
+            return Err(e.into());
+        }
+        Ok(())
+    }
+
+    /// tell the contacts following us whether we are around, `Offline` counts as `Away`
+    pub async fn set_presence(&mut self, presence: Presence) -> Result<(), ClientError> {
+        self.presence = presence;
+        let frame = Frame::PresenceSet { status: presence };
+
+        match self.send_control(&frame).await {
+            Err(ClientError::Stream(_) | ClientError::Connection(_)) => {
+                warn!("connection lost, reconnecting");
+                // sets the presence again once resumed
+                self.reconnect().await
+            }
+            res => res,
+        }
+    }
+
+    /// everyone logged in, by email
+    pub async fn who_online(&mut self) -> Result<Vec<(String, Presence)>, ClientError> {
+        let id = Ulid::new();
+        self.presence_request(id, Frame::WhoOnlineRequest { id })
+            .await
+    }
+
+    /// follow the presence of `contacts` instead of those followed before,
+    /// their changes come as `ClientEvent::Presence`, resolves to their presence now
+    pub async fn subscribe_presence(
+        &mut self,
+        contacts: Vec<String>,
+    ) -> Result<Vec<(String, Presence)>, ClientError> {
+        self.contacts = contacts.clone();
+        let id = Ulid::new();
+        self.presence_request(id, Frame::PresenceSubscribe { id, contacts })
+            .await
+    }
+
+    /// after reconnecting the server knows nothing of what the lost session set
+    async fn restore_presence(&mut self, contacts: Vec<String>, presence: Presence) {
+        if presence != Presence::Online {
+            self.presence = presence;
+            if let Err(e) = self
+                .send_control(&Frame::PresenceSet { status: presence })
+                .await
+            {
+                warn!("set presence again failed: {}", e);
+            }
+        }
+
+        if !contacts.is_empty() {
+            self.contacts = contacts.clone();
+            let id = Ulid::new();
+            let frame = Frame::PresenceSubscribe { id, contacts };
+            if let Err(e) = self.try_request(None, id, &frame).await {
+                warn!("follow contacts again failed: {}", e);
+            }
+        }
+    }
+
+    async fn presence_request(
+        &mut self,
+        id: Ulid,
+        frame: Frame,
+    ) -> Result<Vec<(String, Presence)>, ClientError> {
+        match self.request(id, frame).await? {
+            Frame::Presences { entries, .. } => Ok(entries),
+            frame => Err(ClientError::from(Error::UnexpectedFrame(frame.name()))),
+        }
+    }
+
+    /// send the file at `path` to an email or a room on a stream of its own,
+    /// `progress` is called with the bytes sent so far and the size.
+    /// when the connection is lost it reconnects and goes on from where the server got to
+    pub async fn send_file(
         &mut self,
         to: String,
-        content: String,
-    ) -> Result<(), s2n_quic::stream::Error> {
-        let bytes = Transfer {
+        path: impl AsRef<Path>,
+        mut progress: impl FnMut(u64, u64),
+    ) -> Result<Ulid, ClientError> {
+        let path = path.as_ref().to_path_buf();
+        let size = tokio::fs::metadata(&path).await?.len();
+        let name = path
+            .file_name()
+            .map(|name| name.to_string_lossy().to_string())
+            .unwrap_or_default();
+        let mime = mime_guess::from_path(&path)
+            .first_or_octet_stream()
+            .to_string();
+
+        let hashed = path.clone();
+        let sha256 = tokio::task::spawn_blocking(move || common::sha256_file(hashed))
+            .await
+            .map_err(io::Error::other)??;
+
+        let offer = FileOffer {
+            id: Ulid::new(),
             from: self.email.clone(),
             to,
-            content: content.into(),
+            name,
+            size,
+            mime,
+            sha256,
+        };
+
+        match self.try_send_file(&offer, &path, &mut progress).await {
+            Err(
+                ClientError::ConnectionClosed | ClientError::Stream(_) | ClientError::Connection(_),
+            ) => {
+                warn!("connection lost, reconnecting");
+                self.reconnect().await?;
+                self.try_send_file(&offer, &path, &mut progress).await?;
+            }
+            res => res?,
+        }
+
+        Ok(offer.id)
+    }
+
+    async fn try_send_file(
+        &mut self,
+        offer: &FileOffer,
+        path: &Path,
+        progress: &mut impl FnMut(u64, u64),
+    ) -> Result<(), ClientError> {
+        let id = offer.id;
+        let offset = match self
+            .try_request(None, id, &Frame::FileOffer(offer.clone()))
+            .await?
+        {
+            Frame::FileAccept { offset, .. } => offset,
+            Frame::FileReject { reason, .. } => return Err(ClientError::FileRejected(reason)),
+            frame => return Err(ClientError::from(Error::UnexpectedFrame(frame.name()))),
+        };
+
+        // the server acks once it checked the whole file
+        let reply = self.wait_reply(id)?;
+
+        let mut file = tokio::fs::File::open(path).await?;
+        file.seek(SeekFrom::Start(offset)).await?;
+
+        let mut stream = self.handle.open_send_stream().await?;
+        stream
+            .send(Frame::FileData { id, offset }.to_bytes()?)
+            .await?;
+
+        let mut sent = offset;
+        let mut buf = vec![0; FILE_CHUNK];
+        progress(sent, offer.size);
+        loop {
+            let n = file.read(&mut buf).await?;
+            if n == 0 {
+                break;
+            }
+            stream.send(Bytes::copy_from_slice(&buf[..n])).await?;
+            sent += n as u64;
+            progress(sent, offer.size);
         }
-        .to_bytes();
+        stream.finish()?;
+        stream.flush().await?;
 
-        self.send_stream.send(bytes).await.unwrap();
-        self.send_stream.flush().await.unwrap();
+        match tokio::time::timeout(REPLY_TIMEOUT, reply).await {
+            Ok(Ok(Frame::Ack {
+                status: AckStatus::Delivered | AckStatus::Queued,
+                ..
+            })) => Ok(()),
+            Ok(Ok(Frame::Ack {
+                status: AckStatus::Failed(code),
+                ..
+            })) => Err(ClientError::NotDelivered(code)),
+            Ok(Ok(Frame::FileReject { reason, .. })) => Err(ClientError::FileRejected(reason)),
+            Ok(Ok(frame)) => Err(ClientError::from(Error::UnexpectedFrame(frame.name()))),
+            Ok(Err(_)) => Err(ClientError::ConnectionClosed),
+            Err(_) => {
+                let _ = self.listen.send(Command::ForgetReply(id));
+                Err(ClientError::ReplyTimeout)
+            }
+        }
+    }
+
+    async fn room_request(&mut self, id: Ulid, frame: Frame) -> Result<Vec<String>, ClientError> {
+        match self.request(id, frame).await? {
+            Frame::RoomMembers { members, .. } => Ok(members),
+            frame => Err(ClientError::from(Error::UnexpectedFrame(frame.name()))),
+        }
+    }
+
+    /// send a frame on the control stream and wait for the reply carrying the same `id`
+    async fn request(&mut self, id: Ulid, frame: Frame) -> Result<Frame, ClientError> {
+        self.request_on(None, id, frame).await
+    }
+
+    /// send a frame on the stream of `conversation`, or the control stream, and wait for the reply,
+    /// reconnecting and sending it again once if the connection was lost
+    async fn request_on(
+        &mut self,
+        conversation: Option<&str>,
+        id: Ulid,
+        frame: Frame,
+    ) -> Result<Frame, ClientError> {
+        match self.try_request(conversation, id, &frame).await {
+            Err(
+                ClientError::ConnectionClosed | ClientError::Stream(_) | ClientError::Connection(_),
+            ) => {
+                warn!("connection lost, reconnecting");
+                self.reconnect().await?;
+                self.try_request(conversation, id, &frame).await
+            }
+            res => res,
+        }
+    }
+
+    async fn try_request(
+        &mut self,
+        conversation: Option<&str>,
+        id: Ulid,
+        frame: &Frame,
+    ) -> Result<Frame, ClientError> {
+        let reply = self.wait_reply(id)?;
+
+        match conversation {
+            None => self.send_control(frame).await?,
+            Some(conversation) => {
+                let stream = match self.conversations.entry(conversation.to_string()) {
+                    Entry::Occupied(stream) => stream.into_mut(),
+                    Entry::Vacant(entry) => entry.insert(self.handle.open_send_stream().await?),
+                };
+                stream.send(frame.to_bytes()?).await?;
+                stream.flush().await?;
+            }
+        }
+
+        match tokio::time::timeout(REPLY_TIMEOUT, reply).await {
+            Ok(Ok(frame)) => Ok(frame),
+            Ok(Err(_)) => Err(ClientError::ConnectionClosed),
+            Err(_) => {
+                let _ = self.listen.send(Command::ForgetReply(id));
+                Err(ClientError::ReplyTimeout)
+            }
+        }
+    }
 
+    /// the reply to `id`, ask for it before sending the request
+    fn wait_reply(&self, id: Ulid) -> Result<oneshot::Receiver<Frame>, ClientError> {
+        let (tx, reply) = oneshot::channel();
+        self.listen
+            .send(Command::WaitReply(id, tx))
+            .map_err(|_| ClientError::ConnectionClosed)?;
+        Ok(reply)
+    }
+
+    /// send on the control stream, without waiting for a reply
+    async fn send_control(&self, frame: &Frame) -> Result<(), ClientError> {
+        let mut control = self.control.lock().await;
+        control.send(frame.to_bytes()?).await?;
+        control.flush().await?;
         Ok(())
     }
 }
+
+#[derive(Debug)]
+pub enum ClientError {
+    ConnectionClosed,
+    LoginRejected(String),
+    Stream(s2n_quic::stream::Error),
+    Connection(s2n_quic::connection::Error),
+    NotDelivered(ErrorCode),
+    ReplyTimeout,
+    FileRejected(String),
+    /// decoding, files and encryption, see `common::Error`
+    Session(Error),
+    /// encrypting, but this email published no keys, the message was not sent
+    NoKeys(String),
+}
+
+impl From<Error> for ClientError {
+    fn from(value: Error) -> Self {
+        ClientError::Session(value)
+    }
+}
+
+impl From<CodecError> for ClientError {
+    fn from(value: CodecError) -> Self {
+        ClientError::Session(value.into())
+    }
+}
+
+impl From<io::Error> for ClientError {
+    fn from(value: io::Error) -> Self {
+        ClientError::Session(value.into())
+    }
+}
+
+impl From<s2n_quic::stream::Error> for ClientError {
+    fn from(value: s2n_quic::stream::Error) -> Self {
+        ClientError::Stream(value)
+    }
+}
+
+impl From<s2n_quic::connection::Error> for ClientError {
+    fn from(value: s2n_quic::connection::Error) -> Self {
+        ClientError::Connection(value)
+    }
+}
+
+impl Display for ClientError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ClientError::ConnectionClosed => write!(f, "connection closed"),
+            ClientError::LoginRejected(reason) => write!(f, "login rejected: {}", reason),
+            ClientError::Stream(e) => write!(f, "{}", e),
+            ClientError::Connection(e) => write!(f, "{}", e),
+            ClientError::NotDelivered(code) => write!(f, "message not delivered: {:?}", code),
+            ClientError::ReplyTimeout => write!(f, "timed out waiting for the server"),
+            ClientError::FileRejected(reason) => write!(f, "file rejected: {}", reason),
+            ClientError::Session(e) => write!(f, "{}", e),
+            ClientError::NoKeys(email) => {
+                write!(f, "{} has no keys, not sending it in plaintext", email)
+            }
+        }
+    }
+}
+
+impl std::error::Error for ClientError {}
+
+/// the text of `transfer` and, if it was sealed, the identity key of who sealed it
+pub(crate) fn open_content(
+    keys: Option<&Keys>,
+    transfer: &Transfer,
+) -> Result<(String, Option<[u8; 32]>), CryptoError> {
+    if !crypto::is_sealed(&transfer.content) {
+        return Ok((String::from_utf8_lossy(&transfer.content).to_string(), None));
+    }
+
+    let keys = keys.ok_or(CryptoError::NotForUs)?;
+    let aad = crypto::associated_data(transfer.id, &transfer.from, &transfer.to);
+    let opened = crypto::open(keys, &aad, &transfer.content)?;
+
+    Ok((
+        String::from_utf8_lossy(&opened.plaintext).to_string(),
+        Some(opened.sender),
+    ))
+}
@@ -1,62 +1,479 @@
-use actix::prelude::*;
-use async_stream::stream;
 use bytes::Bytes;
-use common::Transfer;
-use log::info;
-use s2n_quic::stream::ReceiveStream;
+use common::{
+    codec::FrameDecoder,
+    crypto::{self, Keys},
+    protocol::Frame,
+    FileOffer, SignalKind,
+};
+use log::{error, info};
+use s2n_quic::{
+    connection::StreamAcceptor,
+    stream::{ReceiveStream, SendStream},
+};
+use std::{
+    collections::{HashMap, HashSet},
+    io::{self, SeekFrom},
+    path::PathBuf,
+    sync::Arc,
+};
+use tokio::{
+    io::{AsyncSeekExt, AsyncWriteExt},
+    sync::{mpsc, oneshot, Mutex},
+};
+use ulid::Ulid;
 
+use crate::client_lib::{open_content, ClientEvent, Connector};
+
+/// reads everything the server sends, on a task of its own, and turns it into
+/// replies to requests and `ClientEvent`s
 pub(crate) struct ClientListen {
-    rece_stream: Option<ReceiveStream>,
+    /// the control stream, shared with `ChatClient`
+    control: Arc<Mutex<SendStream>>,
     email: String,
+    /// requests waiting for the frame answering them, by request id
+    pending_replies: HashMap<Ulid, oneshot::Sender<Frame>>,
+    /// received files are saved here
+    download_dir: PathBuf,
+    /// offers accepted, waiting for their file stream
+    downloads: HashMap<Ulid, FileOffer>,
+    events: mpsc::UnboundedSender<ClientEvent>,
+    keys: Option<Arc<Keys>>,
+    /// the identity keys each email sealed messages with, one per device
+    identities: HashMap<String, HashSet<[u8; 32]>>,
+    /// the conversation and file streams the server opened are read into this
+    incoming: mpsc::UnboundedSender<Incoming>,
+}
+
+/// what the streams the server opened yield
+pub(crate) enum Incoming {
+    Frame(Bytes),
+    /// a file stream whose `FileData` frame was read, `leftover` are the bytes read past it
+    File {
+        id: Ulid,
+        offset: u64,
+        leftover: Bytes,
+        recv: ReceiveStream,
+    },
+}
+
+/// what `ChatClient` asks of the listening task
+pub(crate) enum Command {
+    /// pass on the reply to a request, must be sent before the request itself
+    WaitReply(Ulid, oneshot::Sender<Frame>),
+    /// the caller gave up waiting
+    ForgetReply(Ulid),
 }
 
 impl ClientListen {
-    pub fn new(rece: ReceiveStream, email: String) -> Self {
-        Self {
-            rece_stream: Some(rece),
+    /// start listening on its own task, `rece` is the control stream, `decoder` may already
+    /// hold bytes received on it during login. it stops when the server ends the session
+    /// or every sender of the returned commands is dropped
+    pub fn start(
+        rece: ReceiveStream,
+        decoder: FrameDecoder,
+        acceptor: StreamAcceptor,
+        control: Arc<Mutex<SendStream>>,
+        email: String,
+        connector: &Connector,
+    ) -> mpsc::UnboundedSender<Command> {
+        let (commands_tx, commands) = mpsc::unbounded_channel();
+        let (incoming_tx, incoming) = mpsc::unbounded_channel();
+
+        let listen = Self {
+            control,
             email,
+            pending_replies: HashMap::new(),
+            download_dir: connector.download_dir.clone(),
+            downloads: HashMap::new(),
+            events: connector.events.clone(),
+            keys: connector.keys.clone(),
+            identities: HashMap::new(),
+            incoming: incoming_tx,
+        };
+        tokio::spawn(listen.run(rece, decoder, acceptor, commands, incoming));
+
+        commands_tx
+    }
+
+    async fn run(
+        mut self,
+        mut rece: ReceiveStream,
+        mut decoder: FrameDecoder,
+        mut acceptor: StreamAcceptor,
+        mut commands: mpsc::UnboundedReceiver<Command>,
+        mut incoming: mpsc::UnboundedReceiver<Incoming>,
+    ) {
+        info!("client listen started");
+
+        loop {
+            // frames received during login come before anything else
+            loop {
+                match decoder.next_frame() {
+                    Ok(Some(bytes)) => {
+                        if !self.handle_bytes(bytes).await {
+                            return;
+                        }
+                    }
+                    Ok(None) => break,
+                    Err(e) => {
+                        error!("decode frame failed: {}", e);
+                        self.disconnected(None);
+                        return;
+                    }
+                }
+            }
+
+            tokio::select! {
+                // a reply must not overtake the command waiting for it
+                biased;
+
+                command = commands.recv() => match command {
+                    Some(Command::WaitReply(id, reply)) => {
+                        self.pending_replies.insert(id, reply);
+                    }
+                    Some(Command::ForgetReply(id)) => {
+                        self.pending_replies.remove(&id);
+                    }
+                    // the client is gone, or replaced after reconnecting
+                    None => return,
+                },
+                received = rece.receive() => match received {
+                    Ok(Some(bytes)) => {
+                        info!("received stream");
+                        decoder.extend(&bytes);
+                    }
+                    _ => {
+                        self.disconnected(None);
+                        return;
+                    }
+                },
+                Ok(Some(stream)) = acceptor.accept_receive_stream() => self.read_stream(stream),
+                Some(incoming) = incoming.recv() => match incoming {
+                    Incoming::Frame(bytes) => {
+                        if !self.handle_bytes(bytes).await {
+                            return;
+                        }
+                    }
+                    Incoming::File {
+                        id,
+                        offset,
+                        leftover,
+                        recv,
+                    } => self.receive_file(id, offset, leftover, recv),
+                },
+            }
         }
     }
-}
 
-impl Actor for ClientListen {
-    type Context = Context<Self>;
+    /// decode a conversation or file stream, unlike the control stream its end is not ours
+    fn read_stream(&self, mut recv: ReceiveStream) {
+        info!("server opened a stream");
 
-    fn started(&mut self, ctx: &mut Self::Context) {
-        info!("client listen started");
+        let incoming = self.incoming.clone();
+        tokio::spawn(async move {
+            let mut decoder = FrameDecoder::new();
+            while let Ok(Some(bytes)) = recv.receive().await {
+                decoder.extend(&bytes);
 
-        let mut recv = self.rece_stream.take().unwrap();
+                loop {
+                    match decoder.next_frame() {
+                        Ok(Some(frame)) => {
+                            // a file stream goes on with raw bytes after its first frame
+                            if let Ok(Frame::FileData { id, offset }) =
+                                Frame::try_from(frame.clone())
+                            {
+                                let _ = incoming.send(Incoming::File {
+                                    id,
+                                    offset,
+                                    leftover: decoder.into_remaining(),
+                                    recv,
+                                });
+                                return;
+                            }
+                            if incoming.send(Incoming::Frame(frame)).is_err() {
+                                return;
+                            }
+                        }
+                        Ok(None) => break,
+                        Err(e) => {
+                            error!("decode frame failed: {}", e);
+                            return;
+                        }
+                    }
+                }
+            }
+        });
+    }
 
-        let incoming_bytes = stream! {
-            while let Ok(bytes) = recv.receive().await {
-                info!("received stream");
-                yield bytes;
+    /// `false` once the server ended the session
+    async fn handle_bytes(&mut self, bytes: Bytes) -> bool {
+        match Frame::try_from(bytes) {
+            Ok(frame) => self.handle_frame(frame).await,
+            Err(e) => {
+                error!("invalid frame: {}", e);
+                true
             }
-        };
+        }
+    }
 
-        ctx.add_stream(incoming_bytes);
+    /// nobody listening is fine
+    fn emit(&self, event: ClientEvent) {
+        let _ = self.events.send(event);
+    }
+
+    fn disconnected(&self, reason: Option<String>) {
+        info!("client listen stopped");
+        self.emit(ClientEvent::Disconnected { reason });
+    }
+
+    /// where the part of the file `id` is kept until it is complete
+    fn part_path(&self, id: Ulid) -> PathBuf {
+        self.download_dir.join(format!("{}.part", id))
+    }
+
+    /// accept the offer, asking for the bytes after those already downloaded
+    async fn accept_file(&mut self, offer: FileOffer) {
+        let id = offer.id;
+        let size = offer.size;
+        let part = self.part_path(id);
+        self.emit(ClientEvent::FileOffered(offer.clone()));
+        self.downloads.insert(id, offer);
+
+        let accepted = async {
+            tokio::fs::create_dir_all(&self.download_dir).await?;
+            let offset = match tokio::fs::metadata(&part).await {
+                Ok(meta) if meta.len() <= size => meta.len(),
+                _ => 0,
+            };
+
+            let mut control = self.control.lock().await;
+            control
+                .send(
+                    Frame::FileAccept { id, offset }
+                        .to_bytes()
+                        .map_err(io::Error::other)?,
+                )
+                .await
+                .map_err(io::Error::other)?;
+            control.flush().await.map_err(io::Error::other)
+        }
+        .await;
+
+        if let Err(e) = accepted {
+            self.downloads.remove(&id);
+            self.emit(ClientEvent::FileFailed {
+                id,
+                reason: format!("accept failed: {}", e),
+            });
+        }
     }
-}
 
-impl StreamHandler<Option<Bytes>> for ClientListen {
-    fn handle(&mut self, bytes: Option<Bytes>, ctx: &mut Self::Context) {
-        info!("handle incoming bytes");
-        if bytes.is_none() {
-            ctx.stop();
+    /// write the file stream into its part file, which becomes the file once it checked out
+    fn receive_file(&mut self, id: Ulid, offset: u64, leftover: Bytes, recv: ReceiveStream) {
+        let Some(offer) = self.downloads.remove(&id) else {
+            info!("file stream for unknown offer: {}", id);
             return;
+        };
+
+        let part = self.part_path(id);
+        let path = self.download_dir.join(offer.file_name());
+        let events = self.events.clone();
+        tokio::spawn(async move {
+            let saved = async {
+                write_download(&part, &offer, offset, leftover, recv, &events).await?;
+                // never overwrite a file of the same name
+                let path = match tokio::fs::try_exists(&path).await? {
+                    true => path.with_file_name(format!("{}-{}", id, offer.file_name())),
+                    false => path,
+                };
+                tokio::fs::rename(&part, &path).await?;
+
+                io::Result::Ok(path)
+            }
+            .await;
+
+            let event = match saved {
+                Ok(path) => ClientEvent::FileSaved { id, path },
+                Err(e) => ClientEvent::FileFailed {
+                    id,
+                    reason: e.to_string(),
+                },
+            };
+            let _ = events.send(event);
+        });
+    }
+
+    /// tell when an email seals with an identity key it did not use before,
+    /// a new device of theirs or someone else
+    fn check_identity(&mut self, email: &str, identity: [u8; 32]) {
+        let pinned = self.identities.entry(email.to_string()).or_default();
+        if pinned.insert(identity) && pinned.len() > 1 {
+            self.emit(ClientEvent::IdentityChanged {
+                email: email.to_string(),
+                fingerprint: crypto::fingerprint(&identity),
+            });
         }
+    }
 
-        let bytes = bytes.unwrap();
+    /// `false` once the server ended the session
+    async fn handle_frame(&mut self, frame: Frame) -> bool {
+        if let Some(id) = frame.reply_id() {
+            if let Some(waiting) = self.pending_replies.remove(&id) {
+                let _ = waiting.send(frame);
+            } else if let Frame::Ack { id, status } = frame {
+                // whoever sent it stopped waiting
+                self.emit(ClientEvent::Ack { id, status });
+            } else {
+                info!("reply to unknown request: {}", id);
+            }
+            return true;
+        }
 
-        let transfer = Transfer::try_from(bytes).unwrap();
+        match frame {
+            Frame::Chat(transfer) => {
+                let text = match open_content(self.keys.as_deref(), &transfer) {
+                    Ok((text, sender)) => {
+                        if let Some(sender) = sender {
+                            self.check_identity(&transfer.from, sender);
+                        }
+                        text
+                    }
+                    Err(e) => format!("[encrypted, {}]", e),
+                };
 
-        if transfer.to != self.email {
-            // not message to me, discard
-            return;
+                // a room, or ours sent from another of our devices
+                let ours = transfer.room().is_some()
+                    || transfer.from == self.email
+                    || transfer.to == self.email;
+                if !ours {
+                    // not message to me, discard
+                    return true;
+                }
+
+                self.emit(ClientEvent::Message {
+                    id: transfer.id,
+                    from: transfer.from,
+                    to: transfer.to,
+                    text,
+                });
+            }
+            Frame::FileOffer(offer) => self.accept_file(offer).await,
+            Frame::PresenceUpdate { email, status } => {
+                self.emit(ClientEvent::Presence {
+                    email,
+                    presence: status,
+                });
+            }
+            Frame::Signal(signal) => {
+                let event = match signal.kind {
+                    SignalKind::TypingStarted | SignalKind::TypingStopped => ClientEvent::Typing {
+                        conversation: signal.room().unwrap_or(&signal.to).to_string(),
+                        typing: signal.kind == SignalKind::TypingStarted,
+                        from: signal.from,
+                    },
+                    SignalKind::Read(id) => ClientEvent::Read {
+                        from: signal.from,
+                        id,
+                    },
+                };
+                self.emit(event);
+            }
+            Frame::Error { code, msg } => self.emit(ClientEvent::Error { code, msg }),
+            Frame::Notice { text } => self.emit(ClientEvent::Notice { text }),
+            Frame::Logout => {
+                info!("server logged us out");
+                self.disconnected(None);
+                return false;
+            }
+            Frame::Shutdown { reason } => {
+                self.disconnected(Some(reason));
+                return false;
+            }
+            frame => {
+                info!("ignore frame: {}", frame.name());
+            }
+        }
+
+        true
+    }
+}
+
+/// write a file stream into `part` from `offset` on, telling the progress every tenth,
+/// then check it is the file `offer` described
+async fn write_download(
+    part: &PathBuf,
+    offer: &FileOffer,
+    offset: u64,
+    leftover: Bytes,
+    mut recv: ReceiveStream,
+    events: &mpsc::UnboundedSender<ClientEvent>,
+) -> io::Result<()> {
+    let mut file = tokio::fs::OpenOptions::new()
+        .create(true)
+        .truncate(false)
+        .write(true)
+        .open(part)
+        .await?;
+    file.set_len(offset).await?;
+    file.seek(SeekFrom::Start(offset)).await?;
+
+    let mut written = offset;
+    let mut shown = progress_step(written, offer.size);
+    let mut next = Some(leftover);
+    loop {
+        let bytes = match next.take() {
+            Some(bytes) => bytes,
+            None => match recv.receive().await {
+                Ok(Some(bytes)) => bytes,
+                Ok(None) => break,
+                Err(e) => return Err(io::Error::other(e)),
+            },
+        };
+
+        written += bytes.len() as u64;
+        if written > offer.size {
+            break;
         }
+        file.write_all(&bytes).await?;
 
-        let content = String::from_utf8(transfer.content.to_vec()).unwrap();
+        let step = progress_step(written, offer.size);
+        if step > shown {
+            shown = step;
+            let _ = events.send(ClientEvent::FileProgress {
+                id: offer.id,
+                received: written,
+                size: offer.size,
+            });
+        }
+    }
+    file.flush().await?;
+
+    if written != offer.size {
+        return Err(io::Error::other(format!(
+            "{}: got {} of {} bytes",
+            offer.name, written, offer.size
+        )));
+    }
+
+    let path = part.clone();
+    let sha256 = tokio::task::spawn_blocking(move || common::sha256_file(path))
+        .await
+        .map_err(io::Error::other)??;
+    if sha256 != offer.sha256 {
+        tokio::fs::remove_file(part).await?;
+        return Err(io::Error::other(format!(
+            "{}: checksum mismatch",
+            offer.name
+        )));
+    }
+
+    Ok(())
+}
 
-        println!("\n${}: {}", transfer.from, content);
+/// tenths of `size` done
+pub fn progress_step(done: u64, size: u64) -> u64 {
+    match size {
+        0 => 10,
+        size => done.min(size) * 10 / size,
     }
 }
@@ -0,0 +1,139 @@
+//! the slash commands both clients understand, any other line is a message
+use std::path::PathBuf;
+
+/// what `/help` prints
+pub const HELP: &str = "\
+/to <email or #room>    talk to someone, or in a room
+/join <#room>           join a room and talk in it
+/leave                  leave the room talked in
+/members                who is in the room talked in
+/who                    everyone online
+/history [count]        the last messages of the conversation
+/send <file>            send a file to who you talk to
+/nick <email> <name>    call someone by a name of your own
+/away, /back            set whether you are around
+/quit                   leave
+/help                   this";
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum Command {
+    To(String),
+    Join(String),
+    Leave,
+    Members,
+    Who,
+    History(Option<u32>),
+    Send(PathBuf),
+    Nick { email: String, nick: String },
+    Away,
+    Back,
+    Quit,
+    Help,
+}
+
+/// `None` when `line` is a message, a command or why it is not one otherwise
+pub fn parse(line: &str) -> Option<Result<Command, String>> {
+    let line = line.trim();
+    let rest = line.strip_prefix('/')?;
+
+    let (name, arg) = match rest.split_once(char::is_whitespace) {
+        Some((name, arg)) => (name, arg.trim()),
+        None => (rest, ""),
+    };
+
+    let command = match (name, arg) {
+        ("to", "") | ("join", "") | ("send", "") | ("nick", "") => {
+            Err(format!("/{} needs an argument, see /help", name))
+        }
+        ("to", to) => Ok(Command::To(to.to_string())),
+        ("join", room) if common::is_room(room) => Ok(Command::Join(room.to_string())),
+        ("join", room) => Err(format!("{} is not a room, rooms start with #", room)),
+        ("leave", "") => Ok(Command::Leave),
+        ("members", "") => Ok(Command::Members),
+        ("who", "") => Ok(Command::Who),
+        ("history", "") => Ok(Command::History(None)),
+        ("history", count) => count
+            .parse()
+            .map(|count| Command::History(Some(count)))
+            .map_err(|_| format!("not a count: {}", count)),
+        ("send", path) => Ok(Command::Send(path.into())),
+        ("nick", arg) => match arg.split_once(char::is_whitespace) {
+            Some((email, nick)) => Ok(Command::Nick {
+                email: email.to_string(),
+                nick: nick.trim().to_string(),
+            }),
+            None => Err("/nick <email> <name>".to_string()),
+        },
+        ("away", "") => Ok(Command::Away),
+        ("back", "") => Ok(Command::Back),
+        ("quit", "") => Ok(Command::Quit),
+        ("help", "") => Ok(Command::Help),
+        ("leave" | "members" | "who" | "away" | "back" | "quit" | "help", _) => {
+            Err(format!("/{} takes no argument", name))
+        }
+        _ => Err(format!("unknown command /{}, /help lists them", name)),
+    };
+
+    Some(command)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn lines_parse_to_commands() {
+        let cases = [
+            ("/to bob@x", Command::To("bob@x".into())),
+            ("  /to   #room  ", Command::To("#room".into())),
+            ("/join #room", Command::Join("#room".into())),
+            ("/leave", Command::Leave),
+            ("/members", Command::Members),
+            ("/who", Command::Who),
+            ("/history", Command::History(None)),
+            ("/history 20", Command::History(Some(20))),
+            ("/send a file.txt", Command::Send("a file.txt".into())),
+            (
+                "/nick bob@x Bob  the builder",
+                Command::Nick {
+                    email: "bob@x".into(),
+                    nick: "Bob  the builder".into(),
+                },
+            ),
+            ("/away", Command::Away),
+            ("/back", Command::Back),
+            ("/quit", Command::Quit),
+            ("/help", Command::Help),
+        ];
+
+        for (line, command) in cases {
+            assert_eq!(parse(line), Some(Ok(command)), "{:?}", line);
+        }
+    }
+
+    #[test]
+    fn malformed_commands_say_why() {
+        let cases = [
+            ("/to", "/to needs an argument, see /help"),
+            ("/send  ", "/send needs an argument, see /help"),
+            ("/join room", "room is not a room, rooms start with #"),
+            ("/history many", "not a count: many"),
+            ("/history -1", "not a count: -1"),
+            ("/nick bob@x", "/nick <email> <name>"),
+            ("/quit now", "/quit takes no argument"),
+            ("/dance", "unknown command /dance, /help lists them"),
+            ("/", "unknown command /, /help lists them"),
+        ];
+
+        for (line, reason) in cases {
+            assert_eq!(parse(line), Some(Err(reason.to_string())), "{:?}", line);
+        }
+    }
+
+    #[test]
+    fn other_lines_are_messages() {
+        for line in ["hi", "", "  ", "a /to b", "1/2"] {
+            assert_eq!(parse(line), None, "{:?}", line);
+        }
+    }
+}
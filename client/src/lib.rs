@@ -0,0 +1,14 @@
+//! client library of the chat server, for bots and user interfaces alike
+//!
+//! connect with `InitClient`, log in to get a `ChatClient`, and take its `events`
+//! for whatever the server sends without being asked. it runs on tokio alone
+mod client_lib;
+mod client_listen;
+
+pub use client_lib::*;
+pub use client_listen::progress_step;
+pub use common::{
+    crypto::{KeyBundle, Keys},
+    protocol::{AckStatus, ErrorCode},
+    FileOffer, HistoryEntry, Presence, SignalKind, Transfer,
+};
@@ -1,21 +1,22 @@
-mod client_lib;
-mod client_listen;
-
-use core::task;
 use std::{
+    collections::HashMap,
     error::Error,
     io::{stdin, Write},
-    net::SocketAddr,
-    path::Path,
-    thread,
-    time::Duration,
 };
 
-use actix::fut::future;
 use clap::Parser;
+use client::{ChatClient, ClientEvent, Presence, SignalKind};
+use commands::Command;
+use common::crypto;
+use futures::StreamExt;
 use log::info;
-use s2n_quic::{client::Connect, Client};
-use tokio::io::AsyncWriteExt;
+use ulid::Ulid;
+
+mod commands;
+mod tui;
+
+/// messages of scrollback shown when a conversation opens
+pub(crate) const HISTORY_PAGE: u32 = 20;
 
 #[derive(Parser, Debug)]
 struct Args {
@@ -23,11 +24,30 @@ struct Args {
     certificate: String,
     #[arg(long, short)]
     server: String,
+    /// create the account instead of logging in
+    #[arg(long)]
+    register: bool,
+    /// log in with this client certificate instead of a password
+    #[arg(long, requires = "client_key")]
+    client_cert: Option<String>,
+    /// private key of the client certificate
+    #[arg(long, requires = "client_cert")]
+    client_key: Option<String>,
+    /// where files sent to us are saved
+    #[arg(long, default_value = client::DEFAULT_DOWNLOAD_DIR)]
+    download_dir: String,
+    /// encrypt messages end-to-end with the keys in this file, created if missing
+    #[arg(long)]
+    keys: Option<String>,
+    /// full screen, with a conversation list, instead of talking to one peer line by line
+    #[arg(long)]
+    tui: bool,
 }
 
-#[actix_rt::main]
+#[tokio::main]
 async fn main() -> Result<(), Box<dyn Error>> {
-    dotenv::dotenv().unwrap();
+    // the file is optional, the environment may be set already
+    let _ = dotenv::dotenv();
     env_logger::init();
 
     let args = Args::parse();
@@ -61,45 +81,362 @@ async fn main() -> Result<(), Box<dyn Error>> {
 
     // client.wait_idle().await.unwrap();
 
-    let client =
-        client_lib::InitClient::new(args.certificate, args.server.parse().unwrap()).await?;
+    let identity = match (args.client_cert, args.client_key) {
+        (Some(certificate), Some(key)) => Some(client::ClientIdentity { certificate, key }),
+        _ => None,
+    };
+    let with_certificate = identity.is_some();
+    let encrypting = args.keys.is_some();
 
-    print!("connected, enter your email: ");
-    let mut stdout = std::io::stdout();
-    stdout.flush().unwrap();
+    let mut client = client::InitClient::new(args.certificate, args.server.parse()?, identity)
+        .await?
+        .download_to(args.download_dir);
+    if let Some(path) = args.keys {
+        let mut keys = crypto::Keys::load_or_generate(&path)?;
+        // a new prekey every start, the old ones still open what was sealed to them
+        keys.rotate_prekey();
+        keys.save(&path)?;
+        println!(
+            "your key fingerprint: {}",
+            crypto::fingerprint(&keys.bundle().identity)
+        );
+        client = client.encrypt_with(keys);
+    }
 
+    let mut stdout = std::io::stdout();
     let stdin = stdin();
 
-    let mut client = {
+    let mut client = if with_certificate {
+        let logged_in = client.login_with_certificate().await?;
+        println!("connected, logged in as {}", logged_in.email());
+
+        logged_in
+    } else {
+        print!("connected, enter your email: ");
+        stdout.flush()?;
+
         let mut txt = String::new();
-        stdin.read_line(&mut txt).unwrap();
+        stdin.read_line(&mut txt)?;
         info!("get email: {}", txt);
-        let logged_in = client.login(txt.trim().to_string()).await?;
+        let email = txt.trim().to_string();
+
+        print!("password: ");
+        stdout.flush()?;
+        let mut txt = String::new();
+        stdin.read_line(&mut txt)?;
+        let password = txt.trim().to_string();
+
+        let logged_in = if args.register {
+            client.register(email, password).await?
+        } else {
+            client.login(email, password).await?
+        };
         info!("client logged in");
 
         logged_in
     };
 
-    let talk_to = {
-        print!("talk to:");
-        stdout.flush().unwrap();
-        let mut txt = String::new();
-        stdin.read_line(&mut txt).unwrap();
-        txt.trim().to_string()
+    if args.tui {
+        return Ok(tui::run(client).await?);
+    }
+
+    let Some(events) = client.events() else {
+        return Err("events already taken".into());
     };
+    let mut events = std::pin::pin!(events);
 
-    for line in stdin.lines() {
-        if let Ok(txt) = line {
-            let txt = txt.trim().to_string();
+    // read input on a thread of its own, so incoming messages and files are
+    // handled while waiting for it
+    let (lines_tx, mut lines) = tokio::sync::mpsc::unbounded_channel();
+    std::thread::spawn(move || {
+        for line in stdin.lines() {
+            if lines_tx.send(line).is_err() {
+                break;
+            }
+        }
+    });
 
-            client
-                .say(talk_to.clone(), txt)
-                .await
-                .expect("client talk wrong");
-        } else {
-            break;
+    println!("/to <email> or /join <#room> to start talking, /help for more");
+    let mut cli = Cli::new(encrypting);
+    loop {
+        tokio::select! {
+            event = events.next() => match event {
+                Some(event) => cli.show(&client, event),
+                None => break,
+            },
+            line = lines.recv() => match line {
+                Some(Ok(line)) => {
+                    if !cli.input(&mut client, line.trim()).await {
+                        break;
+                    }
+                }
+                _ => break,
+            },
         }
     }
 
     Ok(())
 }
+
+/// the line by line client
+struct Cli {
+    /// the email or room lines that are no command go to
+    target: Option<String>,
+    /// whose presence is followed
+    contacts: Vec<String>,
+    /// names of the files being downloaded
+    files: HashMap<Ulid, String>,
+    encrypting: bool,
+}
+
+impl Cli {
+    fn new(encrypting: bool) -> Self {
+        Self {
+            target: None,
+            contacts: vec![],
+            files: HashMap::new(),
+            encrypting,
+        }
+    }
+
+    /// `false` once the user wants to leave
+    async fn input(&mut self, client: &mut ChatClient, line: &str) -> bool {
+        match commands::parse(line) {
+            Some(Ok(command)) => return self.run(client, command).await,
+            Some(Err(e)) => println!("! {}", e),
+            None if line.is_empty() => {}
+            None => match &self.target {
+                Some(target) => {
+                    if let Err(e) = client.send(target.clone(), line.to_string()).await {
+                        println!("! {}", e);
+                    }
+                }
+                None => println!("! /to <email> or /join <#room> first"),
+            },
+        }
+        true
+    }
+
+    async fn run(&mut self, client: &mut ChatClient, command: Command) -> bool {
+        match command {
+            Command::To(name) => self.open(client, client.resolve(&name)).await,
+            Command::Join(room) => self.open(client, room).await,
+            Command::Leave | Command::Members => {
+                let Some(room) = self.target.clone().filter(|t| common::is_room(t)) else {
+                    println!("! not talking in a room");
+                    return true;
+                };
+                let res = match command {
+                    Command::Leave => client.leave(room.clone()).await,
+                    _ => client.members(room.clone()).await,
+                };
+                match (res, command) {
+                    (Ok(_), Command::Leave) => {
+                        println!("left {}", room);
+                        self.target = None;
+                    }
+                    (Ok(members), _) => println!("members: {}", members.join(", ")),
+                    (Err(e), _) => println!("! {}", e),
+                }
+            }
+            Command::Who => match client.who_online().await {
+                Ok(online) => {
+                    for (email, presence) in online {
+                        println!("{} ({})", client.nick(&email), presence);
+                    }
+                }
+                Err(e) => println!("! {}", e),
+            },
+            Command::History(count) => match self.target.clone() {
+                Some(target) => {
+                    let count = count.unwrap_or(HISTORY_PAGE);
+                    show_history(client, target, count).await;
+                }
+                None => println!("! not talking to anyone"),
+            },
+            Command::Send(path) => {
+                let Some(target) = self.target.clone() else {
+                    println!("! not talking to anyone");
+                    return true;
+                };
+                let mut shown = None;
+                let sent = client
+                    .send_file(target, &path, |sent, size| {
+                        let step = client::progress_step(sent, size);
+                        if shown != Some(step) {
+                            shown = Some(step);
+                            println!("sending {}: {}%", path.display(), step * 10);
+                        }
+                    })
+                    .await;
+                match sent {
+                    Ok(_) => println!("sent {}", path.display()),
+                    Err(e) => println!("! {}", e),
+                }
+            }
+            Command::Nick { email, nick } => {
+                println!("{} is {} now", email, nick);
+                client.set_nick(email, nick);
+            }
+            Command::Away | Command::Back => {
+                let presence = match command {
+                    Command::Away => Presence::Away,
+                    _ => Presence::Online,
+                };
+                if let Err(e) = client.set_presence(presence).await {
+                    println!("! {}", e);
+                }
+            }
+            Command::Quit => return false,
+            Command::Help => println!("{}", commands::HELP),
+        }
+        true
+    }
+
+    /// talk to `to` from now on, joining it if it is a room, and show what was said before
+    async fn open(&mut self, client: &mut ChatClient, to: String) {
+        let mut contacts = vec![to.clone()];
+        if common::is_room(&to) {
+            match client.join(to.clone()).await {
+                Ok(members) => {
+                    println!("joined {}, members: {}", to, members.join(", "));
+                    contacts = members;
+                }
+                Err(e) => {
+                    println!("! join {} failed: {}", to, e);
+                    return;
+                }
+            }
+        } else if self.encrypting {
+            match client.peer_keys(to.clone()).await {
+                Ok(bundles) if !bundles.is_empty() => println!(
+                    "messages are end-to-end encrypted, {} fingerprints: {}",
+                    to,
+                    bundles
+                        .iter()
+                        .map(|bundle| crypto::fingerprint(&bundle.identity))
+                        .collect::<Vec<_>>()
+                        .join(", ")
+                ),
+                Ok(_) => println!("! {} has no keys, messages to them are not sent", to),
+                Err(e) => println!("! get keys of {} failed: {}", to, e),
+            }
+        }
+        println!("talking to {}", client.nick(&to));
+        self.target = Some(to.clone());
+
+        // follow the presence of everyone we talked to
+        let known = self.contacts.len();
+        for contact in contacts {
+            if contact != client.email() && !self.contacts.contains(&contact) {
+                self.contacts.push(contact);
+            }
+        }
+        if self.contacts.len() > known {
+            match client.subscribe_presence(self.contacts.clone()).await {
+                Ok(presences) => {
+                    for (email, presence) in presences {
+                        println!("* {} is {}", client.nick(&email), presence);
+                    }
+                }
+                Err(e) => println!("! follow presence failed: {}", e),
+            }
+        }
+
+        show_history(client, to, HISTORY_PAGE).await;
+    }
+
+    /// print what the server sent without being asked
+    fn show(&mut self, client: &ChatClient, event: ClientEvent) {
+        match event {
+            ClientEvent::Message { from, to, text, .. } => {
+                if common::is_room(&to) {
+                    println!("\n{} ${}: {}", to, client.nick(&from), text);
+                } else if from == client.email() {
+                    // sent from another of our devices
+                    println!("\n${} -> {}: {}", from, client.nick(&to), text);
+                } else {
+                    println!("\n${}: {}", client.nick(&from), text);
+                }
+            }
+            ClientEvent::Ack { id, status } => info!("late ack of {}: {:?}", id, status),
+            ClientEvent::Presence { email, presence } => {
+                println!("\n* {} is {}", client.nick(&email), presence)
+            }
+            ClientEvent::Typing {
+                from,
+                conversation,
+                typing,
+            } => match typing {
+                true => println!("* {} is typing in {}", client.nick(&from), conversation),
+                false => println!(
+                    "* {} stopped typing in {}",
+                    client.nick(&from),
+                    conversation
+                ),
+            },
+            ClientEvent::Read { from, id } => println!("* {} read {}", client.nick(&from), id),
+            ClientEvent::IdentityChanged { email, fingerprint } => {
+                println!("\n! new identity key of {}: {}", email, fingerprint)
+            }
+            ClientEvent::FileOffered(offer) => {
+                println!(
+                    "\n${} sends {} ({}, {} bytes)",
+                    client.nick(&offer.from),
+                    offer.name,
+                    offer.mime,
+                    offer.size
+                );
+                self.files.insert(offer.id, offer.name);
+            }
+            ClientEvent::FileProgress { id, received, size } => {
+                let name = self.files.get(&id).map(String::as_str).unwrap_or_default();
+                println!("{}: {}%", name, client::progress_step(received, size) * 10)
+            }
+            ClientEvent::FileSaved { id, path } => {
+                self.files.remove(&id);
+                println!("\nsaved {}", path.display())
+            }
+            ClientEvent::FileFailed { id, reason } => {
+                self.files.remove(&id);
+                println!("\n! download failed: {}", reason)
+            }
+            ClientEvent::Notice { text } => println!("\n! notice: {}", text),
+            ClientEvent::Error { code, msg } => {
+                println!("\n! server error ({:?}): {}", code, msg)
+            }
+            ClientEvent::Disconnected { reason } => {
+                if let Some(reason) = reason {
+                    println!("\n! {}", reason);
+                }
+            }
+        }
+    }
+}
+
+/// print the last `count` messages with `peer`, and tell whoever sent the newest of them it was read
+async fn show_history(client: &mut ChatClient, peer: String, count: u32) {
+    let entries = match client.history(peer, None, count).await {
+        Ok(entries) => entries,
+        Err(e) => {
+            println!("! load history failed: {}", e);
+            return;
+        }
+    };
+
+    for entry in &entries {
+        let from = client.nick(&entry.transfer.from);
+        println!("${}: {}", from, client.read(&entry.transfer));
+    }
+
+    let newest = entries
+        .iter()
+        .rev()
+        .find(|entry| entry.transfer.from != client.email());
+    if let Some(newest) = newest {
+        let read = SignalKind::Read(newest.transfer.id);
+        if let Err(e) = client.signal(newest.transfer.from.clone(), read).await {
+            info!("send read receipt failed: {}", e);
+        }
+    }
+}
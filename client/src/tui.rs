@@ -0,0 +1,530 @@
+//! full screen client: conversations on the left, the selected one on the right,
+//! the input at the bottom
+//!
+//! the slash commands of `commands` work here too, `/to` opens a conversation or switches to it.
+//! tab and shift tab switch conversations, page up and page down scroll
+use std::{collections::HashMap, io};
+
+use client::{ChatClient, ClientEvent, Presence, SignalKind};
+use crossterm::event::{Event, EventStream, KeyCode, KeyEvent, KeyEventKind, KeyModifiers};
+use futures::{Stream, StreamExt};
+use ratatui::{
+    layout::{Constraint, Layout, Rect},
+    style::{Color, Modifier, Style},
+    text::{Line, Span},
+    widgets::{Block, List, ListItem, ListState, Paragraph},
+    DefaultTerminal, Frame,
+};
+
+use crate::{
+    commands::{self, Command, HELP},
+    HISTORY_PAGE,
+};
+
+/// lines a page up or down scrolls
+const SCROLL_PAGE: usize = 10;
+
+struct Conversation {
+    /// an email or a room
+    name: String,
+    messages: Vec<Message>,
+    unread: usize,
+    /// lines scrolled back from the newest
+    scroll: usize,
+}
+
+struct Message {
+    from: String,
+    text: String,
+}
+
+impl Conversation {
+    fn new(name: String) -> Self {
+        Self {
+            name,
+            messages: vec![],
+            unread: 0,
+            scroll: 0,
+        }
+    }
+}
+
+struct App {
+    email: String,
+    conversations: Vec<Conversation>,
+    selected: Option<usize>,
+    input: String,
+    presence: HashMap<String, Presence>,
+    /// the last thing worth telling that belongs to no conversation
+    status: String,
+    quit: bool,
+}
+
+impl App {
+    fn new(email: String) -> Self {
+        Self {
+            email,
+            conversations: vec![],
+            selected: None,
+            input: String::new(),
+            presence: HashMap::new(),
+            status: "/to <email or #room> to start talking".to_string(),
+            quit: false,
+        }
+    }
+
+    fn current(&mut self) -> Option<&mut Conversation> {
+        self.selected.map(|i| &mut self.conversations[i])
+    }
+
+    /// the conversation with `name`, added if there is none yet
+    fn conversation(&mut self, name: &str) -> (usize, &mut Conversation) {
+        let i = match self.conversations.iter().position(|c| c.name == name) {
+            Some(i) => i,
+            None => {
+                self.conversations.push(Conversation::new(name.to_string()));
+                self.conversations.len() - 1
+            }
+        };
+        (i, &mut self.conversations[i])
+    }
+
+    fn select(&mut self, i: usize) {
+        self.selected = Some(i);
+        self.conversations[i].unread = 0;
+    }
+
+    /// move the selection by `step`, wrapping around
+    fn cycle(&mut self, step: isize) {
+        let len = self.conversations.len() as isize;
+        if len == 0 {
+            return;
+        }
+        let i = self.selected.map_or(0, |i| i as isize);
+        self.select((i + step).rem_euclid(len) as usize);
+    }
+
+    /// add a message to its conversation, counted as unread unless it is the one shown
+    fn receive(&mut self, name: &str, from: String, text: String) {
+        let selected = self.selected;
+        let (i, conversation) = self.conversation(name);
+        conversation.messages.push(Message { from, text });
+        if selected != Some(i) {
+            conversation.unread += 1;
+        }
+        if selected.is_none() {
+            self.select(i);
+        }
+    }
+
+    /// who a conversation is with, followed for their presence
+    fn contacts(&self) -> Vec<String> {
+        self.conversations
+            .iter()
+            .filter(|c| !common::is_room(&c.name))
+            .map(|c| c.name.clone())
+            .collect()
+    }
+
+    fn handle_event(&mut self, event: ClientEvent) {
+        match event {
+            ClientEvent::Message { from, to, text, .. } => {
+                let name = if common::is_room(&to) || from == self.email {
+                    to
+                } else {
+                    from.clone()
+                };
+                self.receive(&name, from, text);
+            }
+            ClientEvent::Ack { .. } => {}
+            ClientEvent::Presence { email, presence } => {
+                self.presence.insert(email, presence);
+            }
+            ClientEvent::Typing {
+                from,
+                conversation,
+                typing,
+            } => {
+                self.status = match typing {
+                    true => format!("{} is typing in {}", from, conversation),
+                    false => String::new(),
+                };
+            }
+            ClientEvent::Read { from, id } => self.status = format!("{} read {}", from, id),
+            ClientEvent::IdentityChanged { email, fingerprint } => {
+                self.status = format!("! new identity key of {}: {}", email, fingerprint)
+            }
+            ClientEvent::FileOffered(offer) => {
+                let text = format!(
+                    "sends {} ({}, {} bytes)",
+                    offer.name, offer.mime, offer.size
+                );
+                let name = match common::is_room(&offer.to) {
+                    true => offer.to.clone(),
+                    false => offer.from.clone(),
+                };
+                self.receive(&name, offer.from, text);
+            }
+            ClientEvent::FileProgress { id, received, size } => {
+                self.status = format!(
+                    "downloading {}: {}%",
+                    id,
+                    client::progress_step(received, size) * 10
+                )
+            }
+            ClientEvent::FileSaved { path, .. } => {
+                self.status = format!("saved {}", path.display())
+            }
+            ClientEvent::FileFailed { reason, .. } => {
+                self.status = format!("! download failed: {}", reason)
+            }
+            ClientEvent::Notice { text } => self.status = format!("! notice: {}", text),
+            ClientEvent::Error { code, msg } => {
+                self.status = format!("! server error ({:?}): {}", code, msg)
+            }
+            ClientEvent::Disconnected { reason } => {
+                self.status = match reason {
+                    Some(reason) => format!("! {}", reason),
+                    None => "! connection lost, reconnecting on the next message".to_string(),
+                }
+            }
+        }
+    }
+}
+
+/// run the full screen client until `/quit` or escape
+pub async fn run(mut client: ChatClient) -> io::Result<()> {
+    let Some(events) = client.events() else {
+        return Err(io::Error::other("events already taken"));
+    };
+
+    let mut terminal = ratatui::init();
+    let res = run_app(&mut terminal, &mut client, events).await;
+    ratatui::restore();
+
+    res
+}
+
+async fn run_app(
+    terminal: &mut DefaultTerminal,
+    client: &mut ChatClient,
+    events: impl Stream<Item = ClientEvent>,
+) -> io::Result<()> {
+    let mut app = App::new(client.email().to_string());
+    let mut events = std::pin::pin!(events);
+    let mut keys = EventStream::new();
+
+    while !app.quit {
+        terminal.draw(|frame| draw(frame, &app, client))?;
+
+        tokio::select! {
+            event = events.next() => match event {
+                Some(event) => app.handle_event(event),
+                None => break,
+            },
+            key = keys.next() => match key {
+                Some(Ok(Event::Key(key))) if key.kind == KeyEventKind::Press => {
+                    handle_key(&mut app, client, key).await
+                }
+                Some(Ok(_)) => {}
+                Some(Err(e)) => return Err(e),
+                None => break,
+            },
+        }
+    }
+
+    Ok(())
+}
+
+async fn handle_key(app: &mut App, client: &mut ChatClient, key: KeyEvent) {
+    match key.code {
+        KeyCode::Esc => app.quit = true,
+        KeyCode::Char('c') if key.modifiers.contains(KeyModifiers::CONTROL) => app.quit = true,
+        KeyCode::Tab => app.cycle(1),
+        KeyCode::BackTab => app.cycle(-1),
+        KeyCode::PageUp => {
+            if let Some(conversation) = app.current() {
+                conversation.scroll += SCROLL_PAGE;
+            }
+        }
+        KeyCode::PageDown => {
+            if let Some(conversation) = app.current() {
+                conversation.scroll = conversation.scroll.saturating_sub(SCROLL_PAGE);
+            }
+        }
+        KeyCode::Backspace => {
+            app.input.pop();
+        }
+        KeyCode::Char(c) => app.input.push(c),
+        KeyCode::Enter => {
+            let line = std::mem::take(&mut app.input);
+            submit(app, client, line.trim()).await;
+        }
+        _ => {}
+    }
+}
+
+async fn submit(app: &mut App, client: &mut ChatClient, line: &str) {
+    let command = match commands::parse(line) {
+        None if line.is_empty() => return,
+        None => return say(app, client, line).await,
+        Some(Err(e)) => {
+            app.status = format!("! {}", e);
+            return;
+        }
+        Some(Ok(command)) => command,
+    };
+
+    let current = app.selected.map(|i| app.conversations[i].name.clone());
+    match command {
+        Command::To(name) => {
+            let email = client.resolve(&name);
+            open(app, client, &email).await
+        }
+        Command::Join(room) => open(app, client, &room).await,
+        Command::Leave | Command::Members => {
+            let Some(room) = current.filter(|name| common::is_room(name)) else {
+                app.status = "! not talking in a room".to_string();
+                return;
+            };
+            if let Command::Members = command {
+                app.status = match client.members(room).await {
+                    Ok(members) => format!("members: {}", members.join(", ")),
+                    Err(e) => format!("! {}", e),
+                };
+                return;
+            }
+            match client.leave(room.clone()).await {
+                Ok(_) => {
+                    app.conversations.retain(|c| c.name != room);
+                    app.selected = None;
+                    app.cycle(0);
+                    app.status = format!("left {}", room);
+                }
+                Err(e) => app.status = format!("! {}", e),
+            }
+        }
+        Command::Who => {
+            app.status = match client.who_online().await {
+                Ok(online) => online
+                    .iter()
+                    .map(|(email, presence)| format!("{} ({})", client.nick(email), presence))
+                    .collect::<Vec<_>>()
+                    .join(", "),
+                Err(e) => format!("! {}", e),
+            }
+        }
+        Command::History(count) => match current {
+            Some(name) => load_history(app, client, &name, count.unwrap_or(HISTORY_PAGE)).await,
+            None => app.status = "! no conversation".to_string(),
+        },
+        Command::Send(path) => {
+            let Some(name) = current else {
+                app.status = "! no conversation".to_string();
+                return;
+            };
+            app.status = match client.send_file(name, &path, |_, _| {}).await {
+                Ok(_) => format!("sent {}", path.display()),
+                Err(e) => format!("! {}", e),
+            };
+        }
+        Command::Nick { email, nick } => client.set_nick(email, nick),
+        Command::Away | Command::Back => {
+            let presence = match command {
+                Command::Away => Presence::Away,
+                _ => Presence::Online,
+            };
+            if let Err(e) = client.set_presence(presence).await {
+                app.status = format!("! {}", e);
+            }
+        }
+        Command::Quit => app.quit = true,
+        Command::Help => app.status = HELP.to_string(),
+    }
+}
+
+/// send `line` to the conversation shown
+async fn say(app: &mut App, client: &mut ChatClient, line: &str) {
+    let Some(name) = app.current().map(|c| c.name.clone()) else {
+        app.status = "! no conversation, /to <email or #room> first".to_string();
+        return;
+    };
+    match client.send(name.clone(), line.to_string()).await {
+        Ok(_) => {
+            if let Some(conversation) = app.current() {
+                conversation.scroll = 0;
+            }
+            // the server echoes what we sent to our other devices only, rooms too
+            let from = app.email.clone();
+            app.receive(&name, from, line.to_string());
+        }
+        Err(e) => app.status = format!("! {}", e),
+    }
+}
+
+/// switch to the conversation with `name`, joining the room or loading its history first
+async fn open(app: &mut App, client: &mut ChatClient, name: &str) {
+    if let Some(i) = app.conversations.iter().position(|c| c.name == name) {
+        app.select(i);
+        return;
+    }
+
+    if common::is_room(name) {
+        if let Err(e) = client.join(name.to_string()).await {
+            app.status = format!("! join {} failed: {}", name, e);
+            return;
+        }
+    }
+
+    load_history(app, client, name, HISTORY_PAGE).await;
+    let (i, _) = app.conversation(name);
+    app.select(i);
+
+    match client.subscribe_presence(app.contacts()).await {
+        Ok(presences) => app.presence.extend(presences),
+        Err(e) => app.status = format!("! follow presence failed: {}", e),
+    }
+}
+
+/// show the last `count` messages with `name` instead of those shown
+async fn load_history(app: &mut App, client: &mut ChatClient, name: &str, count: u32) {
+    let history = match client.history(name.to_string(), None, count).await {
+        Ok(entries) => entries,
+        Err(e) => {
+            app.status = format!("! load history failed: {}", e);
+            vec![]
+        }
+    };
+
+    // they are about to be shown, tell whoever sent the newest of them
+    let newest = history
+        .iter()
+        .rev()
+        .find(|entry| entry.transfer.from != app.email);
+    if let Some(newest) = newest {
+        let read = SignalKind::Read(newest.transfer.id);
+        // not worth bothering the user about
+        let _ = client.signal(newest.transfer.from.clone(), read).await;
+    }
+
+    let (_, conversation) = app.conversation(name);
+    conversation.scroll = 0;
+    conversation.messages = history
+        .iter()
+        .map(|entry| Message {
+            from: entry.transfer.from.clone(),
+            text: client.read(&entry.transfer),
+        })
+        .collect();
+}
+
+fn draw(frame: &mut Frame, app: &App, client: &ChatClient) {
+    let [main, input, status] = Layout::vertical([
+        Constraint::Min(3),
+        Constraint::Length(3),
+        // tall enough for /help
+        Constraint::Length(app.status.lines().count().max(1) as u16),
+    ])
+    .areas(frame.area());
+    let [list, messages] =
+        Layout::horizontal([Constraint::Length(28), Constraint::Min(20)]).areas(main);
+
+    draw_conversations(frame, app, client, list);
+    draw_messages(frame, app, client, messages);
+
+    frame.render_widget(
+        Paragraph::new(app.input.as_str()).block(Block::bordered().title(app.email.as_str())),
+        input,
+    );
+    frame.set_cursor_position((input.x + 1 + app.input.chars().count() as u16, input.y + 1));
+
+    frame.render_widget(
+        Paragraph::new(app.status.as_str()).style(Style::default().fg(Color::DarkGray)),
+        status,
+    );
+}
+
+fn draw_conversations(frame: &mut Frame, app: &App, client: &ChatClient, area: Rect) {
+    let items: Vec<ListItem> = app
+        .conversations
+        .iter()
+        .map(|conversation| {
+            let marker = match app.presence.get(&conversation.name) {
+                _ if common::is_room(&conversation.name) => Span::raw("  "),
+                Some(Presence::Online) => Span::styled("● ", Style::default().fg(Color::Green)),
+                Some(Presence::Away) => Span::styled("◐ ", Style::default().fg(Color::Yellow)),
+                Some(Presence::Offline) | None => {
+                    Span::styled("○ ", Style::default().fg(Color::DarkGray))
+                }
+            };
+            let mut line = vec![marker, Span::raw(client.nick(&conversation.name))];
+            if conversation.unread > 0 {
+                line.push(Span::styled(
+                    format!(" ({})", conversation.unread),
+                    Style::default().add_modifier(Modifier::BOLD),
+                ));
+            }
+            ListItem::new(Line::from(line))
+        })
+        .collect();
+
+    let mut state = ListState::default().with_selected(app.selected);
+    frame.render_stateful_widget(
+        List::new(items)
+            .block(Block::bordered().title("conversations"))
+            .highlight_style(Style::default().add_modifier(Modifier::REVERSED)),
+        area,
+        &mut state,
+    );
+}
+
+fn draw_messages(frame: &mut Frame, app: &App, client: &ChatClient, area: Rect) {
+    let Some(conversation) = app.selected.map(|i| &app.conversations[i]) else {
+        frame.render_widget(Block::bordered(), area);
+        return;
+    };
+
+    // wrapped by hand, to know how many lines there are to scroll
+    let width = area.width.saturating_sub(2).max(1) as usize;
+    let lines: Vec<Line> = conversation
+        .messages
+        .iter()
+        .flat_map(|message| {
+            let text = format!("{}: {}", client.nick(&message.from), message.text);
+            let style = match message.from == app.email {
+                true => Style::default().fg(Color::Cyan),
+                false => Style::default(),
+            };
+            wrap(&text, width)
+                .into_iter()
+                .map(move |line| Line::styled(line, style))
+        })
+        .collect();
+
+    // newest at the bottom, `scroll` lines back from there
+    let height = area.height.saturating_sub(2) as usize;
+    let bottom = lines
+        .len()
+        .saturating_sub(conversation.scroll)
+        .max(height.min(lines.len()));
+    let top = bottom.saturating_sub(height);
+
+    frame.render_widget(
+        Paragraph::new(lines[top..bottom].to_vec())
+            .block(Block::bordered().title(client.nick(&conversation.name))),
+        area,
+    );
+}
+
+/// cut `text` into lines of at most `width` characters
+fn wrap(text: &str, width: usize) -> Vec<String> {
+    let mut lines = vec![];
+    for line in text.lines() {
+        let chars: Vec<char> = line.chars().collect();
+        if chars.is_empty() {
+            lines.push(String::new());
+        }
+        lines.extend(chars.chunks(width).map(|chunk| chunk.iter().collect()));
+    }
+    lines
+}
@@ -0,0 +1,71 @@
+//! requests of the server's admin interface
+//!
+//! an admin connects to the unix socket, writes one request as a line of text,
+//! then reads the answer until the server closes the socket:
+//!
+//! ```text
+//! list
+//! kick <email>
+//! ban <email>
+//! unban <email>
+//! notice <text>
+//! stop
+//! ```
+use std::fmt::Display;
+
+/// the first line of an answer starts with it when the request failed
+pub const ERROR_PREFIX: &str = "error: ";
+
+/// longest request line the server reads
+pub const MAX_REQUEST_LEN: u64 = 4096;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AdminRequest {
+    /// every connected session, its email, remote address and connect time
+    List,
+    /// disconnect every session of an email
+    Kick(String),
+    /// kick, and refuse logging in until unbanned
+    Ban(String),
+    Unban(String),
+    /// tell everyone logged in
+    Notice(String),
+    /// shut the server down, letting clients receive what is buffered
+    Stop,
+}
+
+impl AdminRequest {
+    pub fn parse(line: &str) -> Result<Self, String> {
+        let line = line.trim();
+        let (name, arg) = match line.split_once(char::is_whitespace) {
+            Some((name, arg)) => (name, arg.trim()),
+            None => (line, ""),
+        };
+
+        match (name, arg) {
+            ("list", "") => Ok(AdminRequest::List),
+            ("stop", "") => Ok(AdminRequest::Stop),
+            ("list" | "stop", _) => Err(format!("{} takes no argument", name)),
+            ("kick" | "ban" | "unban" | "notice", "") => Err(format!("{} needs an argument", name)),
+            ("kick", email) => Ok(AdminRequest::Kick(email.to_string())),
+            ("ban", email) => Ok(AdminRequest::Ban(email.to_string())),
+            ("unban", email) => Ok(AdminRequest::Unban(email.to_string())),
+            ("notice", text) => Ok(AdminRequest::Notice(text.to_string())),
+            _ => Err(format!("unknown request: {}", name)),
+        }
+    }
+}
+
+/// the line `parse` reads back, without the line break
+impl Display for AdminRequest {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            AdminRequest::List => write!(f, "list"),
+            AdminRequest::Kick(email) => write!(f, "kick {}", email),
+            AdminRequest::Ban(email) => write!(f, "ban {}", email),
+            AdminRequest::Unban(email) => write!(f, "unban {}", email),
+            AdminRequest::Notice(text) => write!(f, "notice {}", text),
+            AdminRequest::Stop => write!(f, "stop"),
+        }
+    }
+}
@@ -0,0 +1,139 @@
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+use std::fmt::Display;
+
+/// version of the wire format, the first byte of every frame
+pub const PROTOCOL_VERSION: u8 = 1;
+
+/// version byte + u32 payload length
+pub const HEADER_LEN: usize = 5;
+
+/// frames bigger than this are treated as garbage
+pub const MAX_FRAME_LEN: usize = 16 * 1024 * 1024;
+
+/// encode fields into one frame
+/// representing the belowing form:
+///
+/// <version: u8><payload length: u32 BE>
+/// <field length: u32 BE><field bytes>
+/// ...
+///
+/// a payload over `MAX_FRAME_LEN` is refused, the decoder would not take it
+pub fn encode_fields(fields: &[&[u8]]) -> Result<Bytes, CodecError> {
+    let payload_len: usize = fields.iter().map(|f| 4 + f.len()).sum();
+    if payload_len > MAX_FRAME_LEN {
+        return Err(CodecError::FrameTooLarge);
+    }
+
+    let mut buf = BytesMut::with_capacity(HEADER_LEN + payload_len);
+    buf.put_u8(PROTOCOL_VERSION);
+    buf.put_u32(payload_len as u32);
+    for field in fields {
+        buf.put_u32(field.len() as u32);
+        buf.put_slice(field);
+    }
+
+    Ok(buf.freeze())
+}
+
+/// split a complete frame into its fields, checking the header
+pub fn decode_fields(mut frame: Bytes) -> Result<Vec<Bytes>, CodecError> {
+    if frame.len() < HEADER_LEN {
+        return Err(CodecError::Incomplete);
+    }
+
+    let version = frame.get_u8();
+    if version != PROTOCOL_VERSION {
+        return Err(CodecError::UnsupportedVersion(version));
+    }
+
+    let payload_len = frame.get_u32() as usize;
+    if payload_len > MAX_FRAME_LEN {
+        return Err(CodecError::FrameTooLarge);
+    }
+    if payload_len != frame.len() {
+        return Err(CodecError::LengthMismatch);
+    }
+
+    let mut fields = vec![];
+    while frame.has_remaining() {
+        if frame.remaining() < 4 {
+            return Err(CodecError::LengthMismatch);
+        }
+        let len = frame.get_u32() as usize;
+        if len > frame.remaining() {
+            return Err(CodecError::LengthMismatch);
+        }
+        fields.push(frame.split_to(len));
+    }
+
+    Ok(fields)
+}
+
+/// buffers bytes received from a stream, and cuts them into complete frames
+///
+/// a `receive()` chunk may hold several frames or only part of one,
+/// the remainder is kept until the next chunk arrives
+#[derive(Default)]
+pub struct FrameDecoder {
+    buf: BytesMut,
+}
+
+impl FrameDecoder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn extend(&mut self, bytes: &[u8]) {
+        self.buf.extend_from_slice(bytes);
+    }
+
+    /// next complete frame, header included, or `None` if more bytes are needed
+    pub fn next_frame(&mut self) -> Result<Option<Bytes>, CodecError> {
+        if self.buf.len() < HEADER_LEN {
+            return Ok(None);
+        }
+
+        let version = self.buf[0];
+        if version != PROTOCOL_VERSION {
+            return Err(CodecError::UnsupportedVersion(version));
+        }
+
+        let payload_len =
+            u32::from_be_bytes([self.buf[1], self.buf[2], self.buf[3], self.buf[4]]) as usize;
+        if payload_len > MAX_FRAME_LEN {
+            return Err(CodecError::FrameTooLarge);
+        }
+
+        if self.buf.len() < HEADER_LEN + payload_len {
+            return Ok(None);
+        }
+
+        Ok(Some(self.buf.split_to(HEADER_LEN + payload_len).freeze()))
+    }
+
+    /// the bytes not yet returned as a frame, for streams that go on without framing
+    pub fn into_remaining(self) -> Bytes {
+        self.buf.freeze()
+    }
+}
+
+#[derive(Debug)]
+pub enum CodecError {
+    Incomplete,
+    UnsupportedVersion(u8),
+    LengthMismatch,
+    FrameTooLarge,
+}
+
+impl Display for CodecError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            CodecError::Incomplete => write!(f, "incomplete frame"),
+            CodecError::UnsupportedVersion(v) => write!(f, "unsupported protocol version: {}", v),
+            CodecError::LengthMismatch => write!(f, "frame length mismatch"),
+            CodecError::FrameTooLarge => write!(f, "frame too large"),
+        }
+    }
+}
+
+impl std::error::Error for CodecError {}
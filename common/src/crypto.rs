@@ -0,0 +1,332 @@
+//! end-to-end encryption of chat content, the server only ever routes what `seal` returns
+//!
+//! every client has a long-term identity key and a prekey it rotates, both X25519, and publishes
+//! their public halves as a `KeyBundle`. a message is encrypted once with a fresh content key,
+//! that key is wrapped for every recipient with a key derived from
+//! DH(ephemeral, recipient prekey) and DH(sender identity, recipient identity)
+//!
+//! sealed content looks like this:
+//!
+//! <SEALED_PREFIX><version: u8><sender identity: 32><ephemeral: 32><recipients: u8>
+//! <recipient identity: 32><recipient prekey: 32><wrapped content key: 48>
+//! ...
+//! <ciphertext>
+//!
+use std::{fmt::Display, fs, io, path::Path};
+
+use bytes::{BufMut, Bytes, BytesMut};
+use chacha20poly1305::{
+    aead::{Aead, KeyInit, OsRng, Payload},
+    ChaCha20Poly1305, Key, Nonce,
+};
+use hkdf::Hkdf;
+use sha2::{Digest, Sha256};
+use ulid::Ulid;
+use x25519_dalek::{PublicKey, SharedSecret, StaticSecret};
+
+/// sealed content starts with this, text never does
+pub const SEALED_PREFIX: &[u8] = b"\0e2e";
+const VERSION: u8 = 1;
+
+/// prekeys kept to open what was sealed to them, older ones are forgotten
+pub const MAX_PREKEYS: usize = 16;
+
+/// devices of an email whose keys are kept, the one that published longest ago is forgotten
+pub const MAX_DEVICES: usize = 16;
+
+const KEY_LEN: usize = 32;
+/// a content key with the AEAD tag
+const WRAPPED_LEN: usize = KEY_LEN + 16;
+const RECIPIENT_LEN: usize = KEY_LEN * 2 + WRAPPED_LEN;
+const HEADER_LEN: usize = SEALED_PREFIX.len() + 1 + KEY_LEN * 2 + 1;
+
+/// the public keys a client publishes, for others to seal messages to it
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct KeyBundle {
+    pub identity: [u8; 32],
+    pub prekey: [u8; 32],
+}
+
+/// the secret keys of a client
+pub struct Keys {
+    identity: StaticSecret,
+    /// newest last, it is the one published
+    prekeys: Vec<StaticSecret>,
+}
+
+impl Keys {
+    pub fn generate() -> Self {
+        Self {
+            identity: StaticSecret::random_from_rng(OsRng),
+            prekeys: vec![StaticSecret::random_from_rng(OsRng)],
+        }
+    }
+
+    /// the keys saved at `path`, generated and saved there if there are none
+    pub fn load_or_generate(path: impl AsRef<Path>) -> io::Result<Self> {
+        let path = path.as_ref();
+        match fs::read(path) {
+            Ok(bytes) => Self::from_bytes(&bytes)
+                .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "corrupt key file")),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                let keys = Self::generate();
+                keys.save(path)?;
+                Ok(keys)
+            }
+            Err(e) => Err(e),
+        }
+    }
+
+    /// the identity key followed by the prekeys, readable by the owner only
+    pub fn save(&self, path: impl AsRef<Path>) -> io::Result<()> {
+        let mut bytes = self.identity.to_bytes().to_vec();
+        for prekey in &self.prekeys {
+            bytes.extend_from_slice(prekey.as_bytes());
+        }
+
+        let mut options = fs::OpenOptions::new();
+        options.create(true).write(true).truncate(true);
+        #[cfg(unix)]
+        std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+
+        io::Write::write_all(&mut options.open(path)?, &bytes)
+    }
+
+    fn from_bytes(bytes: &[u8]) -> Option<Self> {
+        if bytes.len() < KEY_LEN * 2 || bytes.len() % KEY_LEN != 0 {
+            return None;
+        }
+
+        let mut keys = bytes
+            .chunks(KEY_LEN)
+            .map(|key| StaticSecret::from(<[u8; KEY_LEN]>::try_from(key).unwrap_or_default()));
+        let identity = keys.next()?;
+        Some(Self {
+            identity,
+            prekeys: keys.collect(),
+        })
+    }
+
+    pub fn bundle(&self) -> KeyBundle {
+        KeyBundle {
+            identity: PublicKey::from(&self.identity).to_bytes(),
+            prekey: self.prekey().map(|p| p.to_bytes()).unwrap_or_default(),
+        }
+    }
+
+    fn prekey(&self) -> Option<PublicKey> {
+        self.prekeys.last().map(PublicKey::from)
+    }
+
+    /// start publishing a new prekey, keeping the last `MAX_PREKEYS`
+    pub fn rotate_prekey(&mut self) {
+        self.prekeys.push(StaticSecret::random_from_rng(OsRng));
+        if self.prekeys.len() > MAX_PREKEYS {
+            self.prekeys.remove(0);
+        }
+    }
+}
+
+pub fn is_sealed(content: &[u8]) -> bool {
+    content.starts_with(SEALED_PREFIX)
+}
+
+/// what a `Transfer` is sealed with besides its content, so it cannot be passed off as another
+pub fn associated_data(id: Ulid, from: &str, to: &str) -> Vec<u8> {
+    let mut aad = id.to_bytes().to_vec();
+    aad.extend_from_slice(from.as_bytes());
+    aad.push(0);
+    aad.extend_from_slice(to.as_bytes());
+    aad
+}
+
+/// short, human comparable form of an identity key
+pub fn fingerprint(identity: &[u8; 32]) -> String {
+    Sha256::digest(identity)[..8]
+        .chunks(2)
+        .map(|pair| format!("{:02x}{:02x}", pair[0], pair[1]))
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// encrypt `plaintext` so only the owners of `recipients` can read it
+pub fn seal(
+    keys: &Keys,
+    recipients: &[KeyBundle],
+    aad: &[u8],
+    plaintext: &[u8],
+) -> Result<Bytes, CryptoError> {
+    let count = u8::try_from(recipients.len()).map_err(|_| CryptoError::TooManyRecipients)?;
+
+    let ephemeral = StaticSecret::random_from_rng(OsRng);
+    let ephemeral_public = PublicKey::from(&ephemeral);
+    let sender = PublicKey::from(&keys.identity);
+
+    // every key is used for a single message, so the nonce can be fixed
+    let content_key = ChaCha20Poly1305::generate_key(&mut OsRng);
+    let ciphertext = ChaCha20Poly1305::new(&content_key)
+        .encrypt(
+            &Nonce::default(),
+            Payload {
+                msg: plaintext,
+                aad,
+            },
+        )
+        .map_err(|_| CryptoError::Encrypt)?;
+
+    let mut sealed =
+        BytesMut::with_capacity(HEADER_LEN + recipients.len() * RECIPIENT_LEN + ciphertext.len());
+    sealed.put_slice(SEALED_PREFIX);
+    sealed.put_u8(VERSION);
+    sealed.put_slice(sender.as_bytes());
+    sealed.put_slice(ephemeral_public.as_bytes());
+    sealed.put_u8(count);
+
+    for recipient in recipients {
+        let wrap_key = wrap_key(
+            ephemeral.diffie_hellman(&PublicKey::from(recipient.prekey)),
+            keys.identity
+                .diffie_hellman(&PublicKey::from(recipient.identity)),
+            &ephemeral_public,
+            recipient,
+        )?;
+        let wrapped = ChaCha20Poly1305::new(&wrap_key)
+            .encrypt(&Nonce::default(), content_key.as_slice())
+            .map_err(|_| CryptoError::Encrypt)?;
+
+        sealed.put_slice(&recipient.identity);
+        sealed.put_slice(&recipient.prekey);
+        sealed.put_slice(&wrapped);
+    }
+    sealed.put_slice(&ciphertext);
+
+    Ok(sealed.freeze())
+}
+
+/// what `open` found in sealed content
+#[derive(Debug)]
+pub struct Opened {
+    /// identity key of who sealed it
+    pub sender: [u8; 32],
+    pub plaintext: Vec<u8>,
+}
+
+/// decrypt content sealed to `keys` with the same `aad`
+pub fn open(keys: &Keys, aad: &[u8], sealed: &[u8]) -> Result<Opened, CryptoError> {
+    if sealed.len() < HEADER_LEN || !is_sealed(sealed) {
+        return Err(CryptoError::Malformed);
+    }
+    let header = &sealed[SEALED_PREFIX.len()..HEADER_LEN];
+    if header[0] != VERSION {
+        return Err(CryptoError::Malformed);
+    }
+    let sender = key_at(header, 1)?;
+    let ephemeral = PublicKey::from(key_at(header, 1 + KEY_LEN)?);
+    let count = header[1 + KEY_LEN * 2] as usize;
+
+    let body = &sealed[HEADER_LEN..];
+    if body.len() < count * RECIPIENT_LEN {
+        return Err(CryptoError::Malformed);
+    }
+    let (entries, ciphertext) = body.split_at(count * RECIPIENT_LEN);
+
+    let identity = PublicKey::from(&keys.identity).to_bytes();
+    let (bundle, wrapped, prekey) = entries
+        .chunks(RECIPIENT_LEN)
+        .find_map(|entry| {
+            let bundle = KeyBundle {
+                identity: key_at(entry, 0).ok()?,
+                prekey: key_at(entry, KEY_LEN).ok()?,
+            };
+            if bundle.identity != identity {
+                return None;
+            }
+            let prekey = keys
+                .prekeys
+                .iter()
+                .find(|prekey| PublicKey::from(*prekey).to_bytes() == bundle.prekey)?;
+            Some((bundle, &entry[KEY_LEN * 2..], prekey))
+        })
+        .ok_or(CryptoError::NotForUs)?;
+
+    let wrap_key = wrap_key(
+        prekey.diffie_hellman(&ephemeral),
+        keys.identity.diffie_hellman(&PublicKey::from(sender)),
+        &ephemeral,
+        &bundle,
+    )?;
+    let content_key = ChaCha20Poly1305::new(&wrap_key)
+        .decrypt(&Nonce::default(), wrapped)
+        .map_err(|_| CryptoError::Decrypt)?;
+    if content_key.len() != KEY_LEN {
+        return Err(CryptoError::Decrypt);
+    }
+
+    let plaintext = ChaCha20Poly1305::new(Key::from_slice(&content_key))
+        .decrypt(
+            &Nonce::default(),
+            Payload {
+                msg: ciphertext,
+                aad,
+            },
+        )
+        .map_err(|_| CryptoError::Decrypt)?;
+
+    Ok(Opened { sender, plaintext })
+}
+
+fn key_at(bytes: &[u8], at: usize) -> Result<[u8; 32], CryptoError> {
+    bytes
+        .get(at..at + KEY_LEN)
+        .and_then(|key| <[u8; KEY_LEN]>::try_from(key).ok())
+        .ok_or(CryptoError::Malformed)
+}
+
+/// the key a content key is wrapped with for `recipient`
+fn wrap_key(
+    prekey_secret: SharedSecret,
+    identity_secret: SharedSecret,
+    ephemeral: &PublicKey,
+    recipient: &KeyBundle,
+) -> Result<Key, CryptoError> {
+    // a low order point would make the secret known to anyone
+    if !prekey_secret.was_contributory() || !identity_secret.was_contributory() {
+        return Err(CryptoError::Malformed);
+    }
+
+    let mut secret = prekey_secret.to_bytes().to_vec();
+    secret.extend_from_slice(identity_secret.as_bytes());
+    let mut info = b"chat e2e wrap".to_vec();
+    info.extend_from_slice(&recipient.identity);
+    info.extend_from_slice(&recipient.prekey);
+
+    let mut key = Key::default();
+    Hkdf::<Sha256>::new(Some(ephemeral.as_bytes()), &secret)
+        .expand(&info, &mut key)
+        .map_err(|_| CryptoError::Malformed)?;
+    Ok(key)
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum CryptoError {
+    Malformed,
+    /// sealed to keys other than ours, or to a prekey long forgotten
+    NotForUs,
+    Encrypt,
+    Decrypt,
+    TooManyRecipients,
+}
+
+impl Display for CryptoError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            CryptoError::Malformed => write!(f, "malformed sealed content"),
+            CryptoError::NotForUs => write!(f, "not sealed to our keys"),
+            CryptoError::Encrypt => write!(f, "encrypt failed"),
+            CryptoError::Decrypt => write!(f, "decrypt failed"),
+            CryptoError::TooManyRecipients => write!(f, "too many recipients"),
+        }
+    }
+}
+
+impl std::error::Error for CryptoError {}
@@ -0,0 +1,104 @@
+//! the error of everything talking the protocol, the errors of each part convert into it
+use std::{fmt::Display, io};
+
+use crate::{
+    codec::CodecError,
+    crypto::CryptoError,
+    protocol::{ErrorCode, ProtocolError},
+    ClientChangeError, TransferError, ROOM_PREFIX,
+};
+
+#[derive(Debug)]
+pub enum Error {
+    Io(io::Error),
+    /// bytes that do not decode as a frame
+    Protocol(ProtocolError),
+    Transfer(TransferError),
+    ClientChange(ClientChangeError),
+    Crypto(CryptoError),
+    /// a frame that does not belong where it arrived, the name of the frame
+    UnexpectedFrame(&'static str),
+    NotLoggedIn,
+    InvalidRoom(String),
+}
+
+impl Error {
+    /// what `Frame::Error` tells the peer
+    pub fn code(&self) -> ErrorCode {
+        match self {
+            Error::Protocol(_) | Error::InvalidRoom(_) => ErrorCode::InvalidFrame,
+            Error::Transfer(e) => e.into(),
+            Error::UnexpectedFrame(_) => ErrorCode::UnexpectedFrame,
+            Error::NotLoggedIn => ErrorCode::NotLoggedIn,
+            Error::Io(_) | Error::ClientChange(_) | Error::Crypto(_) => ErrorCode::Internal,
+        }
+    }
+}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Error::Io(e) => write!(f, "{}", e),
+            Error::Protocol(e) => write!(f, "invalid frame: {}", e),
+            Error::Transfer(e) => write!(f, "{}", e),
+            Error::ClientChange(e) => write!(f, "{}", e),
+            Error::Crypto(e) => write!(f, "{}", e),
+            Error::UnexpectedFrame(name) => write!(f, "unexpected frame: {}", name),
+            Error::NotLoggedIn => write!(f, "not logged in"),
+            Error::InvalidRoom(room) => write!(
+                f,
+                "invalid room name: {}, must start with {}",
+                room, ROOM_PREFIX
+            ),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Io(e) => Some(e),
+            Error::Protocol(e) => Some(e),
+            Error::Transfer(e) => Some(e),
+            Error::ClientChange(e) => Some(e),
+            Error::Crypto(e) => Some(e),
+            Error::UnexpectedFrame(_) | Error::NotLoggedIn | Error::InvalidRoom(_) => None,
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(value: io::Error) -> Self {
+        Error::Io(value)
+    }
+}
+
+impl From<CodecError> for Error {
+    fn from(value: CodecError) -> Self {
+        Error::Protocol(ProtocolError::Codec(value))
+    }
+}
+
+impl From<ProtocolError> for Error {
+    fn from(value: ProtocolError) -> Self {
+        Error::Protocol(value)
+    }
+}
+
+impl From<TransferError> for Error {
+    fn from(value: TransferError) -> Self {
+        Error::Transfer(value)
+    }
+}
+
+impl From<ClientChangeError> for Error {
+    fn from(value: ClientChangeError) -> Self {
+        Error::ClientChange(value)
+    }
+}
+
+impl From<CryptoError> for Error {
+    fn from(value: CryptoError) -> Self {
+        Error::Crypto(value)
+    }
+}
@@ -1,77 +1,279 @@
 use actix::prelude::*;
 use bytes::Bytes;
-use std::fmt::Display;
+use sha2::{Digest, Sha256};
+use std::{fmt::Display, fs::File, io, path::Path};
+use ulid::Ulid;
+
+pub mod admin;
+pub mod codec;
+pub mod crypto;
+mod error;
+pub mod protocol;
+
+pub use error::Error;
 
 #[derive(Message)]
-#[rtype(result = "Result<(), ClientChangeError>")]
+#[rtype(result = "Result<SessionIdentity, ClientChangeError>")]
 pub enum ClientChange {
     UpdateEmail(String, String),
+    /// the session registered as the first email takes over the identity the token was issued for
+    Resume(String, String),
+}
+
+/// who a session is logged in as, and the token to resume that after losing the connection
+#[derive(Debug, Clone)]
+pub struct SessionIdentity {
+    pub email: String,
+    pub token: String,
 }
 
 #[derive(Debug)]
 pub enum ClientChangeError {
-    NewEmailAlreadyExisted,
+    UnknownSession,
+    InvalidResumeToken,
+    Banned,
 }
 
 impl Display for ClientChangeError {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         let msg = match self {
-            ClientChangeError::NewEmailAlreadyExisted => "new email already exsited",
+            ClientChangeError::UnknownSession => "session not registered",
+            ClientChangeError::InvalidResumeToken => "resume token invalid or expired",
+            ClientChangeError::Banned => "banned",
         };
 
         write!(f, "{}", msg)
     }
 }
 
-#[derive(Message)]
-#[rtype(result = "Result<(), TransferError>")]
+impl std::error::Error for ClientChangeError {}
+
+#[derive(Message, Debug, Clone)]
+#[rtype(result = "Result<Delivery, TransferError>")]
 pub struct Transfer {
+    /// generated by the sender, acks refer to it
+    pub id: Ulid,
     pub from: String,
+    /// an email, or a room name starting with `ROOM_PREFIX`
     pub to: String,
     pub content: Bytes,
 }
 
+/// `Transfer::to` starting with this addresses a room instead of a client
+pub const ROOM_PREFIX: char = '#';
+
 impl Transfer {
-    /// convert Transfer to bytes
-    /// representing the belowing form:
-    ///
-    /// <from email>\n
-    /// <to email>\n
-    /// <content>
-    ///
-    pub fn to_bytes(self) -> Bytes {
-        let mut tb = String::from(self.from);
-        tb.push('\n');
-        tb.push_str(&self.to);
-        tb.push('\n');
-        tb.push_str(&String::from_utf8(self.content.to_vec()).unwrap());
-
-        tb.into()
+    /// the room this transfer is addressed to, if any
+    pub fn room(&self) -> Option<&str> {
+        if is_room(&self.to) {
+            Some(&self.to)
+        } else {
+            None
+        }
+    }
+
+    /// the room, or whoever `of` talks to, that this transfer belongs to
+    pub fn conversation(&self, of: &str) -> &str {
+        match self.room() {
+            Some(room) => room,
+            None if self.from == of => &self.to,
+            None => &self.from,
+        }
     }
 }
 
-impl std::convert::TryFrom<Bytes> for Transfer {
-    type Error = TransferError;
+pub fn is_room(name: &str) -> bool {
+    name.len() > 1 && name.starts_with(ROOM_PREFIX)
+}
 
-    fn try_from(value: Bytes) -> Result<Self, Self::Error> {
-        let infos = value.split(|v| *v == b'\n').collect::<Vec<_>>();
-        if infos.len() != 3 {
-            Err(TransferError::ConvertFromBytesFail)
+/// `local@domain` with both parts present, so that no account is named like a room,
+/// nor a certificate's host name taken for an account
+pub fn is_email(name: &str) -> bool {
+    match name.split_once('@') {
+        Some((local, domain)) => {
+            !local.is_empty()
+                && !domain.is_empty()
+                && !domain.contains('@')
+                && !name.starts_with(ROOM_PREFIX)
+                && !name.chars().any(|c| c.is_whitespace() || c.is_control())
+        }
+        None => false,
+    }
+}
+
+/// routed like a `Transfer`, but never stored nor acked, dropped if nobody is there to see it
+#[derive(Message, Debug, Clone, PartialEq, Eq)]
+#[rtype(result = "()")]
+pub struct Signal {
+    pub from: String,
+    /// an email, or a room name starting with `ROOM_PREFIX`
+    pub to: String,
+    pub kind: SignalKind,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SignalKind {
+    TypingStarted,
+    TypingStopped,
+    /// the `Transfer` with this id was shown to `from`
+    Read(Ulid),
+}
+
+impl Signal {
+    pub fn room(&self) -> Option<&str> {
+        if is_room(&self.to) {
+            Some(&self.to)
         } else {
-            Ok(Self {
-                from: String::from_utf8(infos[0].to_vec()).unwrap(),
-                to: String::from_utf8(infos[1].to_vec()).unwrap(),
-                content: Bytes::copy_from_slice(infos[2]),
-            })
+            None
+        }
+    }
+}
+
+/// a file `from` wants to send to an email or a room, its bytes go on a stream of their own,
+/// see `protocol::Frame::FileData`
+#[derive(Message, Debug, Clone, PartialEq, Eq)]
+#[rtype(result = "()")]
+pub struct FileOffer {
+    pub id: Ulid,
+    pub from: String,
+    pub to: String,
+    /// file name only, never a path
+    pub name: String,
+    pub size: u64,
+    pub mime: String,
+    pub sha256: [u8; 32],
+}
+
+impl FileOffer {
+    /// `name` without anything that would make it a path
+    pub fn file_name(&self) -> String {
+        let name = Path::new(&self.name)
+            .file_name()
+            .map(|name| name.to_string_lossy().to_string())
+            .unwrap_or_default();
+
+        match name.as_str() {
+            "" | "." | ".." => self.id.to_string(),
+            _ => name,
         }
     }
 }
 
+pub fn sha256_file(path: impl AsRef<Path>) -> io::Result<[u8; 32]> {
+    let mut hasher = Sha256::new();
+    io::copy(&mut File::open(path)?, &mut hasher)?;
+
+    Ok(hasher.finalize().into())
+}
+
+/// a routed `Transfer` as the server recorded it
+#[derive(Debug, Clone)]
+pub struct HistoryEntry {
+    /// when the server routed it, milliseconds since UNIX epoch
+    pub at: u64,
+    pub transfer: Transfer,
+}
+
+/// ask the server for the conversation between `of` and `peer`, see `protocol::Frame::HistoryRequest`
+#[derive(Message)]
+#[rtype(result = "Vec<HistoryEntry>")]
+pub struct HistoryQuery {
+    pub of: String,
+    pub peer: String,
+    pub before: Option<Ulid>,
+    pub limit: usize,
+}
+
+/// add `email` to `room`, creating it if needed, results in the members
+#[derive(Message)]
+#[rtype(result = "Vec<String>")]
+pub struct JoinRoom {
+    pub room: String,
+    pub email: String,
+}
+
+/// remove `email` from `room`, results in the members left
+#[derive(Message)]
+#[rtype(result = "Vec<String>")]
+pub struct LeaveRoom {
+    pub room: String,
+    pub email: String,
+}
+
+/// members of `room`, empty if it does not exist
+#[derive(Message)]
+#[rtype(result = "Vec<String>")]
+pub struct ListMembers {
+    pub room: String,
+}
+
+/// messages about a single room
+pub trait RoomMessage {
+    fn room(&self) -> &str;
+}
+
+impl RoomMessage for JoinRoom {
+    fn room(&self) -> &str {
+        &self.room
+    }
+}
+
+impl RoomMessage for LeaveRoom {
+    fn room(&self) -> &str {
+        &self.room
+    }
+}
+
+impl RoomMessage for ListMembers {
+    fn room(&self) -> &str {
+        &self.room
+    }
+}
+
+/// what happened to a `Transfer` the server accepted
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Delivery {
+    /// handed to the recipient's session
+    Delivered,
+    /// recipient offline, kept until they log in
+    Queued,
+}
+
+/// whether an email has a session logged in, and if its user is around
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Presence {
+    Online,
+    /// logged in, but every device said its user stepped away
+    Away,
+    Offline,
+}
+
+impl Display for Presence {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let presence = match self {
+            Presence::Online => "online",
+            Presence::Away => "away",
+            Presence::Offline => "offline",
+        };
+
+        write!(f, "{}", presence)
+    }
+}
+
 #[derive(Debug)]
 pub enum TransferError {
     DestinationClientOffline,
+    DestinationUnavailable,
+    StoreFailed,
+    NotRoomMember,
     ContentNotUTF8,
     ConvertFromBytesFail,
+    /// the recipient does not keep up, its queue is full
+    DestinationBusy,
+    /// no account is named so, nothing is queued for it
+    UnknownRecipient,
+    /// the recipient is offline with as much queued as it may have
+    QueueFull,
 }
 
 impl Display for TransferError {
@@ -80,14 +282,22 @@ impl Display for TransferError {
 
         let msg = match self {
             DestinationClientOffline => "destination client offline",
+            DestinationUnavailable => "destination client unavailable",
+            StoreFailed => "store transfer for offline client failed",
+            NotRoomMember => "not a member of the room",
             ContentNotUTF8 => "transfer content not UTF-8 encoding",
             ConvertFromBytesFail => "convert from bytes failed",
+            DestinationBusy => "destination client not keeping up",
+            UnknownRecipient => "no such account",
+            QueueFull => "too much queued for the offline destination",
         };
 
         write!(f, "{}", msg)
     }
 }
 
+impl std::error::Error for TransferError {}
+
 #[derive(Message)]
 #[rtype(result = "()")]
 pub struct Stop;
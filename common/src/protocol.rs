@@ -0,0 +1,778 @@
+use bytes::Bytes;
+use std::fmt::Display;
+
+use ulid::Ulid;
+
+use crate::{
+    codec::{self, CodecError},
+    crypto::KeyBundle,
+    FileOffer, HistoryEntry, Presence, Signal, SignalKind, Transfer, TransferError,
+};
+
+/// everything sent between client and server is one of these,
+/// encoded as a codec frame whose first field is the tag
+///
+/// the client opens one bidirectional control stream first, carrying authentication,
+/// requests and their replies, acks, errors and `Shutdown`. `Chat` frames travel on
+/// unidirectional streams, one per conversation, opened on demand by the side sending.
+/// every file gets a unidirectional stream too, a `FileData` frame followed by the raw bytes.
+/// `Signal` frames go on one more unidirectional stream each side opens on demand,
+/// so typing and read receipts never wait behind chat
+///
+/// sending a file: `FileOffer` to the server, answered by `FileAccept` with the offset to
+/// stream from (more than 0 when resuming) or `FileReject`, then the file stream, then an `Ack`
+/// once the server checked it. receiving: the server sends `FileOffer`, the client answers
+/// `FileAccept` with the offset it has, and the server opens the file stream
+#[derive(Debug)]
+pub enum Frame {
+    Login {
+        email: String,
+        password: String,
+    },
+    /// create an account, answered like `Login`
+    Register {
+        email: String,
+        password: String,
+    },
+    /// use the identity proved by the client certificate, answered like `Login`
+    CertificateLogin,
+    /// continue the identity of a lost connection, answered like `Login`
+    Resume {
+        token: String,
+    },
+    /// `email` is the identity the session is logged in as,
+    /// `token` resumes it once with `Resume` should the connection be lost
+    LoginOk {
+        email: String,
+        token: String,
+    },
+    LoginRejected {
+        reason: String,
+    },
+    Chat(Transfer),
+    Ack {
+        id: Ulid,
+        status: AckStatus,
+    },
+    Error {
+        code: ErrorCode,
+        msg: String,
+    },
+    Ping,
+    Pong,
+    Logout,
+    /// ask for up to `limit` messages with `peer` sent before `before`, newest when `None`
+    HistoryRequest {
+        id: Ulid,
+        peer: String,
+        before: Option<Ulid>,
+        limit: u32,
+    },
+    /// reply to the `HistoryRequest` with the same id, oldest first
+    History {
+        id: Ulid,
+        entries: Vec<HistoryEntry>,
+    },
+    RoomJoin {
+        id: Ulid,
+        room: String,
+    },
+    RoomLeave {
+        id: Ulid,
+        room: String,
+    },
+    RoomMembersRequest {
+        id: Ulid,
+        room: String,
+    },
+    /// reply to any room request with the same id, the members after it
+    RoomMembers {
+        id: Ulid,
+        room: String,
+        members: Vec<String>,
+    },
+    /// the server is going down, the connection is closed with `SHUTDOWN_CLOSE_CODE` next
+    Shutdown {
+        reason: String,
+    },
+    /// a message from the server's operators to everyone logged in
+    Notice {
+        text: String,
+    },
+    FileOffer(FileOffer),
+    /// stream the file `id` from `offset` on
+    FileAccept {
+        id: Ulid,
+        offset: u64,
+    },
+    FileReject {
+        id: Ulid,
+        reason: String,
+    },
+    /// first frame of a file stream, the bytes of the file from `offset` on follow until its end
+    FileData {
+        id: Ulid,
+        offset: u64,
+    },
+    /// the user of this device is around or not, `Offline` counts as `Away`
+    PresenceSet {
+        status: Presence,
+    },
+    /// follow the presence of `contacts` with `PresenceUpdate`, replacing the ones followed before,
+    /// answered by `Presences` with their current presence
+    PresenceSubscribe {
+        id: Ulid,
+        contacts: Vec<String>,
+    },
+    /// everyone logged in, answered by `Presences`
+    WhoOnlineRequest {
+        id: Ulid,
+    },
+    /// reply to a presence request with the same id
+    Presences {
+        id: Ulid,
+        entries: Vec<(String, Presence)>,
+    },
+    /// the presence of a followed contact changed
+    PresenceUpdate {
+        email: String,
+        status: Presence,
+    },
+    Signal(Signal),
+    /// make `bundle` the keys others seal messages to us with, answered by `Ack`
+    KeysPublish {
+        id: Ulid,
+        bundle: KeyBundle,
+    },
+    KeysRequest {
+        id: Ulid,
+        email: String,
+    },
+    /// reply to the `KeysRequest` with the same id, the keys of every device of `email`,
+    /// none if it published none
+    Keys {
+        id: Ulid,
+        email: String,
+        bundles: Vec<KeyBundle>,
+    },
+}
+
+/// QUIC application error code the server closes connections with when it shuts down
+pub const SHUTDOWN_CLOSE_CODE: u32 = 1;
+
+/// QUIC application error code of connections refused for connecting too often, or banned
+pub const REFUSED_CLOSE_CODE: u32 = 2;
+
+mod tag {
+    pub const LOGIN: u8 = 1;
+    pub const LOGIN_OK: u8 = 2;
+    pub const LOGIN_REJECTED: u8 = 3;
+    pub const CHAT: u8 = 4;
+    pub const ACK: u8 = 5;
+    pub const ERROR: u8 = 6;
+    pub const PING: u8 = 7;
+    pub const PONG: u8 = 8;
+    pub const LOGOUT: u8 = 9;
+    pub const HISTORY_REQUEST: u8 = 10;
+    pub const HISTORY: u8 = 11;
+    pub const REGISTER: u8 = 12;
+    pub const CERTIFICATE_LOGIN: u8 = 13;
+    pub const ROOM_JOIN: u8 = 14;
+    pub const ROOM_LEAVE: u8 = 15;
+    pub const ROOM_MEMBERS_REQUEST: u8 = 16;
+    pub const ROOM_MEMBERS: u8 = 17;
+    pub const RESUME: u8 = 18;
+    pub const SHUTDOWN: u8 = 19;
+    pub const FILE_OFFER: u8 = 20;
+    pub const FILE_ACCEPT: u8 = 21;
+    pub const FILE_REJECT: u8 = 22;
+    pub const FILE_DATA: u8 = 23;
+    pub const PRESENCE_SET: u8 = 24;
+    pub const PRESENCE_SUBSCRIBE: u8 = 25;
+    pub const WHO_ONLINE_REQUEST: u8 = 26;
+    pub const PRESENCES: u8 = 27;
+    pub const PRESENCE_UPDATE: u8 = 28;
+    pub const SIGNAL: u8 = 29;
+    pub const KEYS_PUBLISH: u8 = 30;
+    pub const KEYS_REQUEST: u8 = 31;
+    pub const KEYS: u8 = 32;
+    pub const NOTICE: u8 = 33;
+
+    /// the highest tag in use, tags up to here are known
+    pub const LAST: u8 = NOTICE;
+}
+
+impl Frame {
+    /// variant name, for logs and errors that must not print the fields (passwords)
+    pub fn name(&self) -> &'static str {
+        match self {
+            Frame::Login { .. } => "Login",
+            Frame::Register { .. } => "Register",
+            Frame::CertificateLogin => "CertificateLogin",
+            Frame::Resume { .. } => "Resume",
+            Frame::LoginOk { .. } => "LoginOk",
+            Frame::LoginRejected { .. } => "LoginRejected",
+            Frame::Chat(_) => "Chat",
+            Frame::Ack { .. } => "Ack",
+            Frame::Error { .. } => "Error",
+            Frame::Ping => "Ping",
+            Frame::Pong => "Pong",
+            Frame::Logout => "Logout",
+            Frame::HistoryRequest { .. } => "HistoryRequest",
+            Frame::History { .. } => "History",
+            Frame::RoomJoin { .. } => "RoomJoin",
+            Frame::RoomLeave { .. } => "RoomLeave",
+            Frame::RoomMembersRequest { .. } => "RoomMembersRequest",
+            Frame::RoomMembers { .. } => "RoomMembers",
+            Frame::Shutdown { .. } => "Shutdown",
+            Frame::Notice { .. } => "Notice",
+            Frame::FileOffer(_) => "FileOffer",
+            Frame::FileAccept { .. } => "FileAccept",
+            Frame::FileReject { .. } => "FileReject",
+            Frame::FileData { .. } => "FileData",
+            Frame::PresenceSet { .. } => "PresenceSet",
+            Frame::PresenceSubscribe { .. } => "PresenceSubscribe",
+            Frame::WhoOnlineRequest { .. } => "WhoOnlineRequest",
+            Frame::Presences { .. } => "Presences",
+            Frame::PresenceUpdate { .. } => "PresenceUpdate",
+            Frame::Signal(_) => "Signal",
+            Frame::KeysPublish { .. } => "KeysPublish",
+            Frame::KeysRequest { .. } => "KeysRequest",
+            Frame::Keys { .. } => "Keys",
+        }
+    }
+
+    /// id of the request this frame answers, if it is a reply
+    pub fn reply_id(&self) -> Option<Ulid> {
+        match self {
+            Frame::Ack { id, .. }
+            | Frame::History { id, .. }
+            | Frame::RoomMembers { id, .. }
+            | Frame::FileAccept { id, .. }
+            | Frame::FileReject { id, .. }
+            | Frame::Presences { id, .. }
+            | Frame::Keys { id, .. } => Some(*id),
+            _ => None,
+        }
+    }
+
+    /// convert Frame to one codec frame
+    /// representing the belowing fields:
+    ///
+    /// <tag>
+    /// <variant fields>...
+    ///
+    pub fn to_bytes(&self) -> Result<Bytes, CodecError> {
+        match self {
+            Frame::Login { email, password } => {
+                codec::encode_fields(&[&[tag::LOGIN], email.as_bytes(), password.as_bytes()])
+            }
+            Frame::Register { email, password } => {
+                codec::encode_fields(&[&[tag::REGISTER], email.as_bytes(), password.as_bytes()])
+            }
+            Frame::CertificateLogin => codec::encode_fields(&[&[tag::CERTIFICATE_LOGIN]]),
+            Frame::Resume { token } => codec::encode_fields(&[&[tag::RESUME], token.as_bytes()]),
+            Frame::LoginOk { email, token } => {
+                codec::encode_fields(&[&[tag::LOGIN_OK], email.as_bytes(), token.as_bytes()])
+            }
+            Frame::LoginRejected { reason } => {
+                codec::encode_fields(&[&[tag::LOGIN_REJECTED], reason.as_bytes()])
+            }
+            Frame::Chat(transfer) => encode_chat(transfer),
+            Frame::Ack { id, status } => {
+                let (status, code) = match status {
+                    AckStatus::Delivered => (0u8, 0u16),
+                    AckStatus::Queued => (1, 0),
+                    AckStatus::Failed(code) => (2, *code as u16),
+                };
+                codec::encode_fields(&[&[tag::ACK], &id.to_bytes(), &[status], &code.to_be_bytes()])
+            }
+            Frame::Error { code, msg } => codec::encode_fields(&[
+                &[tag::ERROR],
+                &(*code as u16).to_be_bytes(),
+                msg.as_bytes(),
+            ]),
+            Frame::Ping => codec::encode_fields(&[&[tag::PING]]),
+            Frame::Pong => codec::encode_fields(&[&[tag::PONG]]),
+            Frame::Logout => codec::encode_fields(&[&[tag::LOGOUT]]),
+            Frame::HistoryRequest {
+                id,
+                peer,
+                before,
+                limit,
+            } => {
+                // an empty field means no upper bound
+                let before = before.map(|b| b.to_bytes());
+                codec::encode_fields(&[
+                    &[tag::HISTORY_REQUEST],
+                    &id.to_bytes(),
+                    peer.as_bytes(),
+                    before.as_ref().map_or(&[], |b| b),
+                    &limit.to_be_bytes(),
+                ])
+            }
+            Frame::History { id, entries } => {
+                let id = id.to_bytes();
+                let entries = entries
+                    .iter()
+                    .map(|e| Ok((e.at.to_be_bytes(), encode_chat(&e.transfer)?)))
+                    .collect::<Result<Vec<_>, CodecError>>()?;
+
+                let mut fields: Vec<&[u8]> = vec![&[tag::HISTORY], &id];
+                for (at, chat) in &entries {
+                    fields.push(at);
+                    fields.push(chat);
+                }
+                codec::encode_fields(&fields)
+            }
+            Frame::RoomJoin { id, room } => {
+                codec::encode_fields(&[&[tag::ROOM_JOIN], &id.to_bytes(), room.as_bytes()])
+            }
+            Frame::RoomLeave { id, room } => {
+                codec::encode_fields(&[&[tag::ROOM_LEAVE], &id.to_bytes(), room.as_bytes()])
+            }
+            Frame::RoomMembersRequest { id, room } => codec::encode_fields(&[
+                &[tag::ROOM_MEMBERS_REQUEST],
+                &id.to_bytes(),
+                room.as_bytes(),
+            ]),
+            Frame::RoomMembers { id, room, members } => {
+                let id = id.to_bytes();
+                let mut fields: Vec<&[u8]> = vec![&[tag::ROOM_MEMBERS], &id, room.as_bytes()];
+                fields.extend(members.iter().map(|m| m.as_bytes()));
+                codec::encode_fields(&fields)
+            }
+            Frame::Shutdown { reason } => {
+                codec::encode_fields(&[&[tag::SHUTDOWN], reason.as_bytes()])
+            }
+            Frame::Notice { text } => codec::encode_fields(&[&[tag::NOTICE], text.as_bytes()]),
+            Frame::FileOffer(offer) => codec::encode_fields(&[
+                &[tag::FILE_OFFER],
+                &offer.id.to_bytes(),
+                offer.from.as_bytes(),
+                offer.to.as_bytes(),
+                offer.name.as_bytes(),
+                &offer.size.to_be_bytes(),
+                offer.mime.as_bytes(),
+                &offer.sha256,
+            ]),
+            Frame::FileAccept { id, offset } => {
+                codec::encode_fields(&[&[tag::FILE_ACCEPT], &id.to_bytes(), &offset.to_be_bytes()])
+            }
+            Frame::FileReject { id, reason } => {
+                codec::encode_fields(&[&[tag::FILE_REJECT], &id.to_bytes(), reason.as_bytes()])
+            }
+            Frame::FileData { id, offset } => {
+                codec::encode_fields(&[&[tag::FILE_DATA], &id.to_bytes(), &offset.to_be_bytes()])
+            }
+            Frame::PresenceSet { status } => {
+                codec::encode_fields(&[&[tag::PRESENCE_SET], &[presence_byte(*status)]])
+            }
+            Frame::PresenceSubscribe { id, contacts } => {
+                let id = id.to_bytes();
+                let mut fields: Vec<&[u8]> = vec![&[tag::PRESENCE_SUBSCRIBE], &id];
+                fields.extend(contacts.iter().map(|c| c.as_bytes()));
+                codec::encode_fields(&fields)
+            }
+            Frame::WhoOnlineRequest { id } => {
+                codec::encode_fields(&[&[tag::WHO_ONLINE_REQUEST], &id.to_bytes()])
+            }
+            Frame::Presences { id, entries } => {
+                let id = id.to_bytes();
+                let statuses = entries
+                    .iter()
+                    .map(|(_, status)| [presence_byte(*status)])
+                    .collect::<Vec<_>>();
+
+                let mut fields: Vec<&[u8]> = vec![&[tag::PRESENCES], &id];
+                for ((email, _), status) in entries.iter().zip(&statuses) {
+                    fields.push(email.as_bytes());
+                    fields.push(status);
+                }
+                codec::encode_fields(&fields)
+            }
+            Frame::PresenceUpdate { email, status } => codec::encode_fields(&[
+                &[tag::PRESENCE_UPDATE],
+                email.as_bytes(),
+                &[presence_byte(*status)],
+            ]),
+            Frame::Signal(signal) => {
+                // only a read receipt carries an id, empty otherwise
+                let (kind, id) = match signal.kind {
+                    SignalKind::TypingStarted => (0u8, None),
+                    SignalKind::TypingStopped => (1, None),
+                    SignalKind::Read(id) => (2, Some(id.to_bytes())),
+                };
+                codec::encode_fields(&[
+                    &[tag::SIGNAL],
+                    signal.from.as_bytes(),
+                    signal.to.as_bytes(),
+                    &[kind],
+                    id.as_ref().map_or(&[], |id| id),
+                ])
+            }
+            Frame::KeysPublish { id, bundle } => codec::encode_fields(&[
+                &[tag::KEYS_PUBLISH],
+                &id.to_bytes(),
+                &bundle.identity,
+                &bundle.prekey,
+            ]),
+            Frame::KeysRequest { id, email } => {
+                codec::encode_fields(&[&[tag::KEYS_REQUEST], &id.to_bytes(), email.as_bytes()])
+            }
+            Frame::Keys { id, email, bundles } => {
+                let id = id.to_bytes();
+                let mut fields: Vec<&[u8]> = vec![&[tag::KEYS], &id, email.as_bytes()];
+                for bundle in bundles {
+                    fields.push(&bundle.identity);
+                    fields.push(&bundle.prekey);
+                }
+                codec::encode_fields(&fields)
+            }
+        }
+    }
+}
+
+fn encode_chat(transfer: &Transfer) -> Result<Bytes, CodecError> {
+    codec::encode_fields(&[
+        &[tag::CHAT],
+        &transfer.id.to_bytes(),
+        transfer.from.as_bytes(),
+        transfer.to.as_bytes(),
+        &transfer.content,
+    ])
+}
+
+impl std::convert::TryFrom<Bytes> for Frame {
+    type Error = ProtocolError;
+
+    fn try_from(value: Bytes) -> Result<Self, ProtocolError> {
+        let fields = codec::decode_fields(value).map_err(ProtocolError::Codec)?;
+        let (tag, fields) = match fields.split_first() {
+            Some((tag, rest)) if tag.len() == 1 => (tag[0], rest),
+            _ => return Err(ProtocolError::MissingTag),
+        };
+
+        let frame = match (tag, fields) {
+            (tag::LOGIN, [email, password]) => Frame::Login {
+                email: utf8(email)?,
+                password: utf8(password)?,
+            },
+            (tag::REGISTER, [email, password]) => Frame::Register {
+                email: utf8(email)?,
+                password: utf8(password)?,
+            },
+            (tag::CERTIFICATE_LOGIN, []) => Frame::CertificateLogin,
+            (tag::RESUME, [token]) => Frame::Resume {
+                token: utf8(token)?,
+            },
+            (tag::LOGIN_OK, [email, token]) => Frame::LoginOk {
+                email: utf8(email)?,
+                token: utf8(token)?,
+            },
+            (tag::LOGIN_REJECTED, [reason]) => Frame::LoginRejected {
+                reason: utf8(reason)?,
+            },
+            (tag::CHAT, [id, from, to, content]) => Frame::Chat(Transfer {
+                id: ulid(id)?,
+                from: utf8(from)?,
+                to: utf8(to)?,
+                content: content.clone(),
+            }),
+            (tag::ACK, [id, status, code]) => Frame::Ack {
+                id: ulid(id)?,
+                status: match (status.as_ref(), u16_field(code)?) {
+                    ([0], _) => AckStatus::Delivered,
+                    ([1], _) => AckStatus::Queued,
+                    ([2], code) => AckStatus::Failed(code.into()),
+                    _ => return Err(ProtocolError::InvalidField),
+                },
+            },
+            (tag::ERROR, [code, msg]) => Frame::Error {
+                code: u16_field(code)?.into(),
+                msg: utf8(msg)?,
+            },
+            (tag::PING, []) => Frame::Ping,
+            (tag::PONG, []) => Frame::Pong,
+            (tag::LOGOUT, []) => Frame::Logout,
+            (tag::HISTORY_REQUEST, [id, peer, before, limit]) => Frame::HistoryRequest {
+                id: ulid(id)?,
+                peer: utf8(peer)?,
+                before: if before.is_empty() {
+                    None
+                } else {
+                    Some(ulid(before)?)
+                },
+                limit: <[u8; 4]>::try_from(limit.as_ref())
+                    .map(u32::from_be_bytes)
+                    .map_err(|_| ProtocolError::InvalidField)?,
+            },
+            (tag::HISTORY, [id, entries @ ..]) if entries.len() % 2 == 0 => Frame::History {
+                id: ulid(id)?,
+                entries: entries
+                    .chunks(2)
+                    .map(|entry| {
+                        let at = <[u8; 8]>::try_from(entry[0].as_ref())
+                            .map(u64::from_be_bytes)
+                            .map_err(|_| ProtocolError::InvalidField)?;
+                        // only a chat is decoded, frames nested in frames would not end
+                        if peek_tag(&entry[1]) != Some(tag::CHAT) {
+                            return Err(ProtocolError::InvalidField);
+                        }
+                        match Frame::try_from(entry[1].clone())? {
+                            Frame::Chat(transfer) => Ok(HistoryEntry { at, transfer }),
+                            _ => Err(ProtocolError::InvalidField),
+                        }
+                    })
+                    .collect::<Result<_, _>>()?,
+            },
+            (tag::ROOM_JOIN, [id, room]) => Frame::RoomJoin {
+                id: ulid(id)?,
+                room: utf8(room)?,
+            },
+            (tag::ROOM_LEAVE, [id, room]) => Frame::RoomLeave {
+                id: ulid(id)?,
+                room: utf8(room)?,
+            },
+            (tag::ROOM_MEMBERS_REQUEST, [id, room]) => Frame::RoomMembersRequest {
+                id: ulid(id)?,
+                room: utf8(room)?,
+            },
+            (tag::ROOM_MEMBERS, [id, room, members @ ..]) => Frame::RoomMembers {
+                id: ulid(id)?,
+                room: utf8(room)?,
+                members: members.iter().map(utf8).collect::<Result<_, _>>()?,
+            },
+            (tag::SHUTDOWN, [reason]) => Frame::Shutdown {
+                reason: utf8(reason)?,
+            },
+            (tag::NOTICE, [text]) => Frame::Notice { text: utf8(text)? },
+            (tag::FILE_OFFER, [id, from, to, name, size, mime, sha256]) => {
+                Frame::FileOffer(FileOffer {
+                    id: ulid(id)?,
+                    from: utf8(from)?,
+                    to: utf8(to)?,
+                    name: utf8(name)?,
+                    size: u64_field(size)?,
+                    mime: utf8(mime)?,
+                    sha256: <[u8; 32]>::try_from(sha256.as_ref())
+                        .map_err(|_| ProtocolError::InvalidField)?,
+                })
+            }
+            (tag::FILE_ACCEPT, [id, offset]) => Frame::FileAccept {
+                id: ulid(id)?,
+                offset: u64_field(offset)?,
+            },
+            (tag::FILE_REJECT, [id, reason]) => Frame::FileReject {
+                id: ulid(id)?,
+                reason: utf8(reason)?,
+            },
+            (tag::FILE_DATA, [id, offset]) => Frame::FileData {
+                id: ulid(id)?,
+                offset: u64_field(offset)?,
+            },
+            (tag::PRESENCE_SET, [status]) => Frame::PresenceSet {
+                status: presence(status)?,
+            },
+            (tag::PRESENCE_SUBSCRIBE, [id, contacts @ ..]) => Frame::PresenceSubscribe {
+                id: ulid(id)?,
+                contacts: contacts.iter().map(utf8).collect::<Result<_, _>>()?,
+            },
+            (tag::WHO_ONLINE_REQUEST, [id]) => Frame::WhoOnlineRequest { id: ulid(id)? },
+            (tag::PRESENCES, [id, entries @ ..]) if entries.len() % 2 == 0 => Frame::Presences {
+                id: ulid(id)?,
+                entries: entries
+                    .chunks(2)
+                    .map(|entry| Ok((utf8(&entry[0])?, presence(&entry[1])?)))
+                    .collect::<Result<_, _>>()?,
+            },
+            (tag::PRESENCE_UPDATE, [email, status]) => Frame::PresenceUpdate {
+                email: utf8(email)?,
+                status: presence(status)?,
+            },
+            (tag::SIGNAL, [from, to, kind, id]) => Frame::Signal(Signal {
+                from: utf8(from)?,
+                to: utf8(to)?,
+                kind: match (kind.as_ref(), id.is_empty()) {
+                    ([0], true) => SignalKind::TypingStarted,
+                    ([1], true) => SignalKind::TypingStopped,
+                    ([2], false) => SignalKind::Read(ulid(id)?),
+                    _ => return Err(ProtocolError::InvalidField),
+                },
+            }),
+            (tag::KEYS_PUBLISH, [id, identity, prekey]) => Frame::KeysPublish {
+                id: ulid(id)?,
+                bundle: KeyBundle {
+                    identity: key(identity)?,
+                    prekey: key(prekey)?,
+                },
+            },
+            (tag::KEYS_REQUEST, [id, email]) => Frame::KeysRequest {
+                id: ulid(id)?,
+                email: utf8(email)?,
+            },
+            (tag::KEYS, [id, email, keys @ ..]) if keys.len() % 2 == 0 => Frame::Keys {
+                id: ulid(id)?,
+                email: utf8(email)?,
+                bundles: keys
+                    .chunks(2)
+                    .map(|pair| {
+                        Ok(KeyBundle {
+                            identity: key(&pair[0])?,
+                            prekey: key(&pair[1])?,
+                        })
+                    })
+                    .collect::<Result<_, ProtocolError>>()?,
+            },
+            (tag::LOGIN..=tag::LAST, _) => return Err(ProtocolError::InvalidField),
+            (tag, _) => return Err(ProtocolError::UnknownTag(tag)),
+        };
+
+        Ok(frame)
+    }
+}
+
+/// the tag of an encoded frame, without decoding the rest of it
+fn peek_tag(frame: &[u8]) -> Option<u8> {
+    match frame.get(codec::HEADER_LEN..codec::HEADER_LEN + 5)? {
+        [0, 0, 0, 1, tag] => Some(*tag),
+        _ => None,
+    }
+}
+
+fn utf8(field: &Bytes) -> Result<String, ProtocolError> {
+    String::from_utf8(field.to_vec()).map_err(|_| ProtocolError::NotUTF8)
+}
+
+fn ulid(field: &Bytes) -> Result<Ulid, ProtocolError> {
+    <[u8; 16]>::try_from(field.as_ref())
+        .map(Ulid::from_bytes)
+        .map_err(|_| ProtocolError::InvalidField)
+}
+
+fn u64_field(field: &Bytes) -> Result<u64, ProtocolError> {
+    <[u8; 8]>::try_from(field.as_ref())
+        .map(u64::from_be_bytes)
+        .map_err(|_| ProtocolError::InvalidField)
+}
+
+fn key(field: &Bytes) -> Result<[u8; 32], ProtocolError> {
+    <[u8; 32]>::try_from(field.as_ref()).map_err(|_| ProtocolError::InvalidField)
+}
+
+fn presence_byte(presence: Presence) -> u8 {
+    match presence {
+        Presence::Online => 0,
+        Presence::Away => 1,
+        Presence::Offline => 2,
+    }
+}
+
+fn presence(field: &Bytes) -> Result<Presence, ProtocolError> {
+    match field.as_ref() {
+        [0] => Ok(Presence::Online),
+        [1] => Ok(Presence::Away),
+        [2] => Ok(Presence::Offline),
+        _ => Err(ProtocolError::InvalidField),
+    }
+}
+
+fn u16_field(field: &Bytes) -> Result<u16, ProtocolError> {
+    <[u8; 2]>::try_from(field.as_ref())
+        .map(u16::from_be_bytes)
+        .map_err(|_| ProtocolError::InvalidField)
+}
+
+/// result of delivering the `Transfer` an ack refers to
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AckStatus {
+    Delivered,
+    /// recipient offline, the server keeps it for them
+    Queued,
+    Failed(ErrorCode),
+}
+
+/// carried by `Frame::Error`, so the peer can react without parsing the message
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorCode {
+    Unknown = 0,
+    InvalidFrame = 1,
+    UnexpectedFrame = 2,
+    NotLoggedIn = 3,
+    DestinationOffline = 4,
+    Internal = 5,
+    NotRoomMember = 6,
+    /// a file was not what its offer said
+    FileMismatch = 7,
+    /// sent faster than the server allows, the frame was dropped
+    RateLimited = 8,
+    /// the recipient does not keep up, the transfer was dropped
+    DestinationBusy = 9,
+    /// the account name is no email, see `is_email`
+    InvalidEmail = 10,
+    /// sent to an email without an account
+    UnknownRecipient = 11,
+    /// the offline recipient has as much queued as it may have, the transfer was dropped
+    QueueFull = 12,
+}
+
+impl From<&TransferError> for ErrorCode {
+    fn from(value: &TransferError) -> Self {
+        match value {
+            TransferError::DestinationClientOffline | TransferError::DestinationUnavailable => {
+                ErrorCode::DestinationOffline
+            }
+            TransferError::DestinationBusy => ErrorCode::DestinationBusy,
+            TransferError::UnknownRecipient => ErrorCode::UnknownRecipient,
+            TransferError::QueueFull => ErrorCode::QueueFull,
+            TransferError::StoreFailed => ErrorCode::Internal,
+            TransferError::NotRoomMember => ErrorCode::NotRoomMember,
+            TransferError::ContentNotUTF8 | TransferError::ConvertFromBytesFail => {
+                ErrorCode::InvalidFrame
+            }
+        }
+    }
+}
+
+impl From<u16> for ErrorCode {
+    fn from(value: u16) -> Self {
+        match value {
+            1 => ErrorCode::InvalidFrame,
+            2 => ErrorCode::UnexpectedFrame,
+            3 => ErrorCode::NotLoggedIn,
+            4 => ErrorCode::DestinationOffline,
+            5 => ErrorCode::Internal,
+            6 => ErrorCode::NotRoomMember,
+            7 => ErrorCode::FileMismatch,
+            8 => ErrorCode::RateLimited,
+            9 => ErrorCode::DestinationBusy,
+            10 => ErrorCode::InvalidEmail,
+            11 => ErrorCode::UnknownRecipient,
+            12 => ErrorCode::QueueFull,
+            _ => ErrorCode::Unknown,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum ProtocolError {
+    Codec(codec::CodecError),
+    MissingTag,
+    UnknownTag(u8),
+    InvalidField,
+    NotUTF8,
+}
+
+impl Display for ProtocolError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ProtocolError::Codec(e) => write!(f, "{}", e),
+            ProtocolError::MissingTag => write!(f, "frame without tag"),
+            ProtocolError::UnknownTag(tag) => write!(f, "unknown frame tag: {}", tag),
+            ProtocolError::InvalidField => write!(f, "invalid frame field"),
+            ProtocolError::NotUTF8 => write!(f, "frame field not UTF-8 encoding"),
+        }
+    }
+}
+
+impl std::error::Error for ProtocolError {}
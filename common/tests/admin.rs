@@ -0,0 +1,63 @@
+//! admin request lines parse to their requests, and read back as they print
+use common::admin::AdminRequest;
+
+#[test]
+fn lines_parse_to_requests() {
+    let cases = [
+        ("list", AdminRequest::List),
+        ("stop", AdminRequest::Stop),
+        ("  list \n", AdminRequest::List),
+        ("kick alice@x", AdminRequest::Kick("alice@x".into())),
+        ("ban alice@x", AdminRequest::Ban("alice@x".into())),
+        ("unban alice@x", AdminRequest::Unban("alice@x".into())),
+        ("kick\talice@x  ", AdminRequest::Kick("alice@x".into())),
+        (
+            "notice back in  five minutes",
+            AdminRequest::Notice("back in  five minutes".into()),
+        ),
+    ];
+
+    for (line, expected) in cases {
+        assert_eq!(AdminRequest::parse(line), Ok(expected), "{:?}", line);
+    }
+}
+
+#[test]
+fn malformed_requests_say_why() {
+    let cases = [
+        ("list all", "list takes no argument"),
+        ("stop now", "stop takes no argument"),
+        ("kick", "kick needs an argument"),
+        ("ban  ", "ban needs an argument"),
+        ("unban", "unban needs an argument"),
+        ("notice", "notice needs an argument"),
+        ("", "unknown request: "),
+        ("restart", "unknown request: restart"),
+        ("KICK alice@x", "unknown request: KICK"),
+    ];
+
+    for (line, expected) in cases {
+        assert_eq!(
+            AdminRequest::parse(line),
+            Err(expected.to_string()),
+            "{:?}",
+            line
+        );
+    }
+}
+
+#[test]
+fn requests_read_back_as_they_print() {
+    let requests = [
+        AdminRequest::List,
+        AdminRequest::Kick("alice@x".into()),
+        AdminRequest::Ban("alice@x".into()),
+        AdminRequest::Unban("alice@x".into()),
+        AdminRequest::Notice("back soon".into()),
+        AdminRequest::Stop,
+    ];
+
+    for request in requests {
+        assert_eq!(AdminRequest::parse(&request.to_string()), Ok(request));
+    }
+}
@@ -0,0 +1,126 @@
+//! encoding and decoding the codec frames, up to and past `MAX_FRAME_LEN`
+use bytes::Bytes;
+use common::codec::{
+    decode_fields, encode_fields, CodecError, FrameDecoder, HEADER_LEN, MAX_FRAME_LEN,
+    PROTOCOL_VERSION,
+};
+
+/// the payload length prefix of each field
+const FIELD_LEN: usize = 4;
+
+#[test]
+fn fields_survive_the_round_trip() {
+    let cases: &[&[&[u8]]] = &[
+        &[],
+        &[b""],
+        &[b"", b""],
+        &[b"a"],
+        &[b"alice@example.com", b"bob@example.com", &[0, 1, 2, 255]],
+        &[&[0; 1024], b"", &[7; 3]],
+    ];
+
+    for fields in cases {
+        let frame = encode_fields(fields).unwrap();
+        let payload: usize = fields.iter().map(|f| FIELD_LEN + f.len()).sum();
+        assert_eq!(frame.len(), HEADER_LEN + payload, "{:?}", fields);
+        assert_eq!(frame[0], PROTOCOL_VERSION);
+
+        let decoded = decode_fields(frame.clone()).unwrap();
+        assert_eq!(decoded, fields.to_vec(), "{:?}", fields);
+
+        let mut decoder = FrameDecoder::new();
+        decoder.extend(&frame);
+        assert_eq!(decoder.next_frame().unwrap(), Some(frame));
+        assert_eq!(decoder.next_frame().unwrap(), None);
+    }
+}
+
+#[test]
+fn largest_frame_is_encoded_and_decoded() {
+    let field = vec![0; MAX_FRAME_LEN - FIELD_LEN];
+    let frame = encode_fields(&[&field]).unwrap();
+    assert_eq!(frame.len(), HEADER_LEN + MAX_FRAME_LEN);
+
+    assert_eq!(decode_fields(frame.clone()).unwrap()[0].len(), field.len());
+    let mut decoder = FrameDecoder::new();
+    decoder.extend(&frame);
+    assert_eq!(decoder.next_frame().unwrap(), Some(frame));
+}
+
+#[test]
+fn frame_over_the_limit_is_refused() {
+    let field = vec![0; MAX_FRAME_LEN - FIELD_LEN + 1];
+    assert!(matches!(
+        encode_fields(&[&field]),
+        Err(CodecError::FrameTooLarge)
+    ));
+    // split over fields it is just as large
+    let half = vec![0; MAX_FRAME_LEN / 2];
+    assert!(matches!(
+        encode_fields(&[&half, &half]),
+        Err(CodecError::FrameTooLarge)
+    ));
+}
+
+#[test]
+fn header_claiming_too_much_is_refused() {
+    let mut frame = vec![PROTOCOL_VERSION];
+    frame.extend_from_slice(&(MAX_FRAME_LEN as u32 + 1).to_be_bytes());
+
+    let mut decoder = FrameDecoder::new();
+    decoder.extend(&frame);
+    assert!(matches!(
+        decoder.next_frame(),
+        Err(CodecError::FrameTooLarge)
+    ));
+    assert!(matches!(
+        decode_fields(Bytes::from(frame)),
+        Err(CodecError::FrameTooLarge)
+    ));
+}
+
+#[test]
+fn malformed_frames_are_refused() {
+    let valid = encode_fields(&[b"tag", b"field"]).unwrap().to_vec();
+    let mut wrong_version = valid.clone();
+    wrong_version[0] = PROTOCOL_VERSION + 1;
+    let mut field_past_the_end = valid.clone();
+    field_past_the_end[HEADER_LEN + 3] += 1;
+
+    // the expected error by the name of its variant
+    let cases = [
+        ("empty", vec![], "Incomplete"),
+        ("header cut", valid[..HEADER_LEN - 1].to_vec(), "Incomplete"),
+        ("version", wrong_version, "UnsupportedVersion"),
+        (
+            "payload cut",
+            valid[..valid.len() - 1].to_vec(),
+            "LengthMismatch",
+        ),
+        ("field past the end", field_past_the_end, "LengthMismatch"),
+    ];
+
+    for (name, frame, expected) in cases {
+        match decode_fields(Bytes::from(frame)) {
+            Err(e) => assert!(format!("{:?}", e).starts_with(expected), "{}: {}", name, e),
+            Ok(fields) => panic!("{}: decoded {:?}", name, fields),
+        }
+    }
+}
+
+#[test]
+fn decoder_waits_for_the_rest_of_a_frame() {
+    let first = encode_fields(&[b"first"]).unwrap();
+    let second = encode_fields(&[b"second", b""]).unwrap();
+    let stream = [first.as_ref(), second.as_ref()].concat();
+
+    let mut decoder = FrameDecoder::new();
+    for byte in &stream[..first.len() - 1] {
+        decoder.extend(&[*byte]);
+        assert_eq!(decoder.next_frame().unwrap(), None);
+    }
+    decoder.extend(&stream[first.len() - 1..]);
+    assert_eq!(decoder.next_frame().unwrap(), Some(first));
+    assert_eq!(decoder.next_frame().unwrap(), Some(second));
+    assert_eq!(decoder.next_frame().unwrap(), None);
+}
@@ -0,0 +1,145 @@
+//! sealed content opens for its recipients only, and only as it was sealed
+use common::crypto::{self, CryptoError, Keys, MAX_PREKEYS, SEALED_PREFIX};
+use ulid::Ulid;
+
+fn aad() -> Vec<u8> {
+    crypto::associated_data(Ulid::nil(), "alice@x", "bob@x")
+}
+
+#[test]
+fn every_recipient_opens_what_was_sealed() {
+    let alice = Keys::generate();
+    let phone = Keys::generate();
+    let laptop = Keys::generate();
+
+    let sealed = crypto::seal(
+        &alice,
+        &[phone.bundle(), laptop.bundle(), alice.bundle()],
+        &aad(),
+        b"hi",
+    )
+    .unwrap();
+    assert!(crypto::is_sealed(&sealed));
+
+    for keys in [&phone, &laptop, &alice] {
+        let opened = crypto::open(keys, &aad(), &sealed).unwrap();
+        assert_eq!(opened.plaintext, b"hi");
+        assert_eq!(opened.sender, alice.bundle().identity);
+    }
+}
+
+#[test]
+fn sealed_content_is_refused_when_not_as_sealed() {
+    let alice = Keys::generate();
+    let bob = Keys::generate();
+    let sealed = crypto::seal(&alice, &[bob.bundle()], &aad(), b"hi").unwrap();
+
+    let mut tampered = sealed.to_vec();
+    *tampered.last_mut().unwrap() ^= 1;
+    let mut wrapped_tampered = sealed.to_vec();
+    // inside the content key wrapped for bob, behind his identity and prekey
+    wrapped_tampered[SEALED_PREFIX.len() + 1 + 32 * 2 + 1 + 32 * 2] ^= 1;
+    let mut sender_swapped = sealed.to_vec();
+    sender_swapped[SEALED_PREFIX.len() + 1..SEALED_PREFIX.len() + 1 + 32]
+        .copy_from_slice(&Keys::generate().bundle().identity);
+
+    let other_aad = crypto::associated_data(Ulid::nil(), "mallory@x", "bob@x");
+    let cases = [
+        (
+            "wrong recipient",
+            &Keys::generate(),
+            &aad(),
+            sealed.to_vec(),
+            CryptoError::NotForUs,
+        ),
+        (
+            "the sender not a recipient",
+            &alice,
+            &aad(),
+            sealed.to_vec(),
+            CryptoError::NotForUs,
+        ),
+        (
+            "mismatched aad",
+            &bob,
+            &other_aad,
+            sealed.to_vec(),
+            CryptoError::Decrypt,
+        ),
+        (
+            "tampered ciphertext",
+            &bob,
+            &aad(),
+            tampered,
+            CryptoError::Decrypt,
+        ),
+        (
+            "tampered wrapped key",
+            &bob,
+            &aad(),
+            wrapped_tampered,
+            CryptoError::Decrypt,
+        ),
+        (
+            "sender swapped",
+            &bob,
+            &aad(),
+            sender_swapped,
+            CryptoError::Decrypt,
+        ),
+        (
+            "not sealed",
+            &bob,
+            &aad(),
+            b"hi".to_vec(),
+            CryptoError::Malformed,
+        ),
+    ];
+
+    for (name, keys, aad, sealed, expected) in cases {
+        assert_eq!(
+            crypto::open(keys, aad, &sealed).map(|opened| opened.plaintext),
+            Err(expected),
+            "{}",
+            name
+        );
+    }
+}
+
+#[test]
+fn older_prekeys_open_until_forgotten() {
+    let alice = Keys::generate();
+    let mut bob = Keys::generate();
+    let sealed = crypto::seal(&alice, &[bob.bundle()], &aad(), b"hi").unwrap();
+
+    for _ in 1..MAX_PREKEYS {
+        bob.rotate_prekey();
+    }
+    assert_eq!(
+        crypto::open(&bob, &aad(), &sealed).unwrap().plaintext,
+        b"hi"
+    );
+
+    bob.rotate_prekey();
+    assert_eq!(
+        crypto::open(&bob, &aad(), &sealed).map(|opened| opened.plaintext),
+        Err(CryptoError::NotForUs)
+    );
+}
+
+#[test]
+fn saved_keys_open_what_was_sealed_to_them() {
+    let path = std::env::temp_dir().join(format!("crypto-test-{}.keys", Ulid::new()));
+    let alice = Keys::generate();
+    let bob = Keys::load_or_generate(&path).unwrap();
+    let sealed = crypto::seal(&alice, &[bob.bundle()], &aad(), b"hi").unwrap();
+
+    let loaded = Keys::load_or_generate(&path);
+    let _ = std::fs::remove_file(&path);
+    let loaded = loaded.unwrap();
+    assert_eq!(loaded.bundle(), bob.bundle());
+    assert_eq!(
+        crypto::open(&loaded, &aad(), &sealed).unwrap().plaintext,
+        b"hi"
+    );
+}
@@ -0,0 +1,171 @@
+//! the decoders meet bytes from the network, whatever arrives they answer with an error, never a panic
+use bytes::Bytes;
+use common::{
+    admin::AdminRequest,
+    codec::{self, FrameDecoder, HEADER_LEN, PROTOCOL_VERSION},
+    crypto::{self, Keys},
+    protocol::{ErrorCode, Frame, ProtocolError},
+    Transfer,
+};
+use proptest::prelude::*;
+use ulid::Ulid;
+
+/// every tag, some unknown ones and a missing one
+fn tag() -> impl Strategy<Value = Vec<u8>> {
+    prop_oneof![
+        (0u8..48).prop_map(|tag| vec![tag]),
+        proptest::collection::vec(any::<u8>(), 0..3),
+    ]
+}
+
+/// short fields, most of them the length of a ulid, a u64 or a key so that decoding goes deep
+fn field() -> impl Strategy<Value = Vec<u8>> {
+    prop_oneof![
+        proptest::collection::vec(any::<u8>(), 0..4),
+        proptest::collection::vec(any::<u8>(), 8),
+        proptest::collection::vec(any::<u8>(), 16),
+        proptest::collection::vec(any::<u8>(), 32),
+        "[a-z@.#]{0,12}".prop_map(String::into_bytes),
+    ]
+}
+
+fn encode(tag: &[u8], fields: &[Vec<u8>]) -> Bytes {
+    let mut all: Vec<&[u8]> = vec![tag];
+    all.extend(fields.iter().map(Vec::as_slice));
+    codec::encode_fields(&all).unwrap()
+}
+
+proptest! {
+    #[test]
+    fn frame_from_arbitrary_bytes(bytes in proptest::collection::vec(any::<u8>(), 0..256)) {
+        let _ = Frame::try_from(Bytes::from(bytes));
+    }
+
+    #[test]
+    fn frame_from_arbitrary_fields(
+        tag in tag(),
+        fields in proptest::collection::vec(field(), 0..9),
+    ) {
+        let _ = Frame::try_from(encode(&tag, &fields));
+    }
+
+    #[test]
+    fn frame_with_a_corrupted_header(
+        bytes in proptest::collection::vec(any::<u8>(), 0..64),
+        claimed in any::<u32>(),
+    ) {
+        let mut frame = vec![PROTOCOL_VERSION];
+        frame.extend_from_slice(&claimed.to_be_bytes());
+        frame.extend_from_slice(&bytes);
+        prop_assert!(Frame::try_from(Bytes::from(frame)).is_err() || claimed as usize == bytes.len());
+    }
+
+    #[test]
+    fn decoder_cuts_arbitrary_chunks(
+        chunks in proptest::collection::vec(proptest::collection::vec(any::<u8>(), 0..64), 0..16),
+    ) {
+        let mut decoder = FrameDecoder::new();
+        for chunk in chunks {
+            decoder.extend(&chunk);
+            while let Ok(Some(frame)) = decoder.next_frame() {
+                prop_assert!(frame.len() >= HEADER_LEN);
+                let _ = Frame::try_from(frame);
+            }
+        }
+    }
+
+    #[test]
+    fn decoder_cuts_frames_split_anywhere(
+        texts in proptest::collection::vec("[ -~]{0,40}", 1..8),
+        split in any::<prop::sample::Index>(),
+    ) {
+        let frames: Vec<Bytes> = texts
+            .iter()
+            .map(|text| Frame::Notice { text: text.clone() }.to_bytes().unwrap())
+            .collect();
+        let stream: Vec<u8> = frames.iter().flat_map(|frame| frame.to_vec()).collect();
+        let (first, second) = stream.split_at(split.index(stream.len() + 1));
+
+        let mut decoder = FrameDecoder::new();
+        let mut decoded = vec![];
+        for chunk in [first, second] {
+            decoder.extend(chunk);
+            while let Some(frame) = decoder.next_frame().unwrap() {
+                decoded.push(frame);
+            }
+        }
+        prop_assert_eq!(decoded, frames);
+    }
+
+    #[test]
+    fn chat_survives_the_round_trip(
+        from in "[a-z@.]{1,16}",
+        to in "[a-z@.#]{1,16}",
+        content in proptest::collection::vec(any::<u8>(), 0..64),
+    ) {
+        let transfer = Transfer {
+            id: Ulid::new(),
+            from,
+            to,
+            content: Bytes::from(content),
+        };
+        match Frame::try_from(Frame::Chat(transfer.clone()).to_bytes().unwrap()) {
+            Ok(Frame::Chat(decoded)) => {
+                prop_assert_eq!(decoded.id, transfer.id);
+                prop_assert_eq!(decoded.from, transfer.from);
+                prop_assert_eq!(decoded.to, transfer.to);
+                prop_assert_eq!(decoded.content, transfer.content);
+            }
+            other => prop_assert!(false, "decoded as {:?}", other),
+        }
+    }
+
+    #[test]
+    fn error_code_from_any_number(code in any::<u16>()) {
+        let decoded = ErrorCode::from(code);
+        prop_assert!(decoded as u16 == code || decoded == ErrorCode::Unknown);
+    }
+
+    #[test]
+    fn sealed_content_corrupted(
+        flips in proptest::collection::vec((any::<prop::sample::Index>(), 1u8..), 1..8),
+        cut in any::<prop::sample::Index>(),
+    ) {
+        let keys = Keys::generate();
+        let aad = crypto::associated_data(Ulid::nil(), "alice@x", "bob@x");
+        let sealed = crypto::seal(&keys, &[keys.bundle()], &aad, b"hi").unwrap();
+
+        let mut corrupted = sealed.to_vec();
+        for (at, flip) in flips {
+            let at = at.index(corrupted.len());
+            corrupted[at] ^= flip;
+        }
+        corrupted.truncate(cut.index(corrupted.len() + 1));
+        prop_assert!(crypto::open(&keys, &aad, &corrupted).is_err());
+    }
+
+    #[test]
+    fn admin_request_from_any_line(line in "\\PC{0,64}") {
+        if let Ok(request) = AdminRequest::parse(&line) {
+            prop_assert_eq!(AdminRequest::parse(&request.to_string()), Ok(request));
+        }
+    }
+}
+
+/// history entries nested in history entries, deep enough to overflow the stack if decoded
+#[test]
+fn nested_history_is_refused() {
+    const HISTORY: u8 = 11;
+    let id = Ulid::nil().to_bytes().to_vec();
+    let at = 0u64.to_be_bytes().to_vec();
+
+    let mut frame = encode(&[HISTORY], std::slice::from_ref(&id));
+    for _ in 0..5_000 {
+        frame = encode(&[HISTORY], &[id.clone(), at.clone(), frame.to_vec()]);
+    }
+
+    assert!(matches!(
+        Frame::try_from(frame),
+        Err(ProtocolError::InvalidField)
+    ));
+}
@@ -0,0 +1,28 @@
+//! accounts are named by emails, which never look like a room
+use common::{is_email, is_room};
+
+#[test]
+fn only_emails_are_account_names() {
+    let cases = [
+        ("alice@example.com", true),
+        ("a@b", true),
+        ("server.example.com", false),
+        ("Alice Smith", false),
+        ("", false),
+        ("@example.com", false),
+        ("alice@", false),
+        ("alice@@example.com", false),
+        ("alice@exa@mple.com", false),
+        ("#room", false),
+        ("#room@example.com", false),
+        ("alice smith@example.com", false),
+        ("alice@example.com\n", false),
+    ];
+
+    for (name, email) in cases {
+        assert_eq!(is_email(name), email, "{:?}", name);
+        if email {
+            assert!(!is_room(name), "{:?}", name);
+        }
+    }
+}
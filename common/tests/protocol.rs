@@ -0,0 +1,311 @@
+//! every frame decodes to what was encoded, and malformed ones are refused with the right error
+use bytes::Bytes;
+use common::{
+    codec,
+    crypto::KeyBundle,
+    protocol::{AckStatus, ErrorCode, Frame},
+    FileOffer, HistoryEntry, Presence, Signal, SignalKind, Transfer,
+};
+use ulid::Ulid;
+
+fn transfer(to: &str) -> Transfer {
+    Transfer {
+        id: Ulid::new(),
+        from: "alice@x".into(),
+        to: to.into(),
+        content: Bytes::from_static(b"hi"),
+    }
+}
+
+fn bundle() -> KeyBundle {
+    KeyBundle {
+        identity: [1; 32],
+        prekey: [2; 32],
+    }
+}
+
+/// one of each variant, and the variants with optional or repeated fields once per shape
+fn frames() -> Vec<Frame> {
+    let id = Ulid::new();
+    vec![
+        Frame::Login {
+            email: "alice@x".into(),
+            password: "secret".into(),
+        },
+        Frame::Register {
+            email: "alice@x".into(),
+            password: "".into(),
+        },
+        Frame::CertificateLogin,
+        Frame::Resume { token: "t".into() },
+        Frame::LoginOk {
+            email: "alice@x".into(),
+            token: "t".into(),
+        },
+        Frame::LoginRejected {
+            reason: "no".into(),
+        },
+        Frame::Chat(transfer("bob@x")),
+        Frame::Chat(transfer("#room")),
+        Frame::Ack {
+            id,
+            status: AckStatus::Delivered,
+        },
+        Frame::Ack {
+            id,
+            status: AckStatus::Queued,
+        },
+        Frame::Ack {
+            id,
+            status: AckStatus::Failed(ErrorCode::DestinationBusy),
+        },
+        Frame::Error {
+            code: ErrorCode::RateLimited,
+            msg: "slow down".into(),
+        },
+        Frame::Ping,
+        Frame::Pong,
+        Frame::Logout,
+        Frame::HistoryRequest {
+            id,
+            peer: "bob@x".into(),
+            before: None,
+            limit: 50,
+        },
+        Frame::HistoryRequest {
+            id,
+            peer: "#room".into(),
+            before: Some(Ulid::new()),
+            limit: 0,
+        },
+        Frame::History {
+            id,
+            entries: vec![],
+        },
+        Frame::History {
+            id,
+            entries: vec![
+                HistoryEntry {
+                    at: 1,
+                    transfer: transfer("bob@x"),
+                },
+                HistoryEntry {
+                    at: u64::MAX,
+                    transfer: transfer("#room"),
+                },
+            ],
+        },
+        Frame::RoomJoin {
+            id,
+            room: "#room".into(),
+        },
+        Frame::RoomLeave {
+            id,
+            room: "#room".into(),
+        },
+        Frame::RoomMembersRequest {
+            id,
+            room: "#room".into(),
+        },
+        Frame::RoomMembers {
+            id,
+            room: "#room".into(),
+            members: vec![],
+        },
+        Frame::RoomMembers {
+            id,
+            room: "#room".into(),
+            members: vec!["alice@x".into(), "bob@x".into()],
+        },
+        Frame::Shutdown {
+            reason: "bye".into(),
+        },
+        Frame::Notice {
+            text: "maintenance".into(),
+        },
+        Frame::FileOffer(FileOffer {
+            id,
+            from: "alice@x".into(),
+            to: "bob@x".into(),
+            name: "a.txt".into(),
+            size: 3,
+            mime: "text/plain".into(),
+            sha256: [7; 32],
+        }),
+        Frame::FileAccept { id, offset: 0 },
+        Frame::FileReject {
+            id,
+            reason: "too big".into(),
+        },
+        Frame::FileData { id, offset: 42 },
+        Frame::PresenceSet {
+            status: Presence::Away,
+        },
+        Frame::PresenceSubscribe {
+            id,
+            contacts: vec!["bob@x".into()],
+        },
+        Frame::WhoOnlineRequest { id },
+        Frame::Presences {
+            id,
+            entries: vec![
+                ("alice@x".into(), Presence::Online),
+                ("bob@x".into(), Presence::Offline),
+            ],
+        },
+        Frame::PresenceUpdate {
+            email: "bob@x".into(),
+            status: Presence::Online,
+        },
+        Frame::Signal(Signal {
+            from: "alice@x".into(),
+            to: "bob@x".into(),
+            kind: SignalKind::TypingStarted,
+        }),
+        Frame::Signal(Signal {
+            from: "alice@x".into(),
+            to: "#room".into(),
+            kind: SignalKind::Read(id),
+        }),
+        Frame::KeysPublish {
+            id,
+            bundle: bundle(),
+        },
+        Frame::KeysRequest {
+            id,
+            email: "bob@x".into(),
+        },
+        Frame::Keys {
+            id,
+            email: "bob@x".into(),
+            bundles: vec![],
+        },
+        Frame::Keys {
+            id,
+            email: "bob@x".into(),
+            bundles: vec![bundle()],
+        },
+        Frame::Keys {
+            id,
+            email: "bob@x".into(),
+            bundles: vec![
+                bundle(),
+                KeyBundle {
+                    identity: [3; 32],
+                    prekey: [4; 32],
+                },
+            ],
+        },
+    ]
+}
+
+#[test]
+fn frames_survive_the_round_trip() {
+    for frame in frames() {
+        let decoded = Frame::try_from(frame.to_bytes().unwrap())
+            .unwrap_or_else(|e| panic!("{}: {}", frame.name(), e));
+
+        // frames are compared by what they print, they do not implement `PartialEq`
+        assert_eq!(format!("{:?}", decoded), format!("{:?}", frame));
+        assert_eq!(decoded.reply_id(), frame.reply_id(), "{}", frame.name());
+    }
+}
+
+fn encode(fields: &[&[u8]]) -> Bytes {
+    codec::encode_fields(fields).unwrap()
+}
+
+#[test]
+fn malformed_frames_are_refused() {
+    const LOGIN: u8 = 1;
+    const ACK: u8 = 5;
+    const ERROR: u8 = 6;
+    const PRESENCE_SET: u8 = 24;
+    const KEYS: u8 = 32;
+    let id = Ulid::new().to_bytes();
+
+    // the expected error by the name of its variant
+    let cases = [
+        ("no fields", encode(&[]), "MissingTag"),
+        ("tag too long", encode(&[&[LOGIN, 0]]), "MissingTag"),
+        ("unknown tag", encode(&[&[200]]), "UnknownTag"),
+        ("tag zero", encode(&[&[0]]), "UnknownTag"),
+        (
+            "field missing",
+            encode(&[&[LOGIN], b"alice@x"]),
+            "InvalidField",
+        ),
+        (
+            "field too many",
+            encode(&[&[LOGIN], b"a", b"b", b"c"]),
+            "InvalidField",
+        ),
+        ("not utf-8", encode(&[&[LOGIN], &[0xff], b"b"]), "NotUTF8"),
+        (
+            "short ulid",
+            encode(&[&[ACK], &id[..15], &[0]]),
+            "InvalidField",
+        ),
+        (
+            "unknown ack status",
+            encode(&[&[ACK], &id, &[9]]),
+            "InvalidField",
+        ),
+        (
+            "short error code",
+            encode(&[&[ERROR], &[0], b"m"]),
+            "InvalidField",
+        ),
+        (
+            "unknown presence",
+            encode(&[&[PRESENCE_SET], &[3]]),
+            "InvalidField",
+        ),
+        (
+            "short key",
+            encode(&[&[KEYS], &id, b"bob@x", &[0; 32], &[0; 31]]),
+            "InvalidField",
+        ),
+        (
+            "key without its pair",
+            encode(&[&[KEYS], &id, b"bob@x", &[0; 32]]),
+            "InvalidField",
+        ),
+        ("not a codec frame", Bytes::from_static(&[0, 0]), "Codec"),
+    ];
+
+    for (name, bytes, expected) in cases {
+        match Frame::try_from(bytes) {
+            Err(e) => assert!(
+                format!("{:?}", e).starts_with(expected),
+                "{}: {:?}",
+                name,
+                e
+            ),
+            Ok(frame) => panic!("{}: decoded {:?}", name, frame),
+        }
+    }
+}
+
+#[test]
+fn error_codes_survive_the_round_trip() {
+    let codes = [
+        ErrorCode::Unknown,
+        ErrorCode::InvalidFrame,
+        ErrorCode::UnexpectedFrame,
+        ErrorCode::NotLoggedIn,
+        ErrorCode::DestinationOffline,
+        ErrorCode::Internal,
+        ErrorCode::NotRoomMember,
+        ErrorCode::FileMismatch,
+        ErrorCode::RateLimited,
+        ErrorCode::DestinationBusy,
+        ErrorCode::InvalidEmail,
+        ErrorCode::UnknownRecipient,
+        ErrorCode::QueueFull,
+    ];
+
+    for code in codes {
+        assert_eq!(ErrorCode::from(code as u16), code);
+    }
+}
@@ -0,0 +1,217 @@
+//! the admin interface on a unix socket, see `common::admin` for the requests
+use std::{
+    fs, io,
+    os::unix::fs::{DirBuilderExt, PermissionsExt},
+    path::{Path, PathBuf},
+};
+
+use actix::prelude::*;
+use async_stream::stream;
+use log::{error, info, warn};
+use tokio::{
+    io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader},
+    net::{UnixListener, UnixStream},
+};
+
+use crate::sessions::{Ban, Broadcast, ClientInfo, Kick, ListClients, ServerSession, Unban};
+use common::{
+    admin::{AdminRequest, ERROR_PREFIX, MAX_REQUEST_LEN},
+    Stop,
+};
+
+/// answers the admin requests of every connection to the socket by asking `ServerSession`
+pub struct AdminListener {
+    server: Addr<ServerSession>,
+}
+
+impl AdminListener {
+    /// listen on `path`, only the user the server runs as may connect
+    pub fn start(path: &Path, server: Addr<ServerSession>) -> io::Result<Addr<Self>> {
+        remove_stale(path)?;
+        let listener = bind_private(path)?;
+        info!("admin interface on {}", path.display());
+
+        Ok(Self::create(|ctx| {
+            ctx.add_stream(stream! {
+                loop {
+                    yield listener.accept().await.map(|(stream, _)| stream);
+                }
+            });
+            Self { server }
+        }))
+    }
+}
+
+/// bind in a directory only we may enter and move the socket into place once it is 0600,
+/// so that it is never reachable by others, not even for a moment
+fn bind_private(path: &Path) -> io::Result<UnixListener> {
+    let private = private_dir(path);
+    fs::DirBuilder::new().mode(0o700).create(&private)?;
+
+    let bound = private.join("admin.sock");
+    let listener = UnixListener::bind(&bound).and_then(|listener| {
+        fs::set_permissions(&bound, fs::Permissions::from_mode(0o600))?;
+        fs::rename(&bound, path)?;
+        Ok(listener)
+    });
+    let _ = fs::remove_file(&bound);
+    let _ = fs::remove_dir(&private);
+    listener
+}
+
+/// next to `path`, so that the socket is renamed within the same file system
+fn private_dir(path: &Path) -> PathBuf {
+    let name = path.file_name().unwrap_or_default().to_string_lossy();
+    path.with_file_name(format!(".{}.{}", name, std::process::id()))
+}
+
+/// a socket left by a server that did not stop cleanly is removed, one still served is an error
+fn remove_stale(path: &Path) -> io::Result<()> {
+    if !path.exists() {
+        return Ok(());
+    }
+    if std::os::unix::net::UnixStream::connect(path).is_ok() {
+        return Err(io::Error::new(
+            io::ErrorKind::AddrInUse,
+            format!("{} is served by another server", path.display()),
+        ));
+    }
+    fs::remove_file(path)
+}
+
+impl Actor for AdminListener {
+    type Context = Context<Self>;
+}
+
+impl StreamHandler<io::Result<UnixStream>> for AdminListener {
+    fn handle(&mut self, item: io::Result<UnixStream>, ctx: &mut Self::Context) {
+        match item {
+            Ok(stream) => {
+                ctx.spawn(serve(stream, self.server.clone()).into_actor(self));
+            }
+            Err(e) => error!("accept admin connection failed: {}", e),
+        }
+    }
+}
+
+/// read one request, write the answer and close
+async fn serve(stream: UnixStream, server: Addr<ServerSession>) {
+    let (read, mut write) = stream.into_split();
+    let mut line = String::new();
+    if let Err(e) = BufReader::new(read.take(MAX_REQUEST_LEN))
+        .read_line(&mut line)
+        .await
+    {
+        warn!("read admin request failed: {}", e);
+        return;
+    }
+
+    let request = AdminRequest::parse(&line);
+    let answer = match &request {
+        Ok(request) => {
+            info!("admin request: {}", request);
+            answer(request, &server).await
+        }
+        Err(e) => Err(e.clone()),
+    };
+    let answer = answer.unwrap_or_else(|e| format!("{}{}\n", ERROR_PREFIX, e));
+    if let Err(e) = write.write_all(answer.as_bytes()).await {
+        warn!("write admin answer failed: {}", e);
+    }
+    let _ = write.shutdown().await;
+
+    // answered first, the server may be gone right after
+    if request == Ok(AdminRequest::Stop) {
+        server.do_send(Stop);
+    }
+}
+
+async fn answer(request: &AdminRequest, server: &Addr<ServerSession>) -> Result<String, String> {
+    let answer = match request {
+        AdminRequest::List => list(server.send(ListClients).await.map_err(mailbox)?),
+        AdminRequest::Kick(email) => {
+            let kicked = server
+                .send(Kick {
+                    email: email.clone(),
+                })
+                .await
+                .map_err(mailbox)?;
+            if kicked == 0 {
+                return Err(format!("{} is not logged in", email));
+            }
+            format!("kicked {} sessions of {}\n", kicked, email)
+        }
+        AdminRequest::Ban(email) => {
+            let kicked = server
+                .send(Ban {
+                    email: email.clone(),
+                })
+                .await
+                .map_err(mailbox)?;
+            format!("banned {}, {} sessions kicked\n", email, kicked)
+        }
+        AdminRequest::Unban(email) => {
+            let unbanned = server
+                .send(Unban {
+                    email: email.clone(),
+                })
+                .await
+                .map_err(mailbox)?;
+            if !unbanned {
+                return Err(format!("{} was not banned over the admin interface", email));
+            }
+            format!("unbanned {}\n", email)
+        }
+        AdminRequest::Notice(text) => {
+            let sent = server
+                .send(Broadcast { text: text.clone() })
+                .await
+                .map_err(mailbox)?;
+            format!("notice sent to {} sessions\n", sent)
+        }
+        AdminRequest::Stop => "stopping\n".to_string(),
+    };
+
+    Ok(answer)
+}
+
+/// a line per session: email, remote address and connect time, separated by tabs
+fn list(clients: Vec<ClientInfo>) -> String {
+    clients
+        .into_iter()
+        .map(|client| {
+            let email = client.email.as_deref().unwrap_or("(logging in)");
+            let remote = client
+                .connected
+                .remote
+                .map_or("-".to_string(), |remote| remote.to_string());
+            let since = humantime::format_rfc3339_seconds(client.connected.since);
+            format!("{}\t{}\t{}\n", email, remote, since)
+        })
+        .collect()
+}
+
+fn mailbox(e: MailboxError) -> String {
+    format!("server session unavailable: {}", e)
+}
+
+#[cfg(test)]
+mod tests {
+    use ulid::Ulid;
+
+    use super::*;
+
+    #[actix_rt::test]
+    async fn socket_is_private_once_bound() {
+        let path = std::env::temp_dir().join(format!("admin-test-{}.sock", Ulid::new()));
+        let listener = bind_private(&path).unwrap();
+
+        let mode = fs::metadata(&path).unwrap().permissions().mode();
+        let leftover = private_dir(&path).exists();
+        let (connected, _) = tokio::join!(UnixStream::connect(&path), listener.accept());
+        let _ = fs::remove_file(&path);
+        assert_eq!(mode & 0o777, 0o600);
+        assert!(!leftover);
+        assert!(connected.is_ok());
+    }
+}
@@ -0,0 +1,60 @@
+//! drives the admin interface of a running server, see `common::admin`
+use std::{error::Error, process::ExitCode};
+
+use clap::Parser;
+
+/// send one request to the server's admin socket and print the answer
+#[derive(Parser, Debug)]
+#[command(name = "chat-admin")]
+struct Args {
+    /// the server's `[admin] socket`
+    #[arg(short, long)]
+    socket: String,
+    /// list, kick <email>, ban <email>, unban <email>, notice <text> or stop
+    #[arg(required = true, num_args = 1..)]
+    request: Vec<String>,
+}
+
+fn main() -> Result<ExitCode, Box<dyn Error>> {
+    let args = Args::parse();
+
+    let line = args.request.join(" ");
+    if line.contains('\n') {
+        return Err("a request is one line".into());
+    }
+    let request = common::admin::AdminRequest::parse(&line)?;
+
+    request_server(&args.socket, &request.to_string())
+}
+
+#[cfg(unix)]
+fn request_server(socket: &str, request: &str) -> Result<ExitCode, Box<dyn Error>> {
+    use common::admin::ERROR_PREFIX;
+    use std::{
+        io::{Read, Write},
+        os::unix::net::UnixStream,
+    };
+
+    let mut stream =
+        UnixStream::connect(socket).map_err(|e| format!("connect to {} failed: {}", socket, e))?;
+    writeln!(stream, "{}", request)?;
+
+    let mut answer = String::new();
+    stream.read_to_string(&mut answer)?;
+
+    match answer.strip_prefix(ERROR_PREFIX) {
+        Some(error) => {
+            eprint!("{}", error);
+            Ok(ExitCode::FAILURE)
+        }
+        None => {
+            print!("{}", answer);
+            Ok(ExitCode::SUCCESS)
+        }
+    }
+}
+
+#[cfg(not(unix))]
+fn request_server(_socket: &str, _request: &str) -> Result<ExitCode, Box<dyn Error>> {
+    Err("the admin interface needs unix sockets".into())
+}
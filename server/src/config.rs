@@ -0,0 +1,686 @@
+//! server settings, read from a TOML file with command line flags winning over it
+//!
+//! ```toml
+//! listen = "127.0.0.1:4433"
+//!
+//! [tls]
+//! certificate = "cert.pem"
+//! key = "key.pem"
+//!
+//! [auth]
+//! mode = "password"       # or "certificate", or "both"
+//! client_ca = "ca.pem"    # needed unless the mode is "password"
+//!
+//! [storage]
+//! backend = "file"        # or "memory"
+//! data_dir = "data"
+//!
+//! [limits]
+//! max_file_size = 104857600
+//! max_upload_bytes_per_sender = 1073741824  # of files uploading or not yet downloaded
+//! upload_expiry_seconds = 604800            # files not written to since are removed
+//! max_history = 100
+//! outbound_queue = 256        # frames waiting for each stream of a client
+//! max_queued = 1000           # transfers kept for each client offline
+//! overflow = "spill"          # or "drop", or "disconnect", when a queue is full
+//!
+//! [rate_limit]               # per email and per source address
+//! messages_per_second = 20
+//! message_burst = 50
+//! bytes_per_second = 1048576  # of frames, files go by file_bytes_per_second
+//! byte_burst = 33554442       # two of the largest frame, 16 MiB and its header, at least one
+//! file_bytes_per_second = 0   # uploads are slowed down to it, 0 for no limit
+//! connections_per_second = 5  # per source address
+//! connection_burst = 20
+//! strikes = 20                # violations in a minute before disconnecting
+//! ban_seconds = 60            # how long then refused, 0 to only disconnect
+//!
+//! [log]
+//! level = "info"          # an env_logger filter, like "server=debug,info"
+//!
+//! [admin]
+//! socket = "/run/chat/admin.sock"
+//!
+//! [metrics]
+//! listen = "127.0.0.1:9100"    # serves /metrics
+//!
+//! [bans]
+//! emails = ["mallory@example.com"]
+//! ```
+//!
+//! limits, rate limits, the log level and the bans are applied again on SIGHUP,
+//! the rest needs a restart
+use std::{
+    collections::HashSet,
+    fmt::Display,
+    fs, io,
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use common::codec::{HEADER_LEN, MAX_FRAME_LEN};
+use serde::Deserialize;
+
+use crate::store::UploadLimits;
+
+/// the largest frame a client may send, `rate_limit.byte_burst` must let it through
+const LARGEST_FRAME: u64 = (HEADER_LEN + MAX_FRAME_LEN) as u64;
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub listen: Option<String>,
+    pub tls: TlsConfig,
+    pub auth: AuthConfig,
+    pub storage: StorageConfig,
+    pub limits: Limits,
+    pub rate_limit: RateLimits,
+    pub log: LogConfig,
+    pub admin: AdminConfig,
+    pub metrics: MetricsConfig,
+    pub bans: Bans,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct TlsConfig {
+    pub certificate: Option<String>,
+    pub key: Option<String>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AuthConfig {
+    pub mode: AuthMode,
+    /// CA certificate client certificates must be signed by
+    pub client_ca: Option<String>,
+}
+
+/// how clients may log in
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum AuthMode {
+    /// email and password, no client certificates
+    #[default]
+    Password,
+    /// a client certificate signed by `client_ca`, passwords are refused
+    Certificate,
+    /// a client certificate is required, logging in with a password too
+    Both,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct StorageConfig {
+    pub backend: Backend,
+    /// where the file backend keeps accounts, offline messages, history and files
+    pub data_dir: Option<String>,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Backend {
+    /// lost on restart
+    #[default]
+    Memory,
+    File,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Limits {
+    /// largest file clients may send, in bytes
+    pub max_file_size: u64,
+    /// bytes of the files one client has on the server, uploading or not yet downloaded by all
+    pub max_upload_bytes_per_sender: u64,
+    /// files not written to for this long are removed, downloaded by everyone or not
+    pub upload_expiry_seconds: u64,
+    /// most history entries answered to one request
+    pub max_history: usize,
+    /// frames waiting to be written to each stream of a client, for sessions starting from now on
+    pub outbound_queue: usize,
+    /// transfers kept for each client offline, more are refused
+    pub max_queued: usize,
+    pub overflow: Overflow,
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Self {
+            max_file_size: 100 * 1024 * 1024,
+            max_upload_bytes_per_sender: 1024 * 1024 * 1024,
+            upload_expiry_seconds: 7 * 24 * 60 * 60,
+            max_history: 100,
+            outbound_queue: 256,
+            max_queued: 1000,
+            overflow: Overflow::default(),
+        }
+    }
+}
+
+impl Limits {
+    pub fn uploads(&self) -> UploadLimits {
+        UploadLimits {
+            max_size: self.max_file_size,
+            max_per_sender: self.max_upload_bytes_per_sender,
+            expiry: Duration::from_secs(self.upload_expiry_seconds),
+        }
+    }
+}
+
+/// what becomes of a transfer to a client whose queue is full
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Overflow {
+    /// the sender is told it failed
+    Drop,
+    /// kept like for a client offline, delivered once the client logs in again
+    #[default]
+    Spill,
+    /// kept like `Spill`, and the client is disconnected
+    Disconnect,
+}
+
+/// token buckets, a rate and how much may be used at once, see `rate_limit`
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RateLimits {
+    pub messages_per_second: u32,
+    pub message_burst: u32,
+    pub bytes_per_second: u64,
+    pub byte_burst: u64,
+    /// uploads wait for it instead of failing, 0 for no limit
+    pub file_bytes_per_second: u64,
+    pub connections_per_second: u32,
+    pub connection_burst: u32,
+    /// violations within a minute before the client is disconnected
+    pub strikes: u32,
+    /// how long a disconnected offender is refused, 0 to only disconnect
+    pub ban_seconds: u64,
+}
+
+impl Default for RateLimits {
+    fn default() -> Self {
+        Self {
+            messages_per_second: 20,
+            message_burst: 50,
+            bytes_per_second: 1024 * 1024,
+            byte_burst: 2 * LARGEST_FRAME,
+            file_bytes_per_second: 0,
+            connections_per_second: 5,
+            connection_burst: 20,
+            strikes: 20,
+            ban_seconds: 60,
+        }
+    }
+}
+
+impl RateLimits {
+    /// a rate or burst of 0 refuses everything, and a frame over the byte burst never gets through
+    fn validate(&self) -> Result<(), ConfigError> {
+        let positive = [
+            ("messages_per_second", self.messages_per_second.into()),
+            ("message_burst", self.message_burst.into()),
+            ("bytes_per_second", self.bytes_per_second),
+            ("connections_per_second", self.connections_per_second.into()),
+            ("connection_burst", self.connection_burst.into()),
+        ];
+        if let Some((setting, _)) = positive.iter().find(|(_, value)| *value == 0) {
+            return Err(ConfigError::Invalid(format!(
+                "rate_limit.{} must be more than 0",
+                setting
+            )));
+        }
+        if self.byte_burst < LARGEST_FRAME {
+            return Err(ConfigError::Invalid(format!(
+                "rate_limit.byte_burst must be at least the largest frame, {} bytes",
+                LARGEST_FRAME
+            )));
+        }
+
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LogConfig {
+    /// an env_logger filter, `RUST_LOG` is used when there is none
+    pub level: Option<String>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AdminConfig {
+    /// unix socket the admin interface listens on, none without it
+    pub socket: Option<String>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct MetricsConfig {
+    /// address of the HTTP endpoint serving `/metrics`, none without it
+    pub listen: Option<String>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Bans {
+    /// refused when logging in, and disconnected when banned while online
+    pub emails: HashSet<String>,
+}
+
+/// what sessions go by, applied again on reload except `password_login`
+#[derive(Debug, Clone)]
+pub struct Policy {
+    pub limits: Limits,
+    pub rate_limit: RateLimits,
+    pub bans: HashSet<String>,
+    pub password_login: bool,
+}
+
+/// settings given on the command line, they win over the file
+#[derive(Debug, Clone, Default)]
+pub struct Overrides {
+    pub listen: Option<String>,
+    pub certificate: Option<String>,
+    pub key: Option<String>,
+    pub client_ca: Option<String>,
+    pub data_dir: Option<String>,
+    pub max_file_size: Option<u64>,
+    pub log_level: Option<String>,
+    pub admin_socket: Option<String>,
+    pub metrics_listen: Option<String>,
+}
+
+/// where the settings come from, read again on reload
+#[derive(Debug, Clone)]
+pub struct ConfigSource {
+    pub path: Option<PathBuf>,
+    pub overrides: Overrides,
+}
+
+impl ConfigSource {
+    pub fn load(&self) -> Result<Config, ConfigError> {
+        let mut config = match &self.path {
+            Some(path) => Config::read(path)?,
+            None => Config::default(),
+        };
+        config.apply(&self.overrides);
+        config.validate()?;
+
+        Ok(config)
+    }
+}
+
+impl Config {
+    pub fn read(path: impl AsRef<Path>) -> Result<Self, ConfigError> {
+        let text = fs::read_to_string(path).map_err(ConfigError::Io)?;
+        toml::from_str(&text).map_err(|e| ConfigError::Parse(e.to_string()))
+    }
+
+    fn apply(&mut self, overrides: &Overrides) {
+        let Overrides {
+            listen,
+            certificate,
+            key,
+            client_ca,
+            data_dir,
+            max_file_size,
+            log_level,
+            admin_socket,
+            metrics_listen,
+        } = overrides.clone();
+
+        self.listen = listen.or(self.listen.take());
+        self.tls.certificate = certificate.or(self.tls.certificate.take());
+        self.tls.key = key.or(self.tls.key.take());
+        if client_ca.is_some() {
+            self.auth.client_ca = client_ca;
+            // `--client-ca` always meant requiring certificates, passwords still allowed
+            if self.auth.mode == AuthMode::Password {
+                self.auth.mode = AuthMode::Both;
+            }
+        }
+        if data_dir.is_some() {
+            self.storage.data_dir = data_dir;
+            self.storage.backend = Backend::File;
+        }
+        if let Some(max_file_size) = max_file_size {
+            self.limits.max_file_size = max_file_size;
+        }
+        self.log.level = log_level.or(self.log.level.take());
+        self.admin.socket = admin_socket.or(self.admin.socket.take());
+        self.metrics.listen = metrics_listen.or(self.metrics.listen.take());
+    }
+
+    fn validate(&self) -> Result<(), ConfigError> {
+        self.listen_addr()?;
+        self.metrics_addr()?;
+        if self.tls.certificate.is_none() || self.tls.key.is_none() {
+            return Err(ConfigError::Missing("tls.certificate and tls.key"));
+        }
+        if self.auth.mode != AuthMode::Password && self.auth.client_ca.is_none() {
+            return Err(ConfigError::Missing("auth.client_ca"));
+        }
+        if self.auth.mode == AuthMode::Password && self.auth.client_ca.is_some() {
+            return Err(ConfigError::Invalid(
+                "auth.client_ca needs the mode \"certificate\" or \"both\"".to_string(),
+            ));
+        }
+        if self.limits.outbound_queue == 0 {
+            return Err(ConfigError::Invalid(
+                "limits.outbound_queue must be more than 0".to_string(),
+            ));
+        }
+        if self.limits.max_queued == 0 {
+            return Err(ConfigError::Invalid(
+                "limits.max_queued must be more than 0".to_string(),
+            ));
+        }
+        if self.limits.max_upload_bytes_per_sender < self.limits.max_file_size {
+            return Err(ConfigError::Invalid(
+                "limits.max_upload_bytes_per_sender must be at least limits.max_file_size"
+                    .to_string(),
+            ));
+        }
+        if self.limits.upload_expiry_seconds == 0 {
+            return Err(ConfigError::Invalid(
+                "limits.upload_expiry_seconds must be more than 0".to_string(),
+            ));
+        }
+        if self.storage.backend == Backend::File && self.storage.data_dir.is_none() {
+            return Err(ConfigError::Missing("storage.data_dir"));
+        }
+        self.rate_limit.validate()
+    }
+
+    pub fn listen_addr(&self) -> Result<SocketAddr, ConfigError> {
+        let listen = self.listen.as_ref().ok_or(ConfigError::Missing("listen"))?;
+        listen
+            .parse()
+            .map_err(|_| ConfigError::Invalid(format!("listen address {}", listen)))
+    }
+
+    pub fn metrics_addr(&self) -> Result<Option<SocketAddr>, ConfigError> {
+        self.metrics
+            .listen
+            .as_ref()
+            .map(|listen| {
+                listen
+                    .parse()
+                    .map_err(|_| ConfigError::Invalid(format!("metrics address {}", listen)))
+            })
+            .transpose()
+    }
+
+    /// only known to be there once validated
+    pub fn certificate(&self) -> (&str, &str) {
+        (
+            self.tls.certificate.as_deref().unwrap_or_default(),
+            self.tls.key.as_deref().unwrap_or_default(),
+        )
+    }
+
+    /// the directory of the file backend, `None` for the memory one
+    pub fn data_dir(&self) -> Option<&str> {
+        match self.storage.backend {
+            Backend::File => self.storage.data_dir.as_deref(),
+            Backend::Memory => None,
+        }
+    }
+
+    pub fn policy(&self) -> Policy {
+        Policy {
+            limits: self.limits.clone(),
+            rate_limit: self.rate_limit.clone(),
+            bans: self.bans.emails.clone(),
+            password_login: self.auth.mode != AuthMode::Certificate,
+        }
+    }
+
+    /// names of the settings differing from `other` that only a restart applies
+    pub fn restart_needed(&self, other: &Config) -> Vec<&'static str> {
+        let mut changed = vec![];
+        if self.listen != other.listen {
+            changed.push("listen");
+        }
+        if self.tls != other.tls {
+            changed.push("tls");
+        }
+        if self.auth != other.auth {
+            changed.push("auth");
+        }
+        if self.storage != other.storage {
+            changed.push("storage");
+        }
+        if self.admin != other.admin {
+            changed.push("admin");
+        }
+        if self.metrics != other.metrics {
+            changed.push("metrics");
+        }
+        changed
+    }
+}
+
+#[derive(Debug)]
+pub enum ConfigError {
+    Io(io::Error),
+    Parse(String),
+    Missing(&'static str),
+    Invalid(String),
+}
+
+impl Display for ConfigError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ConfigError::Io(e) => write!(f, "read config failed: {}", e),
+            ConfigError::Parse(e) => write!(f, "invalid config: {}", e),
+            ConfigError::Missing(setting) => write!(f, "missing setting: {}", setting),
+            ConfigError::Invalid(setting) => write!(f, "invalid setting: {}", setting),
+        }
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MINIMAL: &str = r#"
+        listen = "127.0.0.1:4433"
+        [tls]
+        certificate = "cert.pem"
+        key = "key.pem"
+    "#;
+
+    fn config(extra: &str) -> Config {
+        toml::from_str(&format!("{}\n{}", MINIMAL, extra)).unwrap()
+    }
+
+    #[test]
+    fn settings_are_validated() {
+        let cases = [
+            ("", None),
+            ("[auth]\nmode = \"both\"\nclient_ca = \"ca.pem\"", None),
+            ("[storage]\nbackend = \"file\"\ndata_dir = \"data\"", None),
+            ("[metrics]\nlisten = \"127.0.0.1:9100\"", None),
+            (
+                "[auth]\nmode = \"certificate\"",
+                Some("missing setting: auth.client_ca"),
+            ),
+            (
+                "[auth]\nclient_ca = \"ca.pem\"",
+                Some("invalid setting: auth.client_ca needs the mode \"certificate\" or \"both\""),
+            ),
+            (
+                "[storage]\nbackend = \"file\"",
+                Some("missing setting: storage.data_dir"),
+            ),
+            (
+                "[limits]\noutbound_queue = 0",
+                Some("invalid setting: limits.outbound_queue must be more than 0"),
+            ),
+            (
+                "[limits]\nmax_queued = 0",
+                Some("invalid setting: limits.max_queued must be more than 0"),
+            ),
+            ("[limits]\nmax_upload_bytes_per_sender = 104857600", None),
+            (
+                "[limits]\nmax_upload_bytes_per_sender = 104857599",
+                Some("invalid setting: limits.max_upload_bytes_per_sender must be at least limits.max_file_size"),
+            ),
+            (
+                "[limits]\nupload_expiry_seconds = 0",
+                Some("invalid setting: limits.upload_expiry_seconds must be more than 0"),
+            ),
+            (
+                "[metrics]\nlisten = \"nowhere\"",
+                Some("invalid setting: metrics address nowhere"),
+            ),
+            ("[rate_limit]\nbyte_burst = 16777221", None),
+            ("[rate_limit]\nfile_bytes_per_second = 0", None),
+            (
+                "[rate_limit]\nbyte_burst = 16777220",
+                Some("invalid setting: rate_limit.byte_burst must be at least the largest frame, 16777221 bytes"),
+            ),
+            (
+                "[rate_limit]\nmessages_per_second = 0",
+                Some("invalid setting: rate_limit.messages_per_second must be more than 0"),
+            ),
+            (
+                "[rate_limit]\nmessage_burst = 0",
+                Some("invalid setting: rate_limit.message_burst must be more than 0"),
+            ),
+            (
+                "[rate_limit]\nbytes_per_second = 0",
+                Some("invalid setting: rate_limit.bytes_per_second must be more than 0"),
+            ),
+            (
+                "[rate_limit]\nconnections_per_second = 0",
+                Some("invalid setting: rate_limit.connections_per_second must be more than 0"),
+            ),
+            (
+                "[rate_limit]\nconnection_burst = 0",
+                Some("invalid setting: rate_limit.connection_burst must be more than 0"),
+            ),
+        ];
+
+        for (extra, error) in cases {
+            let result = config(extra).validate().map_err(|e| e.to_string());
+            assert_eq!(result.err().as_deref(), error, "{}", extra);
+        }
+    }
+
+    #[test]
+    fn listen_and_tls_are_required() {
+        let cases = [
+            (
+                "[tls]\ncertificate = \"c\"\nkey = \"k\"",
+                "missing setting: listen",
+            ),
+            (
+                "listen = \"here\"\n[tls]\ncertificate = \"c\"\nkey = \"k\"",
+                "invalid setting: listen address here",
+            ),
+            (
+                "listen = \"127.0.0.1:4433\"\n[tls]\ncertificate = \"c\"",
+                "missing setting: tls.certificate and tls.key",
+            ),
+        ];
+
+        for (text, error) in cases {
+            let config: Config = toml::from_str(text).unwrap();
+            assert_eq!(
+                config
+                    .validate()
+                    .map_err(|e| e.to_string())
+                    .err()
+                    .as_deref(),
+                Some(error),
+                "{}",
+                text
+            );
+        }
+    }
+
+    #[test]
+    fn unknown_settings_are_refused() {
+        for text in [
+            "lisen = \"x\"",
+            "[limits]\nmax_files = 1",
+            "[storage]\nbackend = \"s3\"",
+        ] {
+            assert!(toml::from_str::<Config>(text).is_err(), "{}", text);
+        }
+    }
+
+    #[test]
+    fn command_line_wins_over_the_file() {
+        let mut config = config(
+            "[limits]\nmax_file_size = 10\n[admin]\nsocket = \"file.sock\"\n[log]\nlevel = \"warn\"",
+        );
+        config.apply(&Overrides {
+            listen: Some("0.0.0.0:1".into()),
+            max_file_size: Some(20),
+            admin_socket: Some("flag.sock".into()),
+            ..Overrides::default()
+        });
+
+        assert_eq!(config.listen.as_deref(), Some("0.0.0.0:1"));
+        assert_eq!(config.limits.max_file_size, 20);
+        assert_eq!(config.admin.socket.as_deref(), Some("flag.sock"));
+        // not given on the command line
+        assert_eq!(config.tls.certificate.as_deref(), Some("cert.pem"));
+        assert_eq!(config.log.level.as_deref(), Some("warn"));
+    }
+
+    #[test]
+    fn some_flags_imply_other_settings() {
+        let cases = [
+            ("", AuthMode::Both, Backend::File),
+            (
+                "[auth]\nmode = \"certificate\"",
+                AuthMode::Certificate,
+                Backend::File,
+            ),
+        ];
+
+        for (extra, mode, backend) in cases {
+            let mut config = config(extra);
+            config.apply(&Overrides {
+                client_ca: Some("ca.pem".into()),
+                data_dir: Some("data".into()),
+                ..Overrides::default()
+            });
+
+            assert_eq!(config.auth.mode, mode, "{}", extra);
+            assert_eq!(config.storage.backend, backend, "{}", extra);
+            assert!(config.validate().is_ok(), "{}", extra);
+        }
+    }
+
+    #[test]
+    fn restart_is_needed_for_some_settings_only() {
+        let running = config("");
+        let cases = [
+            (
+                "[limits]\nmax_history = 5\n[bans]\nemails = [\"m@x\"]",
+                vec![],
+            ),
+            ("[log]\nlevel = \"debug\"", vec![]),
+            (
+                "[storage]\nbackend = \"file\"\ndata_dir = \"d\"",
+                vec!["storage"],
+            ),
+            ("[admin]\nsocket = \"a.sock\"", vec!["admin"]),
+        ];
+
+        for (extra, changed) in cases {
+            assert_eq!(config(extra).restart_needed(&running), changed, "{}", extra);
+        }
+    }
+}
@@ -0,0 +1,62 @@
+//! a logger whose filter can change while running, for `[log] level` to apply on reload
+use std::sync::{OnceLock, PoisonError, RwLock};
+
+use log::{Log, Metadata, Record, SetLoggerError};
+
+static LOGGER: OnceLock<Reloadable> = OnceLock::new();
+
+struct Reloadable {
+    inner: RwLock<env_logger::Logger>,
+}
+
+/// log with `filter`, or `RUST_LOG` when there is none
+pub fn init(filter: Option<&str>) -> Result<(), SetLoggerError> {
+    let logger = LOGGER.get_or_init(|| Reloadable {
+        inner: RwLock::new(build(filter)),
+    });
+    log::set_logger(logger)?;
+    set_filter(filter);
+
+    Ok(())
+}
+
+/// log with `filter` from now on, or `RUST_LOG` when there is none
+pub fn set_filter(filter: Option<&str>) {
+    let Some(logger) = LOGGER.get() else {
+        return;
+    };
+
+    let new = build(filter);
+    log::set_max_level(new.filter());
+    *logger.inner.write().unwrap_or_else(PoisonError::into_inner) = new;
+}
+
+fn build(filter: Option<&str>) -> env_logger::Logger {
+    match filter {
+        Some(filter) => env_logger::Builder::new().parse_filters(filter).build(),
+        None => env_logger::Builder::from_default_env().build(),
+    }
+}
+
+impl Log for Reloadable {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        self.inner
+            .read()
+            .unwrap_or_else(PoisonError::into_inner)
+            .enabled(metadata)
+    }
+
+    fn log(&self, record: &Record) {
+        let inner = self.inner.read().unwrap_or_else(PoisonError::into_inner);
+        if inner.matches(record) {
+            inner.log(record);
+        }
+    }
+
+    fn flush(&self) {
+        self.inner
+            .read()
+            .unwrap_or_else(PoisonError::into_inner)
+            .flush();
+    }
+}
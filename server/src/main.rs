@@ -1,30 +1,75 @@
+#[cfg(unix)]
+mod admin;
+mod config;
+mod logging;
+mod metrics;
+mod rate_limit;
 mod server;
 mod sessions;
+mod store;
+mod tls;
 
 use clap::Parser;
-use std::error::Error;
+use config::{ConfigSource, Overrides};
+use std::{error::Error, path::PathBuf};
 
 #[derive(Parser, Debug)]
 struct Args {
+    /// TOML settings, see `config`, the flags below win over it
+    #[arg(long)]
+    config: Option<PathBuf>,
     /// Certificate
     #[arg(short, long)]
-    certificate: String,
+    certificate: Option<String>,
     /// private key
     #[arg(short, long)]
-    key: String,
+    key: Option<String>,
     /// server listening address
     #[arg(short, long)]
-    listen: String,
+    listen: Option<String>,
+    /// directory keeping offline messages and history, in memory if not given
+    #[arg(long)]
+    data_dir: Option<String>,
+    /// CA certificate, require clients to authenticate with a certificate it signed
+    #[arg(long)]
+    client_ca: Option<String>,
+    /// largest file clients may send, in bytes
+    #[arg(long)]
+    max_file_size: Option<u64>,
+    /// an env_logger filter, like `info` or `server=debug`
+    #[arg(long)]
+    log_level: Option<String>,
+    /// unix socket to serve the admin interface on, for `chat-admin`
+    #[arg(long)]
+    admin_socket: Option<String>,
+    /// address to serve Prometheus metrics on, at `/metrics`
+    #[arg(long)]
+    metrics_listen: Option<String>,
 }
 
 #[actix_rt::main]
 async fn main() -> Result<(), Box<dyn Error>> {
     dotenv::dotenv()?;
-    env_logger::init();
 
     let args = Args::parse();
+    let source = ConfigSource {
+        path: args.config,
+        overrides: Overrides {
+            listen: args.listen,
+            certificate: args.certificate,
+            key: args.key,
+            client_ca: args.client_ca,
+            data_dir: args.data_dir,
+            max_file_size: args.max_file_size,
+            log_level: args.log_level,
+            admin_socket: args.admin_socket,
+            metrics_listen: args.metrics_listen,
+        },
+    };
+    let config = source.load()?;
+    logging::init(config.log.level.as_deref())?;
 
-    let server = server::Server::new(args.certificate, args.key, args.listen)?;
+    let server = server::Server::new(config, source);
     server.start()?.await;
 
     Ok(())
@@ -0,0 +1,288 @@
+//! counters and gauges of the server, served in the Prometheus text format on `/metrics`
+use std::{
+    io,
+    net::SocketAddr,
+    sync::{atomic::AtomicU64, OnceLock},
+};
+
+use log::{error, info, warn};
+use prometheus_client::{
+    encoding::{text, EncodeLabelSet},
+    metrics::{counter::Counter, family::Family, gauge::Gauge},
+    registry::Registry,
+};
+use s2n_quic::provider::event::{events, ConnectionInfo, ConnectionMeta, Subscriber};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+};
+
+static METRICS: OnceLock<Metrics> = OnceLock::new();
+
+/// longest request head `/metrics` reads
+const MAX_REQUEST_LEN: usize = 8 * 1024;
+
+pub fn metrics() -> &'static Metrics {
+    METRICS.get_or_init(Metrics::new)
+}
+
+#[derive(Clone, Debug, Hash, PartialEq, Eq, EncodeLabelSet)]
+pub struct ActorLabels {
+    pub actor: &'static str,
+}
+
+#[derive(Clone, Debug, Hash, PartialEq, Eq, EncodeLabelSet)]
+pub struct ConnectionLabels {
+    /// s2n-quic's id of the connection
+    pub connection: u64,
+}
+
+pub struct Metrics {
+    registry: Registry,
+    /// sessions connected, logged in or not
+    pub connections: Gauge,
+    pub logins: Counter,
+    pub login_failures: Counter,
+    pub transfers_routed: Counter,
+    /// transfers queued for a recipient offline
+    pub transfers_offline: Counter,
+    /// frames and files, read from clients
+    pub received_bytes: Counter,
+    pub sent_bytes: Counter,
+    pub decode_errors: Counter,
+    /// see `Queued`
+    mailbox_depth: Family<ActorLabels, Gauge>,
+    rtt: Family<ConnectionLabels, Gauge<f64, AtomicU64>>,
+    congestion_window: Family<ConnectionLabels, Gauge>,
+    bytes_in_flight: Family<ConnectionLabels, Gauge>,
+    packets_lost: Family<ConnectionLabels, Counter>,
+}
+
+impl Metrics {
+    fn new() -> Self {
+        let mut metrics = Self {
+            registry: Registry::with_prefix("chat"),
+            connections: Gauge::default(),
+            logins: Counter::default(),
+            login_failures: Counter::default(),
+            transfers_routed: Counter::default(),
+            transfers_offline: Counter::default(),
+            received_bytes: Counter::default(),
+            sent_bytes: Counter::default(),
+            decode_errors: Counter::default(),
+            mailbox_depth: Family::default(),
+            rtt: Family::default(),
+            congestion_window: Family::default(),
+            bytes_in_flight: Family::default(),
+            packets_lost: Family::default(),
+        };
+
+        let registry = &mut metrics.registry;
+        registry.register(
+            "connections",
+            "Sessions connected",
+            metrics.connections.clone(),
+        );
+        registry.register("logins", "Successful logins", metrics.logins.clone());
+        registry.register(
+            "login_failures",
+            "Rejected logins",
+            metrics.login_failures.clone(),
+        );
+        registry.register(
+            "transfers_routed",
+            "Transfers clients sent",
+            metrics.transfers_routed.clone(),
+        );
+        registry.register(
+            "transfers_offline",
+            "Transfers queued for recipients offline",
+            metrics.transfers_offline.clone(),
+        );
+        registry.register(
+            "received_bytes",
+            "Bytes read from clients",
+            metrics.received_bytes.clone(),
+        );
+        registry.register(
+            "sent_bytes",
+            "Bytes sent to clients",
+            metrics.sent_bytes.clone(),
+        );
+        registry.register(
+            "decode_errors",
+            "Frames clients sent that could not be decoded",
+            metrics.decode_errors.clone(),
+        );
+        registry.register(
+            "mailbox_depth",
+            "Transfers waiting in or handled by an actor's mailbox",
+            metrics.mailbox_depth.clone(),
+        );
+        registry.register(
+            "quic_rtt_seconds",
+            "Smoothed round trip time of a connection",
+            metrics.rtt.clone(),
+        );
+        registry.register(
+            "quic_congestion_window_bytes",
+            "Congestion window of a connection",
+            metrics.congestion_window.clone(),
+        );
+        registry.register(
+            "quic_bytes_in_flight",
+            "Bytes sent on a connection and not acknowledged yet",
+            metrics.bytes_in_flight.clone(),
+        );
+        registry.register(
+            "quic_packets_lost",
+            "Packets of a connection declared lost",
+            metrics.packets_lost.clone(),
+        );
+
+        metrics
+    }
+
+    fn encode(&self) -> Result<String, std::fmt::Error> {
+        let mut body = String::new();
+        text::encode(&mut body, &self.registry)?;
+        Ok(body)
+    }
+}
+
+/// a message counted in the mailbox depth of `actor` until dropped, held by whoever
+/// awaits the reply, so it is uncounted once handled or dropped unhandled
+pub struct Queued(Gauge);
+
+impl Queued {
+    pub fn new(actor: &'static str) -> Self {
+        let depth = metrics()
+            .mailbox_depth
+            .get_or_create(&ActorLabels { actor })
+            .clone();
+        depth.inc();
+        Self(depth)
+    }
+}
+
+impl Drop for Queued {
+    fn drop(&mut self) {
+        self.0.dec();
+    }
+}
+
+/// keeps the QUIC statistics of every connection, they are removed once it closed
+pub struct QuicStats;
+
+impl Subscriber for QuicStats {
+    type ConnectionContext = ConnectionLabels;
+
+    fn create_connection_context(
+        &mut self,
+        meta: &ConnectionMeta,
+        _info: &ConnectionInfo,
+    ) -> Self::ConnectionContext {
+        ConnectionLabels {
+            connection: meta.id,
+        }
+    }
+
+    fn on_recovery_metrics(
+        &mut self,
+        context: &mut Self::ConnectionContext,
+        _meta: &ConnectionMeta,
+        event: &events::RecoveryMetrics,
+    ) {
+        let metrics = metrics();
+        metrics
+            .rtt
+            .get_or_create(context)
+            .set(event.smoothed_rtt.as_secs_f64());
+        metrics
+            .congestion_window
+            .get_or_create(context)
+            .set(event.congestion_window.into());
+        metrics
+            .bytes_in_flight
+            .get_or_create(context)
+            .set(event.bytes_in_flight.into());
+    }
+
+    fn on_packet_lost(
+        &mut self,
+        context: &mut Self::ConnectionContext,
+        _meta: &ConnectionMeta,
+        _event: &events::PacketLost,
+    ) {
+        metrics().packets_lost.get_or_create(context).inc();
+    }
+
+    fn on_connection_closed(
+        &mut self,
+        context: &mut Self::ConnectionContext,
+        _meta: &ConnectionMeta,
+        _event: &events::ConnectionClosed,
+    ) {
+        let metrics = metrics();
+        metrics.rtt.remove(context);
+        metrics.congestion_window.remove(context);
+        metrics.bytes_in_flight.remove(context);
+        metrics.packets_lost.remove(context);
+    }
+}
+
+/// answer `GET /metrics` on `listen`, anything else is not found
+pub fn serve(listen: SocketAddr) -> io::Result<()> {
+    let listener = std::net::TcpListener::bind(listen)?;
+    listener.set_nonblocking(true)?;
+    let listener = TcpListener::from_std(listener)?;
+    info!("metrics on http://{}/metrics", listen);
+
+    actix_rt::spawn(async move {
+        loop {
+            match listener.accept().await {
+                Ok((stream, _)) => {
+                    actix_rt::spawn(async move {
+                        if let Err(e) = respond(stream).await {
+                            warn!("answer metrics request failed: {}", e);
+                        }
+                    });
+                }
+                Err(e) => error!("accept metrics connection failed: {}", e),
+            }
+        }
+    });
+
+    Ok(())
+}
+
+async fn respond(mut stream: TcpStream) -> io::Result<()> {
+    let mut head = Vec::new();
+    let mut buf = [0; 1024];
+    while !head.windows(4).any(|w| w == b"\r\n\r\n") {
+        let n = stream.read(&mut buf).await?;
+        if n == 0 || head.len() + n > MAX_REQUEST_LEN {
+            return Ok(());
+        }
+        head.extend_from_slice(&buf[..n]);
+    }
+
+    let request_line = head.split(|b| *b == b'\r').next().unwrap_or_default();
+    let mut parts = request_line.split(|b| *b == b' ');
+    let found = parts.next() == Some(b"GET") && parts.next() == Some(b"/metrics");
+
+    let (status, body) = match found.then(|| metrics().encode()) {
+        Some(Ok(body)) => ("200 OK", body),
+        Some(Err(_)) => ("500 Internal Server Error", String::new()),
+        None => ("404 Not Found", String::new()),
+    };
+    let response = format!(
+        "HTTP/1.1 {}\r\nContent-Type: application/openmetrics-text; version=1.0.0; charset=utf-8\r\n\
+         Content-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        body.len(),
+        body
+    );
+    stream.write_all(response.as_bytes()).await?;
+    stream.shutdown().await
+}
@@ -0,0 +1,398 @@
+//! token buckets limiting what every email and every source address may send,
+//! shared by the sessions, see `config::RateLimits`
+use std::{
+    collections::HashMap,
+    net::IpAddr,
+    sync::{Arc, Mutex, MutexGuard, PoisonError},
+    time::{Duration, Instant},
+};
+
+use crate::config::RateLimits;
+
+/// violations older than this are forgotten
+const STRIKE_WINDOW: Duration = Duration::from_secs(60);
+
+/// the limiter every session charges
+#[derive(Clone)]
+pub struct SharedLimiter(Arc<Mutex<RateLimiter>>);
+
+impl SharedLimiter {
+    pub fn new(limits: RateLimits) -> Self {
+        Self(Arc::new(Mutex::new(RateLimiter::new(limits))))
+    }
+
+    /// the buckets stay usable after a session panicked holding them
+    pub fn lock(&self) -> MutexGuard<'_, RateLimiter> {
+        self.0.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+/// whose buckets are charged, a session logged in is charged as both
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum Peer {
+    Email(String),
+    Address(IpAddr),
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum Verdict {
+    Allowed,
+    /// over the limit, drop what was sent
+    Limited,
+    /// limited too often, disconnect
+    Exceeded,
+}
+
+struct TokenBucket {
+    tokens: f64,
+    last: Instant,
+}
+
+impl TokenBucket {
+    fn full(burst: f64, now: Instant) -> Self {
+        Self {
+            tokens: burst,
+            last: now,
+        }
+    }
+
+    fn refill(&mut self, rate: f64, burst: f64, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.last).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * rate).min(burst);
+        self.last = now;
+    }
+
+    /// whether there are `n` to take, without taking them
+    fn holds(&mut self, n: f64, rate: f64, burst: f64, now: Instant) -> bool {
+        self.refill(rate, burst, now);
+        self.tokens >= n
+    }
+
+    fn take(&mut self, n: f64, rate: f64, burst: f64, now: Instant) -> bool {
+        if !self.holds(n, rate, burst, now) {
+            return false;
+        }
+        self.tokens -= n;
+        true
+    }
+
+    /// take `n` even if there are not enough, results in how long until the debt is paid
+    fn borrow(&mut self, n: f64, rate: f64, now: Instant) -> Duration {
+        self.refill(rate, rate, now);
+        self.tokens -= n;
+        if self.tokens >= 0.0 {
+            return Duration::ZERO;
+        }
+        Duration::from_secs_f64(-self.tokens / rate)
+    }
+}
+
+struct Buckets {
+    messages: TokenBucket,
+    bytes: TokenBucket,
+    file_bytes: TokenBucket,
+    connections: TokenBucket,
+    strikes: u32,
+    first_strike: Instant,
+    banned_until: Option<Instant>,
+    last_seen: Instant,
+}
+
+pub struct RateLimiter {
+    limits: RateLimits,
+    peers: HashMap<Peer, Buckets>,
+}
+
+impl RateLimiter {
+    pub fn new(limits: RateLimits) -> Self {
+        Self {
+            limits,
+            peers: HashMap::new(),
+        }
+    }
+
+    /// the buckets keep what they hold, only refilled at the new rates
+    pub fn set_limits(&mut self, limits: RateLimits) {
+        self.limits = limits;
+    }
+
+    fn buckets(&mut self, peer: &Peer, now: Instant) -> &mut Buckets {
+        let limits = &self.limits;
+        let buckets = self.peers.entry(peer.clone()).or_insert_with(|| Buckets {
+            messages: TokenBucket::full(limits.message_burst.into(), now),
+            bytes: TokenBucket::full(limits.byte_burst as f64, now),
+            file_bytes: TokenBucket::full(limits.file_bytes_per_second as f64, now),
+            connections: TokenBucket::full(limits.connection_burst.into(), now),
+            strikes: 0,
+            first_strike: now,
+            banned_until: None,
+            last_seen: now,
+        });
+        buckets.last_seen = now;
+        buckets
+    }
+
+    /// a frame of `len` bytes arrived from `peers`, every one of them is charged,
+    /// or none when any of them is over the limit
+    pub fn frame(&mut self, peers: &[Peer], len: usize) -> Verdict {
+        let now = Instant::now();
+        let limits = self.limits.clone();
+
+        let refused: Vec<&Peer> = peers
+            .iter()
+            .filter(|peer| {
+                let buckets = self.buckets(peer, now);
+                !(buckets.messages.holds(
+                    1.0,
+                    limits.messages_per_second.into(),
+                    limits.message_burst.into(),
+                    now,
+                ) && buckets.bytes.holds(
+                    len as f64,
+                    limits.bytes_per_second as f64,
+                    limits.byte_burst as f64,
+                    now,
+                ))
+            })
+            .collect();
+
+        if refused.is_empty() {
+            for peer in peers {
+                let buckets = self.buckets(peer, now);
+                buckets.messages.tokens -= 1.0;
+                buckets.bytes.tokens -= len as f64;
+            }
+            return Verdict::Allowed;
+        }
+        refused.into_iter().fold(Verdict::Allowed, |verdict, peer| {
+            verdict.max(self.strike(peer, now))
+        })
+    }
+
+    /// a new connection from `address`
+    pub fn connection(&mut self, address: IpAddr) -> Verdict {
+        let now = Instant::now();
+        let limits = self.limits.clone();
+        let peer = Peer::Address(address);
+
+        let allowed = self.buckets(&peer, now).connections.take(
+            1.0,
+            limits.connections_per_second.into(),
+            limits.connection_burst.into(),
+            now,
+        );
+        if allowed {
+            Verdict::Allowed
+        } else {
+            self.strike(&peer, now)
+        }
+    }
+
+    /// `len` bytes of a file arrived from `peers`, results in how long to wait before reading more
+    pub fn file_bytes(&mut self, peers: &[Peer], len: usize) -> Duration {
+        let rate = self.limits.file_bytes_per_second as f64;
+        if rate == 0.0 {
+            return Duration::ZERO;
+        }
+
+        let now = Instant::now();
+        peers
+            .iter()
+            .map(|peer| {
+                self.buckets(peer, now)
+                    .file_bytes
+                    .borrow(len as f64, rate, now)
+            })
+            .max()
+            .unwrap_or_default()
+    }
+
+    fn strike(&mut self, peer: &Peer, now: Instant) -> Verdict {
+        let limits = self.limits.clone();
+        let buckets = self.buckets(peer, now);
+        if now.saturating_duration_since(buckets.first_strike) > STRIKE_WINDOW {
+            buckets.strikes = 0;
+            buckets.first_strike = now;
+        }
+        buckets.strikes += 1;
+        if buckets.strikes < limits.strikes {
+            return Verdict::Limited;
+        }
+
+        buckets.strikes = 0;
+        if limits.ban_seconds > 0 {
+            buckets.banned_until = Some(now + Duration::from_secs(limits.ban_seconds));
+        }
+        Verdict::Exceeded
+    }
+
+    /// whether `peer` was disconnected for exceeding the limits and is still refused
+    pub fn banned(&self, peer: &Peer) -> bool {
+        self.peers
+            .get(peer)
+            .and_then(|buckets| buckets.banned_until)
+            .is_some_and(|until| until > Instant::now())
+    }
+
+    /// forget the peers not heard of for a while, unless banned
+    pub fn prune(&mut self) {
+        let now = Instant::now();
+        self.peers.retain(|_, buckets| {
+            now.saturating_duration_since(buckets.last_seen) < STRIKE_WINDOW
+                || buckets.banned_until.is_some_and(|until| until > now)
+        });
+    }
+}
+
+impl Verdict {
+    fn max(self, other: Verdict) -> Verdict {
+        match (self, other) {
+            (Verdict::Exceeded, _) | (_, Verdict::Exceeded) => Verdict::Exceeded,
+            (Verdict::Limited, _) | (_, Verdict::Limited) => Verdict::Limited,
+            _ => Verdict::Allowed,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv4Addr;
+
+    use super::*;
+
+    /// buckets that barely refill while a test runs
+    fn limits() -> RateLimits {
+        RateLimits {
+            messages_per_second: 1,
+            message_burst: 3,
+            bytes_per_second: 1,
+            byte_burst: 100,
+            connections_per_second: 1,
+            connection_burst: 2,
+            strikes: 3,
+            ban_seconds: 60,
+            ..RateLimits::default()
+        }
+    }
+
+    fn alice() -> Vec<Peer> {
+        vec![Peer::Email("alice@x".into())]
+    }
+
+    #[test]
+    fn buckets_refill_at_their_rate_up_to_the_burst() {
+        let start = Instant::now();
+        let mut bucket = TokenBucket::full(10.0, start);
+        assert!(bucket.take(10.0, 2.0, 10.0, start));
+        assert!(!bucket.take(1.0, 2.0, 10.0, start));
+
+        let cases = [
+            ("half a second", 500, 1.0),
+            ("two seconds", 2_000, 4.0),
+            ("past the burst", 60_000, 10.0),
+        ];
+
+        for (name, millis, expected) in cases {
+            let mut bucket = TokenBucket {
+                tokens: 0.0,
+                last: start,
+            };
+            bucket.refill(2.0, 10.0, start + Duration::from_millis(millis));
+            assert_eq!(bucket.tokens, expected, "{}", name);
+        }
+    }
+
+    #[test]
+    fn frames_are_allowed_up_to_the_burst() {
+        let mut limiter = RateLimiter::new(limits());
+        let verdicts: Vec<Verdict> = (0..4).map(|_| limiter.frame(&alice(), 1)).collect();
+        assert_eq!(
+            verdicts,
+            [
+                Verdict::Allowed,
+                Verdict::Allowed,
+                Verdict::Allowed,
+                Verdict::Limited
+            ]
+        );
+    }
+
+    #[test]
+    fn a_frame_refused_for_its_size_costs_no_message() {
+        let mut limiter = RateLimiter::new(limits());
+        assert_eq!(limiter.frame(&alice(), 101), Verdict::Limited);
+        assert_eq!(limiter.frame(&alice(), 101), Verdict::Limited);
+
+        // both refusals left the message bucket full
+        for _ in 0..3 {
+            assert_eq!(limiter.frame(&alice(), 1), Verdict::Allowed);
+        }
+    }
+
+    #[test]
+    fn strikes_disconnect_and_ban() {
+        let cases = [("banned", 60, true), ("only disconnected", 0, false)];
+
+        for (name, ban_seconds, banned) in cases {
+            let mut limiter = RateLimiter::new(RateLimits {
+                ban_seconds,
+                ..limits()
+            });
+            let verdicts: Vec<Verdict> = (0..6).map(|_| limiter.frame(&alice(), 1)).collect();
+            assert_eq!(
+                verdicts[3..],
+                [Verdict::Limited, Verdict::Limited, Verdict::Exceeded],
+                "{}",
+                name
+            );
+            assert_eq!(limiter.banned(&alice()[0]), banned, "{}", name);
+        }
+    }
+
+    #[test]
+    fn every_peer_is_charged() {
+        let mut limiter = RateLimiter::new(limits());
+        let address = Peer::Address(Ipv4Addr::LOCALHOST.into());
+        for _ in 0..3 {
+            assert_eq!(
+                limiter.frame(std::slice::from_ref(&address), 1),
+                Verdict::Allowed
+            );
+        }
+
+        // the address has nothing left for alice logged in from it
+        let both = [alice()[0].clone(), address];
+        assert_eq!(limiter.frame(&both, 1), Verdict::Limited);
+        assert_eq!(limiter.frame(&alice(), 1), Verdict::Allowed);
+    }
+
+    #[test]
+    fn a_frame_refused_for_one_peer_is_charged_to_none() {
+        let address = Peer::Address(Ipv4Addr::LOCALHOST.into());
+        let cases = [("out of messages", 1, 1), ("out of bytes", 100, 1)];
+
+        for (name, spent_len, len) in cases {
+            let mut limiter = RateLimiter::new(limits());
+            while limiter.frame(std::slice::from_ref(&address), spent_len) == Verdict::Allowed {}
+
+            let both = [alice()[0].clone(), address.clone()];
+            assert_eq!(limiter.frame(&both, len), Verdict::Limited, "{}", name);
+
+            // alice's buckets are as full as before, the whole burst of messages and bytes goes
+            let buckets = limiter.buckets(&alice()[0], Instant::now());
+            assert_eq!(buckets.messages.tokens, 3.0, "{}", name);
+            assert_eq!(buckets.bytes.tokens, 100.0, "{}", name);
+        }
+    }
+
+    #[test]
+    fn connections_are_allowed_up_to_the_burst() {
+        let mut limiter = RateLimiter::new(limits());
+        let address = Ipv4Addr::LOCALHOST.into();
+        let verdicts: Vec<Verdict> = (0..3).map(|_| limiter.connection(address)).collect();
+        assert_eq!(
+            verdicts,
+            [Verdict::Allowed, Verdict::Allowed, Verdict::Limited]
+        );
+    }
+}
@@ -1,77 +1,257 @@
-use std::{error::Error, fmt::Display, future::Future, net::SocketAddr, path::Path, task::Poll};
+use std::{
+    error::Error,
+    future::Future,
+    path::Path,
+    sync::{Arc, Mutex},
+    task::Poll,
+};
 
-use actix::{Actor, Addr};
-use log::info;
+use actix::{Actor, Addr, SyncArbiter};
+use common::Stop;
+use log::{error, info, warn};
+use s2n_quic::provider::tls;
+use tokio::sync::oneshot;
 
-use crate::sessions::ServerSession;
+#[cfg(unix)]
+use crate::admin::AdminListener;
+use crate::{
+    config::{Config, ConfigSource},
+    logging,
+    metrics::{self, QuicStats},
+    sessions::{Accounts, Reload, RoomRegistry, ServerSession, SharedUsers, ACCOUNTS_THREADS},
+    store::{
+        FileHistory, FileKeys, FileStore, FileUsers, MemoryHistory, MemoryKeys, MemoryStore,
+        MemoryUsers, Stores, Uploads, UserStore,
+    },
+    tls::{AcceptAnyName, ClientCertificates},
+};
 
 pub struct Server {
-    certificate: String,
-    key: String,
-    listen: SocketAddr,
+    config: Config,
+    /// read again on SIGHUP
+    source: ConfigSource,
     session: Option<Addr<ServerSession>>,
+    #[cfg(unix)]
+    admin: Option<Addr<AdminListener>>,
 }
 
 impl Server {
-    pub fn new(
-        certificate: impl AsRef<str>,
-        key: impl AsRef<str>,
-        listen: impl AsRef<str>,
-    ) -> Result<Self, ConstructServerError> {
+    /// `config` as loaded from `source`, see `config` for what it holds
+    pub fn new(config: Config, source: ConfigSource) -> Self {
         info!("new a Server");
 
-        Ok(Self {
-            certificate: certificate.as_ref().to_string(),
-            key: key.as_ref().to_string(),
-            listen: listen
-                .as_ref()
-                .parse()
-                .map_err(|_| ConstructServerError::InvalidIpAddr)?,
+        Self {
+            config,
+            source,
             session: None,
-        })
+            #[cfg(unix)]
+            admin: None,
+        }
     }
 
     pub fn start(mut self) -> Result<RunningServer, Box<dyn Error>> {
-        let server = s2n_quic::Server::builder()
-            .with_tls((Path::new(&self.certificate), Path::new(&self.key)))?
-            .with_io(self.listen.to_string().as_str())?
-            .start()?;
+        let builder = s2n_quic::Server::builder()
+            .with_event((ClientCertificates, QuicStats))?
+            .with_io(self.config.listen_addr()?.to_string().as_str())?;
+
+        let (certificate, key) = self.config.certificate();
+        let server = match &self.config.auth.client_ca {
+            Some(ca) => {
+                info!("require client certificates signed by {}", ca);
+                let tls = tls::default::Server::builder()
+                    .with_certificate(Path::new(certificate), Path::new(key))?
+                    .with_empty_trust_store()?
+                    .with_trusted_certificate(Path::new(ca))?
+                    .with_client_authentication()?
+                    .with_verify_host_name_callback(AcceptAnyName)?
+                    .build()?;
+                builder.with_tls(tls)?.start()?
+            }
+            None => builder
+                .with_tls((Path::new(certificate), Path::new(key)))?
+                .start()?,
+        };
+
+        let (stores, users): (Stores, Box<dyn UserStore + Send>) = match self.config.data_dir() {
+            Some(dir) => {
+                let dir = Path::new(dir);
+                std::fs::create_dir_all(dir)?;
+                (
+                    Stores {
+                        messages: Box::new(FileStore::open(dir.join("offline.log"))?),
+                        history: Box::new(FileHistory::open(dir.join("history.log"))?),
+                        keys: Box::new(FileKeys::open(dir.join("keys.log"))?),
+                    },
+                    Box::new(FileUsers::open(dir.join("users.log"))?),
+                )
+            }
+            None => (
+                Stores {
+                    messages: Box::new(MemoryStore::new()),
+                    history: Box::new(MemoryHistory::new()),
+                    keys: Box::new(MemoryKeys::new()),
+                },
+                Box::new(MemoryUsers::new()),
+            ),
+        };
+
+        // files in flight, in the temporary directory without a data directory
+        let uploads_dir = match self.config.data_dir() {
+            Some(dir) => Path::new(dir).join("files"),
+            None => std::env::temp_dir().join(format!("chat-files-{}", std::process::id())),
+        };
+        let uploads = Uploads::open(uploads_dir, self.config.limits.uploads())?;
+
+        let users: SharedUsers = Arc::new(Mutex::new(users));
+        let accounts =
+            SyncArbiter::start(ACCOUNTS_THREADS, move || Accounts::new(Arc::clone(&users)));
+
+        let rooms = RoomRegistry::new().start();
+        let (stopped_tx, stopped) = oneshot::channel();
 
         info!("start a server session");
-        let server_session = ServerSession::new(server);
+        let server_session = ServerSession::new(
+            server,
+            stores,
+            uploads,
+            accounts,
+            rooms,
+            self.config.policy(),
+            stopped_tx,
+        );
         let addr = server_session.start();
-        self.session = Some(addr);
+        self.session = Some(addr.clone());
+        self.start_admin(addr.clone())?;
+        if let Some(listen) = self.config.metrics_addr()? {
+            metrics::serve(listen)?;
+        }
+
+        let running = self.config.clone();
+        let source = self.source.clone();
+        let session = addr.clone();
+        actix_rt::spawn(async move {
+            if let Err(e) = reload_on_hangup(running, source, session).await {
+                error!("listen for reload signals failed: {}", e);
+            }
+        });
 
-        Ok(RunningServer(self))
+        actix_rt::spawn(async move {
+            match shutdown_signal().await {
+                Ok(()) => {
+                    info!("shutdown signal received");
+                    addr.do_send(Stop);
+                }
+                Err(e) => error!("listen for shutdown signals failed: {}", e),
+            }
+        });
+
+        Ok(RunningServer {
+            _server: self,
+            stopped,
+        })
     }
 }
 
-pub struct RunningServer(#[allow(dead_code)] Server);
-
-impl Future for RunningServer {
-    type Output = ();
+impl Server {
+    #[cfg(unix)]
+    fn start_admin(&mut self, session: Addr<ServerSession>) -> std::io::Result<()> {
+        if let Some(socket) = &self.config.admin.socket {
+            self.admin = Some(AdminListener::start(Path::new(socket), session)?);
+        }
+        Ok(())
+    }
 
-    fn poll(
-        self: std::pin::Pin<&mut Self>,
-        _cx: &mut std::task::Context<'_>,
-    ) -> Poll<Self::Output> {
-        Poll::Pending
+    #[cfg(not(unix))]
+    fn start_admin(&mut self, _session: Addr<ServerSession>) -> std::io::Result<()> {
+        if self.config.admin.socket.is_some() {
+            warn!("the admin interface needs unix sockets, not started");
+        }
+        Ok(())
     }
 }
 
-#[derive(Debug)]
-pub enum ConstructServerError {
-    InvalidIpAddr,
+/// the admin socket would refuse the next server otherwise
+#[cfg(unix)]
+impl Drop for Server {
+    fn drop(&mut self) {
+        if let (Some(_), Some(socket)) = (&self.admin, &self.config.admin.socket) {
+            let _ = std::fs::remove_file(socket);
+        }
+    }
 }
 
-impl Display for ConstructServerError {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        let error_msg = match self {
-            ConstructServerError::InvalidIpAddr => "invalid Ip address",
+/// load the settings again on every SIGHUP, and apply those that can change while running
+#[cfg(unix)]
+async fn reload_on_hangup(
+    mut running: Config,
+    source: ConfigSource,
+    session: Addr<ServerSession>,
+) -> std::io::Result<()> {
+    use actix_rt::signal::unix::{signal, SignalKind};
+
+    let mut hangup = signal(SignalKind::hangup())?;
+    while hangup.recv().await.is_some() {
+        info!("reload signal received");
+        let config = match source.load() {
+            Ok(config) => config,
+            Err(e) => {
+                error!("reload failed, keeping the settings: {}", e);
+                continue;
+            }
         };
 
-        write!(f, "{}", error_msg)
+        for setting in config.restart_needed(&running) {
+            warn!("{} changed, it applies after a restart", setting);
+        }
+        logging::set_filter(config.log.level.as_deref());
+        session.do_send(Reload(config.policy()));
+
+        running = config;
+    }
+
+    Ok(())
+}
+
+#[cfg(not(unix))]
+async fn reload_on_hangup(
+    _running: Config,
+    _source: ConfigSource,
+    _session: Addr<ServerSession>,
+) -> std::io::Result<()> {
+    Ok(())
+}
+
+/// resolves on SIGINT or SIGTERM
+#[cfg(unix)]
+async fn shutdown_signal() -> std::io::Result<()> {
+    use actix_rt::signal::unix::{signal, SignalKind};
+
+    let mut terminate = signal(SignalKind::terminate())?;
+    tokio::select! {
+        res = actix_rt::signal::ctrl_c() => res,
+        _ = terminate.recv() => Ok(()),
     }
 }
 
-impl Error for ConstructServerError {}
+#[cfg(not(unix))]
+async fn shutdown_signal() -> std::io::Result<()> {
+    actix_rt::signal::ctrl_c().await
+}
+
+/// resolves once the server shut down, see `ServerSession`'s `Handler<Stop>`
+pub struct RunningServer {
+    _server: Server,
+    stopped: oneshot::Receiver<()>,
+}
+
+impl Future for RunningServer {
+    type Output = ();
+
+    fn poll(
+        mut self: std::pin::Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+    ) -> Poll<Self::Output> {
+        // the session dropping the sender means it is gone as well
+        std::pin::Pin::new(&mut self.stopped).poll(cx).map(|_| ())
+    }
+}
@@ -0,0 +1,279 @@
+use std::{
+    fmt::Display,
+    sync::{Arc, Mutex},
+};
+
+use actix::prelude::*;
+use argon2::{
+    password_hash::{rand_core::OsRng, SaltString},
+    Argon2, PasswordHash, PasswordHasher, PasswordVerifier,
+};
+use common::is_email;
+use log::{error, info};
+
+use crate::store::UserStore;
+
+/// how many threads hash passwords, so logins don't block the session actors
+pub const ACCOUNTS_THREADS: usize = 2;
+
+pub type SharedUsers = Arc<Mutex<Box<dyn UserStore + Send>>>;
+
+/// verifies and registers accounts, started in a `SyncArbiter`
+pub struct Accounts {
+    users: SharedUsers,
+    /// verified against when the email has no account, so a login takes as long either way
+    /// and its timing does not tell which emails are registered
+    dummy_hash: String,
+}
+
+impl Accounts {
+    pub fn new(users: SharedUsers) -> Self {
+        let salt = SaltString::generate(&mut OsRng);
+        let dummy_hash = Argon2::default()
+            .hash_password(salt.as_str().as_bytes(), &salt)
+            .expect("hash with default argon2 parameters")
+            .to_string();
+
+        Self { users, dummy_hash }
+    }
+
+    fn login(&self, email: &str, password: &str) -> Result<(), AuthError> {
+        if !is_email(email) {
+            return Err(AuthError::InvalidEmail);
+        }
+
+        let stored = self
+            .users
+            .lock()
+            .map_err(|_| AuthError::Internal)?
+            .password_hash(email);
+        let known = stored.is_some();
+        let stored = stored.unwrap_or_else(|| self.dummy_hash.clone());
+
+        let hash = PasswordHash::new(&stored).map_err(|e| {
+            error!("stored hash of {} invalid: {}", email, e);
+            AuthError::Internal
+        })?;
+
+        let verified = Argon2::default().verify_password(password.as_bytes(), &hash);
+        match verified {
+            Ok(()) if known => Ok(()),
+            _ => Err(AuthError::InvalidCredentials),
+        }
+    }
+
+    fn register(&self, email: &str, password: &str) -> Result<(), AuthError> {
+        if !is_email(email) {
+            return Err(AuthError::InvalidEmail);
+        }
+        if password.is_empty() {
+            return Err(AuthError::InvalidCredentials);
+        }
+        self.insert(email, password)
+    }
+
+    /// the certificate proved `email`, its account is made on the first login,
+    /// with a password nobody knows so it is only ever logged in with a certificate
+    fn certify(&self, email: &str) -> Result<(), AuthError> {
+        if !is_email(email) {
+            return Err(AuthError::InvalidEmail);
+        }
+        if self.registered(email)? {
+            return Ok(());
+        }
+
+        let password = SaltString::generate(&mut OsRng);
+        match self.insert(email, password.as_str()) {
+            // registered meanwhile by another login of the same certificate
+            Err(AuthError::AccountExists) => Ok(()),
+            res => res,
+        }
+    }
+
+    fn registered(&self, email: &str) -> Result<bool, AuthError> {
+        let users = self.users.lock().map_err(|_| AuthError::Internal)?;
+        Ok(users.password_hash(email).is_some())
+    }
+
+    fn insert(&self, email: &str, password: &str) -> Result<(), AuthError> {
+        let salt = SaltString::generate(&mut OsRng);
+        let hash = Argon2::default()
+            .hash_password(password.as_bytes(), &salt)
+            .map_err(|e| {
+                error!("hash password failed: {}", e);
+                AuthError::Internal
+            })?
+            .to_string();
+
+        let mut users = self.users.lock().map_err(|_| AuthError::Internal)?;
+        if users.password_hash(email).is_some() {
+            return Err(AuthError::AccountExists);
+        }
+        users.insert(email.to_string(), hash).map_err(|e| {
+            error!("{}", e);
+            AuthError::Internal
+        })?;
+        info!("account registered: {}", email);
+
+        Ok(())
+    }
+}
+
+impl Actor for Accounts {
+    type Context = SyncContext<Self>;
+}
+
+#[derive(Message)]
+#[rtype(result = "Result<(), AuthError>")]
+pub enum Authenticate {
+    Login {
+        email: String,
+        password: String,
+    },
+    Register {
+        email: String,
+        password: String,
+    },
+    /// proved by the client certificate
+    Certificate {
+        email: String,
+    },
+}
+
+impl Handler<Authenticate> for Accounts {
+    type Result = Result<(), AuthError>;
+
+    fn handle(&mut self, msg: Authenticate, _ctx: &mut Self::Context) -> Self::Result {
+        match msg {
+            Authenticate::Login { email, password } => self.login(&email, &password),
+            Authenticate::Register { email, password } => self.register(&email, &password),
+            Authenticate::Certificate { email } => self.certify(&email),
+        }
+    }
+}
+
+/// whether `email` has an account, only accounts are queued transfers for
+#[derive(Message)]
+#[rtype(result = "Result<bool, AuthError>")]
+pub struct Registered {
+    pub email: String,
+}
+
+impl Handler<Registered> for Accounts {
+    type Result = Result<bool, AuthError>;
+
+    fn handle(&mut self, msg: Registered, _ctx: &mut Self::Context) -> Self::Result {
+        self.registered(&msg.email)
+    }
+}
+
+#[derive(Debug)]
+pub enum AuthError {
+    InvalidCredentials,
+    /// not `local@domain`, which could be taken for a room
+    InvalidEmail,
+    AccountExists,
+    Internal,
+}
+
+impl Display for AuthError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let msg = match self {
+            AuthError::InvalidCredentials => "invalid email or password",
+            AuthError::InvalidEmail => "not an email",
+            AuthError::AccountExists => "account already exists",
+            AuthError::Internal => "internal server error",
+        };
+
+        write!(f, "{}", msg)
+    }
+}
+
+impl std::error::Error for AuthError {}
+
+#[cfg(test)]
+mod tests {
+    use crate::store::MemoryUsers;
+
+    use super::*;
+
+    #[test]
+    fn login_checks_the_password_of_known_emails_only() {
+        let users: SharedUsers = Arc::new(Mutex::new(Box::new(MemoryUsers::new())));
+        let accounts = Accounts::new(users);
+        accounts.register("alice@x", "secret").unwrap();
+
+        let cases = [
+            ("alice@x", "secret", true),
+            ("alice@x", "wrong", false),
+            ("bob@x", "secret", false),
+        ];
+
+        for (email, password, ok) in cases {
+            let result = accounts.login(email, password);
+            assert_eq!(result.is_ok(), ok, "{} {}", email, password);
+            if !ok {
+                assert!(matches!(result, Err(AuthError::InvalidCredentials)));
+            }
+        }
+
+        assert!(matches!(
+            accounts.login("#room", "secret"),
+            Err(AuthError::InvalidEmail)
+        ));
+
+        // even the password the dummy hash was made of does not log in an unknown email
+        let dummy = PasswordHash::new(&accounts.dummy_hash)
+            .unwrap()
+            .salt
+            .unwrap()
+            .to_string();
+        assert!(matches!(
+            accounts.login("bob@x", &dummy),
+            Err(AuthError::InvalidCredentials)
+        ));
+    }
+
+    #[test]
+    fn only_emails_are_registered() {
+        let users: SharedUsers = Arc::new(Mutex::new(Box::new(MemoryUsers::new())));
+        let accounts = Accounts::new(users);
+
+        // the expected error by the name of its variant
+        let cases = [
+            ("alice@x", "secret", None),
+            ("alice@x", "secret", Some("AccountExists")),
+            ("bob@x", "", Some("InvalidCredentials")),
+            ("#room", "secret", Some("InvalidEmail")),
+            ("#room@x", "secret", Some("InvalidEmail")),
+            ("bob", "secret", Some("InvalidEmail")),
+            ("", "secret", Some("InvalidEmail")),
+        ];
+
+        for (email, password, expected) in cases {
+            let result = accounts
+                .register(email, password)
+                .map_err(|e| format!("{:?}", e));
+            assert_eq!(result.err().as_deref(), expected, "{:?}", email);
+        }
+    }
+
+    #[test]
+    fn certificates_make_an_account_no_password_logs_in() {
+        let users: SharedUsers = Arc::new(Mutex::new(Box::new(MemoryUsers::new())));
+        let accounts = Accounts::new(users);
+        assert!(!accounts.registered("alice@x").unwrap());
+
+        accounts.certify("alice@x").unwrap();
+        accounts.certify("alice@x").unwrap();
+        assert!(accounts.registered("alice@x").unwrap());
+        assert!(matches!(
+            accounts.register("alice@x", "secret"),
+            Err(AuthError::AccountExists)
+        ));
+        assert!(matches!(
+            accounts.login("alice@x", ""),
+            Err(AuthError::InvalidCredentials)
+        ));
+    }
+}
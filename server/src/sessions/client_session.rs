@@ -1,34 +1,164 @@
-use std::fmt::Display;
+use std::{
+    collections::HashMap,
+    io::{self, SeekFrom},
+    net::IpAddr,
+    path::PathBuf,
+    time::Instant,
+};
 
 use actix::prelude::*;
 use async_stream::stream;
 use bytes::Bytes;
 use log::{error, info, warn};
 use s2n_quic::{
-    connection::Connection,
-    stream::{BidirectionalStream, SendStream},
+    connection::{Connection, Handle},
+    stream::{PeerStream, ReceiveStream, SendStream},
+};
+use tokio::{
+    io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt},
+    sync::mpsc::{self, error::TrySendError},
+    task::JoinHandle,
+};
+use ulid::Ulid;
+
+use super::{
+    accounts::{AuthError, Authenticate},
+    server_session::{
+        Disconnected, Downloaded, Echo, FinishUpload, LoggedOut, LookupKeys, OfferFile,
+        PublishKeys, SetPresence, StartDownload, StartUpload, SubscribePresence, WhoOnline,
+        SHUTDOWN_DEADLINE,
+    },
+    Accounts, RoomRegistry, ServerSession,
+};
+use crate::{
+    metrics::{metrics, Queued},
+    rate_limit::{Peer, SharedLimiter, Verdict},
+    store::UploadError,
+    tls::CertificateIdentity,
+};
+use common::{
+    codec::FrameDecoder,
+    crypto::KeyBundle,
+    protocol::{AckStatus, ErrorCode, Frame, REFUSED_CLOSE_CODE, SHUTDOWN_CLOSE_CODE},
+    Error, *,
 };
 
-use super::ServerSession;
-use common::*;
+/// bytes of a file read and sent at once
+const FILE_CHUNK: usize = 64 * 1024;
 
 pub struct ClientSession {
     server_addr: Addr<ServerSession>,
+    accounts: Addr<Accounts>,
+    rooms: Addr<RoomRegistry>,
+    limiter: SharedLimiter,
     conn: Option<Connection>,
+    /// opens streams and closes the connection after `conn` moved into the stream accepting its streams
+    conn_handle: Handle,
     email: String,
-    send_stream: Option<SendStream>,
+    /// proved by the client certificate, when the server requires one
+    certificate_email: Option<String>,
+    /// the source address, charged by the rate limiter along with the email
+    address: Option<IpAddr>,
+    policy: SessionPolicy,
+    /// the control stream, and a stream per conversation chat is sent to the client on
+    send_streams: HashMap<StreamKey, Outbound>,
     status: ClientStatus,
 }
 
+/// what the client's receive streams yield
+enum Incoming {
+    Frame(Bytes),
+    /// the control stream ended, and the session with it
+    Closed,
+    /// bytes that are no frame, the stream is not read any further
+    Invalid {
+        error: Error,
+        control: bool,
+    },
+    /// a file stream whose `FileData` frame was read, `leftover` are the bytes read past it
+    File {
+        id: Ulid,
+        offset: u64,
+        leftover: Bytes,
+        recv: ReceiveStream,
+    },
+}
+
+/// which of the client's streams, see `protocol::Frame` for the layout
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+enum StreamKey {
+    Control,
+    /// a room, or the other email of a conversation
+    Conversation(String),
+    Signals,
+}
+
+/// what the server's policy says about a session, taken when it starts
+#[derive(Debug, Clone, Copy)]
+pub struct SessionPolicy {
+    /// whether `Login` and `Register` are allowed, or only `CertificateLogin`
+    pub password_login: bool,
+    /// frames waiting to be written to each stream, see `Outbound`
+    pub outbound_queue: usize,
+}
+
+/// a stream to the client, frames wait in a bounded queue for the task writing them,
+/// so a client reading slowly fills its queue instead of the server's memory
+struct Outbound {
+    queue: mpsc::Sender<Bytes>,
+    writer: JoinHandle<()>,
+}
+
+impl Outbound {
+    fn new(mut stream: SendStream, capacity: usize, email: String) -> Self {
+        let (queue, mut frames) = mpsc::channel::<Bytes>(capacity);
+        let writer = actix_rt::spawn(async move {
+            while let Some(bytes) = frames.recv().await {
+                let len = bytes.len() as u64;
+                if let Err(e) = stream.send(bytes).await {
+                    warn!("client: {} write stream failed: {}", email, e);
+                    return;
+                }
+                metrics().sent_bytes.inc_by(len);
+            }
+            // the queue closed, what was written still has to reach the client
+            let _ = stream.flush().await;
+        });
+
+        Self { queue, writer }
+    }
+}
+
 impl ClientSession {
-    pub fn new(conn: Connection, email: String, server_addr: Addr<ServerSession>) -> Self {
+    pub fn new(
+        conn: Connection,
+        email: String,
+        server_addr: Addr<ServerSession>,
+        accounts: Addr<Accounts>,
+        rooms: Addr<RoomRegistry>,
+        limiter: SharedLimiter,
+        policy: SessionPolicy,
+    ) -> Self {
         info!("client new, email: {}", email);
 
+        let certificate_email = conn
+            .query_event_context(|id: &CertificateIdentity| id.0.clone())
+            .ok()
+            .flatten();
+        let address = conn.remote_addr().ok().map(|remote| remote.ip());
+
         Self {
             server_addr,
+            accounts,
+            rooms,
+            limiter,
+            conn_handle: conn.handle(),
             conn: Some(conn),
             email,
-            send_stream: None,
+            certificate_email,
+            address,
+            policy,
+            send_streams: HashMap::new(),
             status: ClientStatus::Init,
         }
     }
@@ -37,69 +167,653 @@ impl ClientSession {
         &mut self,
         bytes: Bytes,
         ctx: &mut actix::Context<ClientSession>,
-    ) -> Result<(), ClientSessionError> {
-        match self.status {
-            ClientStatus::Init => {
-                if let Ok(email) = String::from_utf8(bytes.to_vec()) {
-                    self.change_email(email, ctx);
-                } else {
-                    return Err(ClientSessionError::InvalidEmail);
+    ) -> Result<(), Error> {
+        let frame = Frame::try_from(bytes)?;
+
+        match (&self.status, frame) {
+            (ClientStatus::Init, Frame::Login { .. } | Frame::Register { .. })
+                if !self.policy.password_login =>
+            {
+                metrics().login_failures.inc();
+                self.send_frame(&Frame::LoginRejected {
+                    reason: "password login disabled, log in with a certificate".to_string(),
+                });
+            }
+            (ClientStatus::Init, Frame::Login { email, password }) => {
+                self.authenticate(Authenticate::Login { email, password }, ctx);
+            }
+            (ClientStatus::Init, Frame::Register { email, password }) => {
+                self.authenticate(Authenticate::Register { email, password }, ctx);
+            }
+            (ClientStatus::Init, Frame::CertificateLogin) => match self.certificate_email.clone() {
+                Some(email) => self.authenticate(Authenticate::Certificate { email }, ctx),
+                None => {
+                    metrics().login_failures.inc();
+                    self.send_frame(&Frame::LoginRejected {
+                        reason: "no client certificate identity".to_string(),
+                    });
                 }
+            },
+            (ClientStatus::Init, Frame::Resume { token }) => {
+                self.change_identity(ClientChange::Resume(self.email.clone(), token), ctx);
             }
-            ClientStatus::LoggedIn => {
-                let transfer: Transfer = bytes
-                    .try_into()
-                    .map_err(|_| ClientSessionError::InvalidBytes)?;
-
-                self.server_addr.do_send(transfer);
+            (ClientStatus::LoggedIn, Frame::Chat(transfer)) => {
+                self.route(transfer, ctx);
+            }
+            (
+                ClientStatus::LoggedIn,
+                Frame::HistoryRequest {
+                    id,
+                    peer,
+                    before,
+                    limit,
+                },
+            ) => {
+                self.history(id, peer, before, limit as usize, ctx);
+            }
+            (ClientStatus::LoggedIn, Frame::RoomJoin { id, room }) => {
+                let email = self.email.clone();
+                self.room_request(id, JoinRoom { room, email }, ctx)?;
+            }
+            (ClientStatus::LoggedIn, Frame::RoomLeave { id, room }) => {
+                let email = self.email.clone();
+                self.room_request(id, LeaveRoom { room, email }, ctx)?;
+            }
+            (ClientStatus::LoggedIn, Frame::RoomMembersRequest { id, room }) => {
+                self.room_request(id, ListMembers { room }, ctx)?;
+            }
+            (ClientStatus::LoggedIn, Frame::FileOffer(offer)) => {
+                self.offer_file(offer, ctx);
+            }
+            (ClientStatus::LoggedIn, Frame::FileAccept { id, offset }) => {
+                self.send_file(id, offset, ctx);
+            }
+            (ClientStatus::LoggedIn, Frame::PresenceSet { status }) => {
+                self.server_addr.do_send(SetPresence {
+                    email: self.email.clone(),
+                    session: ctx.address(),
+                    away: status != Presence::Online,
+                });
+            }
+            (ClientStatus::LoggedIn, Frame::PresenceSubscribe { id, contacts }) => {
+                let subscribe = SubscribePresence {
+                    session: ctx.address(),
+                    contacts,
+                };
+                self.presence_request(id, subscribe, ctx);
+            }
+            (ClientStatus::LoggedIn, Frame::WhoOnlineRequest { id }) => {
+                self.presence_request(id, WhoOnline, ctx);
+            }
+            (ClientStatus::LoggedIn, Frame::Signal(mut signal)) => {
+                signal.from = self.email.clone();
+                self.server_addr.do_send(signal);
+            }
+            (ClientStatus::LoggedIn, Frame::KeysPublish { id, bundle }) => {
+                self.publish_keys(id, bundle, ctx);
+            }
+            (ClientStatus::LoggedIn, Frame::KeysRequest { id, email }) => {
+                self.lookup_keys(id, email, ctx);
+            }
+            (
+                ClientStatus::Init,
+                Frame::Chat(_)
+                | Frame::HistoryRequest { .. }
+                | Frame::RoomJoin { .. }
+                | Frame::RoomLeave { .. }
+                | Frame::RoomMembersRequest { .. }
+                | Frame::FileOffer(_)
+                | Frame::FileAccept { .. }
+                | Frame::PresenceSet { .. }
+                | Frame::PresenceSubscribe { .. }
+                | Frame::WhoOnlineRequest { .. }
+                | Frame::Signal(_)
+                | Frame::KeysPublish { .. }
+                | Frame::KeysRequest { .. },
+            ) => {
+                return Err(Error::NotLoggedIn);
+            }
+            (_, Frame::Ping) => {
+                self.send_frame(&Frame::Pong);
+            }
+            (_, Frame::Logout) => {
+                info!("client: {} logout", self.email);
+                self.server_addr.do_send(LoggedOut {
+                    email: self.email.clone(),
+                });
+                ctx.stop();
+            }
+            (_, frame) => {
+                return Err(Error::UnexpectedFrame(frame.name()));
             }
         }
 
         Ok(())
     }
 
+    /// check the credentials, only then take over the email
+    fn authenticate(&mut self, auth: Authenticate, ctx: &mut actix::Context<ClientSession>) {
+        let email = match &auth {
+            Authenticate::Login { email, .. }
+            | Authenticate::Register { email, .. }
+            | Authenticate::Certificate { email } => email.clone(),
+        };
+
+        self.accounts
+            .send(auth)
+            .into_actor(self)
+            .map(|res, act, ctx| {
+                let res = res.unwrap_or_else(|e| {
+                    error!("{}", e);
+                    Err(AuthError::Internal)
+                });
+
+                match res {
+                    Ok(()) => act.change_email(email, ctx),
+                    Err(e) => {
+                        metrics().login_failures.inc();
+                        warn!(
+                            "client: {} authenticate as {} failed: {}",
+                            act.email, email, e
+                        );
+                        match e {
+                            AuthError::InvalidEmail => act.send_frame(&Frame::Error {
+                                code: ErrorCode::InvalidEmail,
+                                msg: e.to_string(),
+                            }),
+                            e => act.send_frame(&Frame::LoginRejected {
+                                reason: e.to_string(),
+                            }),
+                        }
+                    }
+                }
+            })
+            .wait(ctx);
+    }
+
     fn change_email(&mut self, email: String, ctx: &mut actix::Context<ClientSession>) {
+        self.change_identity(ClientChange::UpdateEmail(self.email.clone(), email), ctx);
+    }
+
+    fn change_identity(&mut self, change: ClientChange, ctx: &mut actix::Context<ClientSession>) {
         self.server_addr
-            .send(ClientChange::UpdateEmail(self.email.clone(), email.clone()))
+            .send(change)
             .into_actor(self)
-            .map(|res, act, _ctx| {
-                if let Err(e) = res {
-                    error!("{}", e);
-                } else {
-                    act.email = email;
+            .map(|res, act, _ctx| match res {
+                Ok(Ok(SessionIdentity { email, token })) => {
+                    act.email = email.clone();
                     act.status = ClientStatus::LoggedIn;
+                    metrics().logins.inc();
                     info!("change email successful");
+                    act.send_frame(&Frame::LoginOk { email, token });
+                }
+                Ok(Err(e)) => {
+                    metrics().login_failures.inc();
+                    warn!("client: {} {}", act.email, e);
+                    act.send_frame(&Frame::LoginRejected {
+                        reason: e.to_string(),
+                    });
+                }
+                Err(e) => {
+                    error!("{}", e);
+                    act.send_frame(&Frame::LoginRejected {
+                        reason: "server busy".to_string(),
+                    });
                 }
             })
             .wait(ctx);
-        info!("after email changed");
     }
-}
 
-#[derive(Debug)]
-enum ClientStatus {
-    Init,
-    LoggedIn,
-}
+    /// hand the transfer to the server, and ack the sender once it was delivered or failed
+    fn route(&mut self, mut transfer: Transfer, ctx: &mut actix::Context<ClientSession>) {
+        // whatever the client claims, it sends as who it logged in as
+        transfer.from = self.email.clone();
+        let id = transfer.id;
+        let echo = transfer.clone();
+        let queued = Queued::new("server_session");
 
-#[derive(Debug)]
-pub enum ClientSessionError {
-    InvalidEmail,
-    InvalidBytes,
-}
+        self.server_addr
+            .send(transfer)
+            .into_actor(self)
+            .map(move |res, act, ctx| {
+                drop(queued);
+                if matches!(res, Ok(Ok(_))) {
+                    act.server_addr.do_send(Echo {
+                        transfer: echo,
+                        origin: ctx.address(),
+                    });
+                }
+
+                let status = match res {
+                    Ok(Ok(Delivery::Delivered)) => AckStatus::Delivered,
+                    Ok(Ok(Delivery::Queued)) => AckStatus::Queued,
+                    Ok(Err(e)) => {
+                        warn!("client: {} transfer {} failed: {}", act.email, id, e);
+                        AckStatus::Failed((&e).into())
+                    }
+                    Err(e) => {
+                        error!("{}", e);
+                        AckStatus::Failed(ErrorCode::DestinationOffline)
+                    }
+                };
+                act.send_frame(&Frame::Ack { id, status });
+            })
+            .spawn(ctx);
+    }
 
-impl Display for ClientSessionError {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        let msg = match self {
-            ClientSessionError::InvalidEmail => "invalid email",
-            ClientSessionError::InvalidBytes => "invalid bytes",
+    fn history(
+        &mut self,
+        id: Ulid,
+        peer: String,
+        before: Option<Ulid>,
+        limit: usize,
+        ctx: &mut actix::Context<ClientSession>,
+    ) {
+        self.server_addr
+            .send(HistoryQuery {
+                of: self.email.clone(),
+                peer,
+                before,
+                limit,
+            })
+            .into_actor(self)
+            .map(move |res, act, _ctx| {
+                let entries = res.unwrap_or_else(|e| {
+                    error!("{}", e);
+                    vec![]
+                });
+                act.send_frame(&Frame::History { id, entries });
+            })
+            .spawn(ctx);
+    }
+
+    fn publish_keys(
+        &mut self,
+        id: Ulid,
+        bundle: KeyBundle,
+        ctx: &mut actix::Context<ClientSession>,
+    ) {
+        self.server_addr
+            .send(PublishKeys {
+                email: self.email.clone(),
+                bundle,
+            })
+            .into_actor(self)
+            .map(move |res, act, _ctx| {
+                let status = match res {
+                    Ok(Ok(())) => AckStatus::Delivered,
+                    Ok(Err(e)) => {
+                        error!("client: {} publish keys failed: {}", act.email, e);
+                        AckStatus::Failed(ErrorCode::Internal)
+                    }
+                    Err(e) => {
+                        error!("{}", e);
+                        AckStatus::Failed(ErrorCode::Internal)
+                    }
+                };
+                act.send_frame(&Frame::Ack { id, status });
+            })
+            .spawn(ctx);
+    }
+
+    fn lookup_keys(&mut self, id: Ulid, email: String, ctx: &mut actix::Context<ClientSession>) {
+        self.server_addr
+            .send(LookupKeys {
+                email: email.clone(),
+            })
+            .into_actor(self)
+            .map(move |res, act, _ctx| {
+                let bundles = res.unwrap_or_else(|e| {
+                    error!("{}", e);
+                    vec![]
+                });
+                act.send_frame(&Frame::Keys { id, email, bundles });
+            })
+            .spawn(ctx);
+    }
+
+    /// ask the server about presence, reply with what it results in
+    fn presence_request<M>(&mut self, id: Ulid, msg: M, ctx: &mut actix::Context<ClientSession>)
+    where
+        M: Message<Result = Vec<(String, Presence)>> + Send + 'static,
+        ServerSession: Handler<M>,
+    {
+        self.server_addr
+            .send(msg)
+            .into_actor(self)
+            .map(move |res, act, _ctx| {
+                let entries = res.unwrap_or_else(|e| {
+                    error!("{}", e);
+                    vec![]
+                });
+                act.send_frame(&Frame::Presences { id, entries });
+            })
+            .spawn(ctx);
+    }
+
+    /// forward a room change or query to the registry, reply with the members it results in
+    fn room_request<M>(
+        &mut self,
+        id: Ulid,
+        msg: M,
+        ctx: &mut actix::Context<ClientSession>,
+    ) -> Result<(), Error>
+    where
+        M: RoomMessage + Message<Result = Vec<String>> + Send + 'static,
+        RoomRegistry: Handler<M>,
+    {
+        let room = msg.room().to_string();
+        if !is_room(&room) {
+            return Err(Error::InvalidRoom(room));
+        }
+
+        self.rooms
+            .send(msg)
+            .into_actor(self)
+            .map(move |res, act, _ctx| {
+                let members = res.unwrap_or_else(|e| {
+                    error!("{}", e);
+                    vec![]
+                });
+                act.send_frame(&Frame::RoomMembers { id, room, members });
+            })
+            .spawn(ctx);
+
+        Ok(())
+    }
+
+    /// register the client's offer, and tell it where to stream the file from
+    fn offer_file(&mut self, mut offer: FileOffer, ctx: &mut actix::Context<ClientSession>) {
+        offer.from = self.email.clone();
+        let id = offer.id;
+
+        self.server_addr
+            .send(OfferFile(offer))
+            .into_actor(self)
+            .map(move |res, act, _ctx| {
+                let frame = match res {
+                    Ok(Ok(offset)) => Frame::FileAccept { id, offset },
+                    Ok(Err(e)) => Frame::FileReject {
+                        id,
+                        reason: e.to_string(),
+                    },
+                    Err(e) => {
+                        error!("{}", e);
+                        Frame::FileReject {
+                            id,
+                            reason: "server busy".to_string(),
+                        }
+                    }
+                };
+                act.send_frame(&frame);
+            })
+            .spawn(ctx);
+    }
+
+    /// write the file stream the client opened, ack it once it matched the offer
+    fn receive_file(
+        &mut self,
+        id: Ulid,
+        offset: u64,
+        leftover: Bytes,
+        recv: ReceiveStream,
+        ctx: &mut actix::Context<ClientSession>,
+    ) {
+        info!("client: {} uploads {} from {}", self.email, id, offset);
+        let server_addr = self.server_addr.clone();
+        let limiter = self.limiter.clone();
+        let peers = self.peers();
+
+        self.server_addr
+            .send(StartUpload {
+                id,
+                from: self.email.clone(),
+                offset,
+            })
+            .into_actor(self)
+            .then(move |res, act, _ctx| {
+                async move {
+                    let (path, size) = res.map_err(|e| UploadError::Io(io::Error::other(e)))??;
+                    let throttle = Throttle { limiter, peers };
+                    let sha256 = write_upload(path, size, offset, leftover, recv, throttle).await?;
+
+                    server_addr
+                        .send(FinishUpload { id, sha256 })
+                        .await
+                        .map_err(|e| UploadError::Io(io::Error::other(e)))?
+                }
+                .into_actor(act)
+            })
+            .map(move |res, act, _ctx| {
+                let frame = match res {
+                    Ok(Delivery::Delivered) => Frame::Ack {
+                        id,
+                        status: AckStatus::Delivered,
+                    },
+                    Ok(Delivery::Queued) => Frame::Ack {
+                        id,
+                        status: AckStatus::Queued,
+                    },
+                    Err(UploadError::Mismatch) => Frame::Ack {
+                        id,
+                        status: AckStatus::Failed(ErrorCode::FileMismatch),
+                    },
+                    Err(UploadError::NotRoomMember) => Frame::Ack {
+                        id,
+                        status: AckStatus::Failed(ErrorCode::NotRoomMember),
+                    },
+                    Err(e) => {
+                        warn!("client: {} upload {} failed: {}", act.email, id, e);
+                        Frame::FileReject {
+                            id,
+                            reason: e.to_string(),
+                        }
+                    }
+                };
+                act.send_frame(&frame);
+            })
+            .spawn(ctx);
+    }
+
+    /// stream the file `id` to the client from `offset` on, on a stream of its own
+    fn send_file(&mut self, id: Ulid, offset: u64, ctx: &mut actix::Context<ClientSession>) {
+        let email = self.email.clone();
+
+        self.server_addr
+            .send(StartDownload {
+                id,
+                email: email.clone(),
+            })
+            .into_actor(self)
+            .then(move |res, act, _ctx| {
+                let conn_handle = act.conn_handle.clone();
+                async move {
+                    let (path, offer) = res.map_err(|e| UploadError::Io(io::Error::other(e)))??;
+                    if offset > offer.size {
+                        return Err(UploadError::Conflict);
+                    }
+                    stream_download(conn_handle, path, id, offset).await
+                }
+                .into_actor(act)
+            })
+            .map(move |res, act, _ctx| match res {
+                Ok(()) => act.server_addr.do_send(Downloaded { id, email }),
+                Err(e) => {
+                    warn!("client: {} download {} failed: {}", act.email, id, e);
+                    act.send_frame(&Frame::FileReject {
+                        id,
+                        reason: e.to_string(),
+                    });
+                }
+            })
+            .spawn(ctx);
+    }
+
+    /// whose rate limits what the client sends is charged to
+    fn peers(&self) -> Vec<Peer> {
+        let mut peers = self
+            .address
+            .map(Peer::Address)
+            .into_iter()
+            .collect::<Vec<_>>();
+        if matches!(self.status, ClientStatus::LoggedIn) {
+            peers.push(Peer::Email(self.email.clone()));
+        }
+        peers
+    }
+
+    /// tell the client `bytes` was dropped, a chat is acked as failed so its sender knows which
+    fn rate_limited(&mut self, bytes: Bytes) {
+        let frame = match Frame::try_from(bytes) {
+            Ok(Frame::Chat(transfer)) => Frame::Ack {
+                id: transfer.id,
+                status: AckStatus::Failed(ErrorCode::RateLimited),
+            },
+            _ => Frame::Error {
+                code: ErrorCode::RateLimited,
+                msg: "sending too fast, dropped".to_string(),
+            },
         };
+        self.send_frame(&frame);
+    }
 
-        write!(f, "{}", msg)
+    /// tell the client what went wrong with what it sent
+    fn report(&mut self, error: &Error) {
+        if matches!(error, Error::Protocol(_)) {
+            metrics().decode_errors.inc();
+        }
+        error!("client: {} {}", self.email, error);
+        self.send_frame(&Frame::Error {
+            code: error.code(),
+            msg: error.to_string(),
+        });
+    }
+
+    fn send_frame(&mut self, frame: &Frame) {
+        if let Err(e) = self.try_send_frame(frame) {
+            warn!("client: {} send {} failed: {}", self.email, frame.name(), e);
+        }
+    }
+
+    /// send on the control stream
+    fn try_send_frame(&mut self, frame: &Frame) -> Result<(), TransferError> {
+        self.send_on(&StreamKey::Control, frame)
+    }
+
+    /// queue the frame on `key`, a full queue means the client does not keep up
+    fn send_on(&mut self, key: &StreamKey, frame: &Frame) -> Result<(), TransferError> {
+        let outbound = self
+            .send_streams
+            .get(key)
+            .ok_or(TransferError::DestinationUnavailable)?;
+
+        let bytes = frame.to_bytes().map_err(|e| {
+            warn!(
+                "client: {} encode {} failed: {}",
+                self.email,
+                frame.name(),
+                e
+            );
+            TransferError::ConvertFromBytesFail
+        })?;
+        match outbound.queue.try_send(bytes) {
+            Ok(()) => Ok(()),
+            Err(TrySendError::Full(_)) => Err(TransferError::DestinationBusy),
+            Err(TrySendError::Closed(_)) => {
+                self.send_streams.remove(key);
+                Err(TransferError::DestinationUnavailable)
+            }
+        }
+    }
+
+    fn add_send_stream(&mut self, key: StreamKey, stream: SendStream) {
+        let outbound = Outbound::new(stream, self.policy.outbound_queue, self.email.clone());
+        self.send_streams.insert(key, outbound);
+    }
+
+    /// decode the frames arriving on `recv`, only the control stream ends the session when closed
+    fn add_receive_stream(
+        &mut self,
+        mut recv: ReceiveStream,
+        control: bool,
+        ctx: &mut actix::Context<ClientSession>,
+    ) {
+        let email = self.email.clone();
+
+        let recv_bytes = stream! {
+            let mut decoder = FrameDecoder::new();
+            while let Ok(Some(bytes)) = recv.receive().await {
+                info!("client: {} received data", email);
+                metrics().received_bytes.inc_by(bytes.len() as u64);
+                decoder.extend(&bytes);
+
+                loop {
+                    match decoder.next_frame() {
+                        Ok(Some(frame)) => {
+                            // a file stream goes on with raw bytes after its first frame
+                            if !control {
+                                if let Ok(Frame::FileData { id, offset }) = Frame::try_from(frame.clone()) {
+                                    yield Incoming::File {
+                                        id,
+                                        offset,
+                                        leftover: decoder.into_remaining(),
+                                        recv,
+                                    };
+                                    return;
+                                }
+                            }
+                            yield Incoming::Frame(frame)
+                        }
+                        Ok(None) => break,
+                        Err(e) => {
+                            yield Incoming::Invalid { error: e.into(), control };
+                            return;
+                        }
+                    }
+                }
+            }
+
+            warn!("client: {} stream closed", email);
+            if control {
+                yield Incoming::Closed;
+            }
+        };
+
+        ctx.add_stream(recv_bytes);
+    }
+
+    /// close the connection with `code` once the buffered frames are sent, or at `deadline`
+    fn close(&mut self, deadline: Instant, code: u32) -> ResponseActFuture<Self, ()> {
+        // transfers arriving from now on fail, and are queued by the server, the writers
+        // see their queues closed once they wrote what is in them
+        let writers = std::mem::take(&mut self.send_streams)
+            .into_values()
+            .map(|outbound| outbound.writer)
+            .collect::<Vec<_>>();
+        let conn_handle = self.conn_handle.clone();
+        let email = self.email.clone();
+
+        Box::pin(
+            async move {
+                let drained =
+                    tokio::time::timeout_at(deadline.into(), futures::future::join_all(writers))
+                        .await;
+                if !matches!(drained, Ok(written) if written.iter().all(|w| w.is_ok())) {
+                    warn!("client: {} not drained before closing", email);
+                }
+                conn_handle.close(code.into());
+            }
+            .into_actor(self)
+            .map(|_, _act, ctx| ctx.stop()),
+        )
     }
 }
 
-impl std::error::Error for ClientSessionError {}
+#[derive(Debug)]
+enum ClientStatus {
+    Init,
+    LoggedIn,
+}
 
 impl Actor for ClientSession {
     type Context = Context<Self>;
@@ -109,18 +823,17 @@ impl Actor for ClientSession {
         let email = self.email.clone();
 
         let recv_stream = {
-            let conn = self.conn.take();
-            if conn.is_none() {
-                info!("conn none");
+            let Some(mut conn) = self.conn.take() else {
+                error!("client: {} started without a connection", self.email);
+                ctx.stop();
                 return;
-            }
-            let mut conn = conn.unwrap();
+            };
 
             stream! {
-                while let Ok(stream) = conn.accept_bidirectional_stream().await {
-                    if stream.is_some() {
+                while let Ok(stream) = conn.accept().await {
+                    if let Some(stream) = stream {
                         info!("client: {} received stream", email);
-                        yield stream.unwrap();
+                        yield stream;
                     } else {
                         info!("client: {} connection closed without error", email);
                         return;
@@ -132,61 +845,345 @@ impl Actor for ClientSession {
 
         ctx.add_stream(recv_stream);
     }
-}
 
-impl StreamHandler<BidirectionalStream> for ClientSession {
-    fn handle(&mut self, stream: BidirectionalStream, ctx: &mut Self::Context) {
-        info!("openn bidirectional stream");
-        let email = self.email.clone();
-        let (mut recv, send) = stream.split();
+    fn stopped(&mut self, ctx: &mut Self::Context) {
+        info!("client: {} stopped", self.email);
+        self.server_addr.do_send(Disconnected {
+            email: self.email.clone(),
+            session: ctx.address(),
+        });
+    }
+}
 
-        self.send_stream = Some(send);
+impl StreamHandler<PeerStream> for ClientSession {
+    fn handle(&mut self, stream: PeerStream, ctx: &mut Self::Context) {
+        match stream {
+            PeerStream::Bidirectional(stream) => {
+                if self.send_streams.contains_key(&StreamKey::Control) {
+                    warn!("client: {} opened a second control stream", self.email);
+                    return;
+                }
 
-        let recv_bytes = stream! {
-            while let Ok(bytes) = recv.receive().await {
-                info!("client: {} received data", email);
-                yield bytes;
+                info!("client: {} opened the control stream", self.email);
+                let (recv, send) = stream.split();
+                self.add_send_stream(StreamKey::Control, send);
+                self.add_receive_stream(recv, true, ctx);
             }
-
-            warn!("client: {} stream closed", email);
-        };
-
-        ctx.add_stream(recv_bytes);
+            PeerStream::Receive(stream) => {
+                info!("client: {} opened a conversation stream", self.email);
+                self.add_receive_stream(stream, false, ctx);
+            }
+        }
     }
 }
 
-impl StreamHandler<Option<Bytes>> for ClientSession {
-    fn handle(&mut self, bytes: Option<Bytes>, ctx: &mut Self::Context) {
+impl StreamHandler<Incoming> for ClientSession {
+    fn handle(&mut self, incoming: Incoming, ctx: &mut Self::Context) {
         info!("client: {} handling data", self.email);
 
-        if bytes.is_none() {
-            info!("client: {} handle data none, stop session", self.email);
-            ctx.stop();
+        let bytes = match incoming {
+            Incoming::Frame(bytes) => bytes,
+            Incoming::Closed => {
+                info!("client: {} control stream closed, stop session", self.email);
+                ctx.stop();
+                return;
+            }
+            Incoming::Invalid { error, control } => {
+                self.report(&error);
+                // frames can not be told apart anymore, the session ends with the control stream
+                if control {
+                    ctx.notify(Refuse {
+                        deadline: Instant::now() + SHUTDOWN_DEADLINE,
+                    });
+                }
+                return;
+            }
+            Incoming::File {
+                id,
+                offset,
+                leftover,
+                recv,
+            } => {
+                if matches!(self.status, ClientStatus::LoggedIn) {
+                    self.receive_file(id, offset, leftover, recv, ctx);
+                }
+                return;
+            }
+        };
+
+        let verdict = self.limiter.lock().frame(&self.peers(), bytes.len());
+        match verdict {
+            Verdict::Allowed => {}
+            Verdict::Limited => {
+                info!("client: {} rate limited", self.email);
+                self.rate_limited(bytes);
+                return;
+            }
+            Verdict::Exceeded => {
+                warn!(
+                    "client: {} exceeded the rate limits, disconnect",
+                    self.email
+                );
+                self.rate_limited(bytes);
+                self.send_frame(&Frame::Error {
+                    code: ErrorCode::RateLimited,
+                    msg: "rate limits exceeded, disconnected".to_string(),
+                });
+                ctx.notify(Refuse {
+                    deadline: Instant::now() + SHUTDOWN_DEADLINE,
+                });
+                return;
+            }
         }
 
-        if let Err(e) = self.handle_data(bytes.unwrap(), ctx) {
-            error!("{}", e);
-            self.send_stream
-                .as_mut()
-                .unwrap()
-                .send_data(e.to_string().into())
-                .expect("client send data failed");
+        if let Err(e) = self.handle_data(bytes, ctx) {
+            self.report(&e);
         }
     }
+
+    /// a conversation stream ending leaves the session running
+    fn finished(&mut self, _ctx: &mut Self::Context) {}
 }
 
 impl Handler<Transfer> for ClientSession {
-    type Result = Result<(), TransferError>;
+    type Result = AtomicResponse<Self, Result<Delivery, TransferError>>;
 
     fn handle(&mut self, msg: Transfer, _ctx: &mut Self::Context) -> Self::Result {
-        if self.email != msg.to {
-            return Err(TransferError::DestinationClientOffline);
+        // rooms, and echoes of what the email sent from another device
+        if self.email != msg.to && self.email != msg.from && msg.room().is_none() {
+            return AtomicResponse::new(Box::pin(fut::ready(Err(
+                TransferError::DestinationClientOffline,
+            ))));
+        }
+
+        let key = StreamKey::Conversation(msg.conversation(&self.email).to_string());
+        let frame = Frame::Chat(msg);
+        if self.send_streams.contains_key(&key) {
+            return AtomicResponse::new(Box::pin(fut::ready(
+                self.send_on(&key, &frame).map(|_| Delivery::Delivered),
+            )));
         }
 
-        let bytes = msg.to_bytes();
-        self.send_stream.as_mut().unwrap().send_data(bytes).unwrap();
+        // atomic, so transfers of the same conversation wait for its stream instead of opening another
+        let mut conn_handle = self.conn_handle.clone();
+        AtomicResponse::new(Box::pin(
+            async move { conn_handle.open_send_stream().await }
+                .into_actor(self)
+                .map(move |opened, act, _ctx| {
+                    let stream = opened.map_err(|e| {
+                        warn!("client: {} open stream failed: {}", act.email, e);
+                        TransferError::DestinationUnavailable
+                    })?;
+                    act.add_send_stream(key.clone(), stream);
+                    act.send_on(&key, &frame).map(|_| Delivery::Delivered)
+                }),
+        ))
+    }
+}
 
-        Ok(())
+impl Handler<Signal> for ClientSession {
+    type Result = AtomicResponse<Self, ()>;
+
+    fn handle(&mut self, msg: Signal, _ctx: &mut Self::Context) -> Self::Result {
+        let frame = Frame::Signal(msg);
+        if self.send_streams.contains_key(&StreamKey::Signals) {
+            let _ = self.send_on(&StreamKey::Signals, &frame);
+            return AtomicResponse::new(Box::pin(fut::ready(())));
+        }
+
+        let mut conn_handle = self.conn_handle.clone();
+        AtomicResponse::new(Box::pin(
+            async move { conn_handle.open_send_stream().await }
+                .into_actor(self)
+                .map(move |opened, act, _ctx| match opened {
+                    Ok(stream) => {
+                        act.add_send_stream(StreamKey::Signals, stream);
+                        let _ = act.send_on(&StreamKey::Signals, &frame);
+                    }
+                    Err(e) => warn!("client: {} open stream failed: {}", act.email, e),
+                }),
+        ))
+    }
+}
+
+impl Handler<FileOffer> for ClientSession {
+    type Result = ();
+
+    fn handle(&mut self, msg: FileOffer, _ctx: &mut Self::Context) -> Self::Result {
+        self.send_frame(&Frame::FileOffer(msg));
+    }
+}
+
+/// the presence of an email the client follows changed
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct PresenceChanged {
+    pub email: String,
+    pub presence: Presence,
+}
+
+impl Handler<PresenceChanged> for ClientSession {
+    type Result = ();
+
+    fn handle(&mut self, msg: PresenceChanged, _ctx: &mut Self::Context) -> Self::Result {
+        self.send_frame(&Frame::PresenceUpdate {
+            email: msg.email,
+            status: msg.presence,
+        });
+    }
+}
+
+/// slows an upload down to the file rate of whoever sends it
+struct Throttle {
+    limiter: SharedLimiter,
+    peers: Vec<Peer>,
+}
+
+impl Throttle {
+    async fn wait(&self, len: usize) {
+        let wait = self.limiter.lock().file_bytes(&self.peers, len);
+        if !wait.is_zero() {
+            tokio::time::sleep(wait).await;
+        }
+    }
+}
+
+/// write what arrives on `recv` to `path` from `offset`, results in the checksum once it is `size` long
+async fn write_upload(
+    path: PathBuf,
+    size: u64,
+    offset: u64,
+    leftover: Bytes,
+    mut recv: ReceiveStream,
+    throttle: Throttle,
+) -> Result<[u8; 32], UploadError> {
+    let mut file = tokio::fs::OpenOptions::new()
+        .create(true)
+        .truncate(false)
+        .write(true)
+        .open(&path)
+        .await?;
+    file.set_len(offset).await?;
+    file.seek(SeekFrom::Start(offset)).await?;
+
+    let mut written = offset;
+    let mut next = Some(leftover);
+    loop {
+        let bytes = match next.take() {
+            Some(bytes) => bytes,
+            None => match recv.receive().await {
+                Ok(Some(bytes)) => {
+                    metrics().received_bytes.inc_by(bytes.len() as u64);
+                    throttle.wait(bytes.len()).await;
+                    bytes
+                }
+                Ok(None) => break,
+                Err(e) => return Err(UploadError::Io(io::Error::other(e))),
+            },
+        };
+
+        written += bytes.len() as u64;
+        if written > size {
+            return Err(UploadError::Mismatch);
+        }
+        file.write_all(&bytes).await?;
+    }
+    file.flush().await?;
+
+    if written != size {
+        return Err(UploadError::Io(io::ErrorKind::UnexpectedEof.into()));
+    }
+
+    tokio::task::spawn_blocking(move || common::sha256_file(path))
+        .await
+        .map_err(|e| UploadError::Io(io::Error::other(e)))?
+        .map_err(UploadError::Io)
+}
+
+/// open a stream and send the `FileData` frame, then the file from `offset` on
+async fn stream_download(
+    mut conn_handle: Handle,
+    path: PathBuf,
+    id: Ulid,
+    offset: u64,
+) -> Result<(), UploadError> {
+    let stream_error = |e| UploadError::Io(io::Error::other(e));
+
+    let mut file = tokio::fs::File::open(path).await?;
+    file.seek(SeekFrom::Start(offset)).await?;
+
+    let mut stream = conn_handle
+        .open_send_stream()
+        .await
+        .map_err(|e| UploadError::Io(io::Error::other(e)))?;
+    let frame = Frame::FileData { id, offset }
+        .to_bytes()
+        .map_err(io::Error::other)?;
+    stream.send(frame).await.map_err(stream_error)?;
+
+    let mut buf = vec![0; FILE_CHUNK];
+    loop {
+        let n = file.read(&mut buf).await?;
+        if n == 0 {
+            break;
+        }
+        stream
+            .send(Bytes::copy_from_slice(&buf[..n]))
+            .await
+            .map_err(stream_error)?;
+        metrics().sent_bytes.inc_by(n as u64);
+    }
+
+    stream.finish().map_err(stream_error)?;
+    // resolves once the client acknowledged every byte
+    stream.flush().await.map_err(stream_error)
+}
+
+/// a message from the server's operators
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct Notice(pub String);
+
+impl Handler<Notice> for ClientSession {
+    type Result = ();
+
+    fn handle(&mut self, msg: Notice, _ctx: &mut Self::Context) -> Self::Result {
+        self.send_frame(&Frame::Notice { text: msg.0 });
+    }
+}
+
+/// the server goes down: tell the client, send what is buffered until `deadline`, then close
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct Shutdown {
+    pub reason: String,
+    pub deadline: Instant,
+}
+
+impl Handler<Shutdown> for ClientSession {
+    type Result = ResponseActFuture<Self, ()>;
+
+    fn handle(&mut self, msg: Shutdown, _ctx: &mut Self::Context) -> Self::Result {
+        info!("client: {} shutting down", self.email);
+        self.send_frame(&Frame::Shutdown { reason: msg.reason });
+        self.close(msg.deadline, SHUTDOWN_CLOSE_CODE)
+    }
+}
+
+/// the client broke the protocol, or the rate limits too often: send what is buffered, the error
+/// it was told of included, until `deadline`, then close with `REFUSED_CLOSE_CODE`
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct Refuse {
+    pub deadline: Instant,
+}
+
+impl Handler<Refuse> for ClientSession {
+    type Result = ResponseActFuture<Self, ()>;
+
+    fn handle(&mut self, msg: Refuse, _ctx: &mut Self::Context) -> Self::Result {
+        info!("client: {} refused", self.email);
+        self.close(msg.deadline, REFUSED_CLOSE_CODE)
     }
 }
 
@@ -1,5 +1,11 @@
+mod accounts;
 mod client_session;
+mod room_registry;
 mod server_session;
 
+pub use accounts::{Accounts, SharedUsers, ACCOUNTS_THREADS};
 pub use client_session::ClientSession;
-pub use server_session::ServerSession;
+pub use room_registry::RoomRegistry;
+pub use server_session::{
+    Ban, Broadcast, ClientInfo, Kick, ListClients, Reload, ServerSession, Unban,
+};
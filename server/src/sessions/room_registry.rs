@@ -0,0 +1,71 @@
+use std::collections::{BTreeSet, HashMap};
+
+use actix::prelude::*;
+use log::info;
+
+use common::*;
+
+/// room memberships, by email so they survive members reconnecting
+#[derive(Default)]
+pub struct RoomRegistry {
+    rooms: HashMap<String, BTreeSet<String>>,
+}
+
+impl RoomRegistry {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn members(&self, room: &str) -> Vec<String> {
+        self.rooms
+            .get(room)
+            .map(|members| members.iter().cloned().collect())
+            .unwrap_or_default()
+    }
+}
+
+impl Actor for RoomRegistry {
+    type Context = Context<Self>;
+
+    fn started(&mut self, _ctx: &mut Self::Context) {
+        info!("room registry started");
+    }
+}
+
+impl Handler<JoinRoom> for RoomRegistry {
+    type Result = MessageResult<JoinRoom>;
+
+    fn handle(&mut self, msg: JoinRoom, _ctx: &mut Self::Context) -> Self::Result {
+        info!("client: {} join room {}", msg.email, msg.room);
+        self.rooms
+            .entry(msg.room.clone())
+            .or_default()
+            .insert(msg.email);
+
+        MessageResult(self.members(&msg.room))
+    }
+}
+
+impl Handler<LeaveRoom> for RoomRegistry {
+    type Result = MessageResult<LeaveRoom>;
+
+    fn handle(&mut self, msg: LeaveRoom, _ctx: &mut Self::Context) -> Self::Result {
+        info!("client: {} leave room {}", msg.email, msg.room);
+        if let Some(members) = self.rooms.get_mut(&msg.room) {
+            members.remove(&msg.email);
+            if members.is_empty() {
+                self.rooms.remove(&msg.room);
+            }
+        }
+
+        MessageResult(self.members(&msg.room))
+    }
+}
+
+impl Handler<ListMembers> for RoomRegistry {
+    type Result = MessageResult<ListMembers>;
+
+    fn handle(&mut self, msg: ListMembers, _ctx: &mut Self::Context) -> Self::Result {
+        MessageResult(self.members(&msg.room))
+    }
+}
@@ -1,24 +1,394 @@
 use actix::prelude::*;
+use argon2::password_hash::rand_core::{OsRng, RngCore};
 use async_stream::stream;
-use log::info;
+use log::{error, info, warn};
 use s2n_quic::Connection;
-use std::{collections::HashMap, sync::Arc};
-use tokio::sync::Mutex;
+use std::{
+    collections::{HashMap, HashSet},
+    net::SocketAddr,
+    path::PathBuf,
+    sync::Arc,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+use tokio::sync::{oneshot, Mutex};
+use ulid::Ulid;
 
-use crate::sessions::ClientSession;
-use common::*;
+use super::{
+    accounts::Registered,
+    client_session::{Notice, PresenceChanged, SessionPolicy, Shutdown},
+};
+use crate::{
+    config::{Limits, Overflow, Policy},
+    metrics::{metrics, Queued},
+    rate_limit::{Peer, SharedLimiter, Verdict},
+    sessions::{Accounts, ClientSession, RoomRegistry},
+    store::{HistoryStore, KeyStore, MessageStore, StoreError, Stores, UploadError, Uploads},
+};
+use common::{
+    crypto::{self, KeyBundle},
+    protocol::{REFUSED_CLOSE_CODE, SHUTDOWN_CLOSE_CODE},
+    *,
+};
+
+/// how long a resume token stays valid after it was issued
+pub const RESUME_TOKEN_TTL: Duration = Duration::from_secs(60 * 60);
+
+/// how often the rate limiter forgets peers gone quiet
+const LIMITER_PRUNE_INTERVAL: Duration = Duration::from_secs(60);
+
+/// how often uploads not written to for their expiry are removed
+const UPLOAD_EXPIRE_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+/// how long flushing queued transfers waits for a session whose queue is full
+const FLUSH_RETRY: Duration = Duration::from_millis(100);
+
+/// how long clients get to receive what is still buffered for them when the server stops
+pub const SHUTDOWN_DEADLINE: Duration = Duration::from_secs(5);
 
 pub struct ServerSession {
     quic_server: Arc<Mutex<s2n_quic::Server>>,
-    clients: HashMap<String, Addr<ClientSession>>,
+    /// every device session logged in as an email
+    clients: HashMap<String, Vec<Addr<ClientSession>>>,
+    /// sessions not logged in yet, by their temporary id
+    connecting: HashMap<String, Addr<ClientSession>>,
+    /// where and when every session, logged in or not, connected from
+    connections: HashMap<Addr<ClientSession>, Connected>,
+    /// devices whose user stepped away
+    away: HashSet<Addr<ClientSession>>,
+    /// sessions following the presence of an email
+    watchers: HashMap<String, HashSet<Addr<ClientSession>>>,
+    store: Box<dyn MessageStore>,
+    history: Box<dyn HistoryStore>,
+    /// the key directory, see `common::crypto`
+    keys: Box<dyn KeyStore>,
+    uploads: Uploads,
+    accounts: Addr<Accounts>,
+    rooms: Addr<RoomRegistry>,
+    /// email a token resumes, the session it was issued to and when it expires,
+    /// every token is used at most once
+    resume_tokens: HashMap<String, (String, Addr<ClientSession>, Instant)>,
+    limits: Limits,
+    /// emails refused to log in, as configured
+    bans: HashSet<String>,
+    /// banned over the admin interface, kept across reloads
+    admin_bans: HashSet<String>,
+    /// shared with every session, see `rate_limit`
+    limiter: SharedLimiter,
+    password_login: bool,
+    /// the stream of incoming connections, cancelled when shutting down
+    incoming: Option<SpawnHandle>,
+    shutting_down: bool,
+    /// resolves `RunningServer` once stopped
+    stopped: Option<oneshot::Sender<()>>,
 }
 
 impl ServerSession {
-    pub fn new(quic_server: s2n_quic::Server) -> Self {
+    pub fn new(
+        quic_server: s2n_quic::Server,
+        stores: Stores,
+        uploads: Uploads,
+        accounts: Addr<Accounts>,
+        rooms: Addr<RoomRegistry>,
+        policy: Policy,
+        stopped: oneshot::Sender<()>,
+    ) -> Self {
         info!("new server session");
         Self {
             quic_server: Arc::new(Mutex::new(quic_server)),
             clients: HashMap::new(),
+            connecting: HashMap::new(),
+            connections: HashMap::new(),
+            away: HashSet::new(),
+            watchers: HashMap::new(),
+            store: stores.messages,
+            history: stores.history,
+            keys: stores.keys,
+            uploads,
+            accounts,
+            rooms,
+            resume_tokens: HashMap::new(),
+            limits: policy.limits,
+            bans: policy.bans,
+            admin_bans: HashSet::new(),
+            limiter: SharedLimiter::new(policy.rate_limit),
+            password_login: policy.password_login,
+            incoming: None,
+            shutting_down: false,
+            stopped: Some(stopped),
+        }
+    }
+
+    fn banned(&self, email: &str) -> bool {
+        self.bans.contains(email)
+            || self.admin_bans.contains(email)
+            || self.limiter.lock().banned(&Peer::Email(email.to_string()))
+    }
+
+    /// whether the address `connection` comes from may connect now
+    fn admit(&self, connection: &Connection) -> bool {
+        let Ok(remote) = connection.remote_addr() else {
+            return true;
+        };
+        let address = remote.ip();
+
+        let mut limiter = self.limiter.lock();
+        if limiter.banned(&Peer::Address(address)) {
+            warn!(
+                "{} is banned for exceeding the rate limits, refused",
+                address
+            );
+            return false;
+        }
+        match limiter.connection(address) {
+            Verdict::Allowed => true,
+            _ => {
+                warn!("{} connects too often, refused", address);
+                false
+            }
+        }
+    }
+
+    /// shut down every session of `email`, results in how many there were
+    fn kick(&mut self, email: &str, reason: &str) -> usize {
+        self.drop_tokens(email);
+        let deadline = Instant::now() + SHUTDOWN_DEADLINE;
+        let devices = self.clients.get(email).map_or(&[][..], |devices| devices);
+        for device in devices {
+            device.do_send(Shutdown {
+                reason: reason.to_string(),
+                deadline,
+            });
+        }
+        devices.len()
+    }
+
+    /// `device` of `email` did not take a transfer, its queue was full
+    fn overflowed(&self, email: &str, device: &Addr<ClientSession>) {
+        warn!(
+            "client: {} not keeping up, overflow: {:?}",
+            email, self.limits.overflow
+        );
+        if self.limits.overflow == Overflow::Disconnect {
+            device.do_send(Shutdown {
+                reason: "not keeping up with what is sent to you".to_string(),
+                deadline: Instant::now() + SHUTDOWN_DEADLINE,
+            });
+        }
+    }
+
+    fn register(&mut self, email: &str, session: Addr<ClientSession>) {
+        self.clients
+            .entry(email.to_string())
+            .or_default()
+            .push(session);
+    }
+
+    /// whether `session` was registered as `email`
+    fn unregister(&mut self, email: &str, session: &Addr<ClientSession>) -> bool {
+        let Some(devices) = self.clients.get_mut(email) else {
+            return false;
+        };
+        let before = devices.len();
+        devices.retain(|device| device != session);
+        let removed = devices.len() != before;
+
+        if devices.is_empty() {
+            self.clients.remove(email);
+        }
+        if removed {
+            self.away.remove(session);
+            self.unwatch(session);
+        }
+        removed
+    }
+
+    fn presence(&self, email: &str) -> Presence {
+        match self.clients.get(email) {
+            None => Presence::Offline,
+            Some(devices) if devices.iter().all(|device| self.away.contains(device)) => {
+                Presence::Away
+            }
+            Some(_) => Presence::Online,
+        }
+    }
+
+    /// tell the sessions following `email` if its presence is no longer `before`
+    fn announce(&self, email: &str, before: Presence) {
+        let presence = self.presence(email);
+        if presence == before {
+            return;
+        }
+
+        info!("client: {} is {}", email, presence);
+        for watcher in self.watchers.get(email).into_iter().flatten() {
+            watcher.do_send(PresenceChanged {
+                email: email.to_string(),
+                presence,
+            });
+        }
+    }
+
+    /// `session` no longer follows anyone
+    fn unwatch(&mut self, session: &Addr<ClientSession>) {
+        self.watchers.retain(|_, watchers| {
+            watchers.remove(session);
+            !watchers.is_empty()
+        });
+    }
+
+    /// no session resumes as `email` with a token issued before
+    fn drop_tokens(&mut self, email: &str) {
+        self.resume_tokens
+            .retain(|_, (token_email, _, _)| token_email != email);
+    }
+
+    /// a fresh token resuming `email` on the device `session` is for
+    fn issue_token(&mut self, email: &str, session: Addr<ClientSession>) -> String {
+        let now = Instant::now();
+        self.resume_tokens
+            .retain(|_, (_, _, expires)| *expires > now);
+
+        let mut bytes = [0u8; 32];
+        OsRng.fill_bytes(&mut bytes);
+        let token = bytes
+            .iter()
+            .map(|b| format!("{:02x}", b))
+            .collect::<String>();
+
+        self.resume_tokens.insert(
+            token.clone(),
+            (email.to_string(), session, now + RESUME_TOKEN_TTL),
+        );
+        token
+    }
+
+    fn record(&mut self, msg: &Transfer) {
+        let at = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_millis() as u64)
+            .unwrap_or_default();
+
+        if let Err(e) = self.history.record(HistoryEntry {
+            at,
+            transfer: msg.clone(),
+        }) {
+            error!("record transfer {} failed: {}", msg.id, e);
+        }
+    }
+
+    /// keep the transfer until `recipient` logs in
+    fn queue(&mut self, recipient: &str, msg: Transfer) -> Result<Delivery, TransferError> {
+        if self.store.queued(recipient) >= self.limits.max_queued {
+            warn!(
+                "client: {} offline with {} queued, transfer {} refused",
+                recipient, self.limits.max_queued, msg.id
+            );
+            return Err(TransferError::QueueFull);
+        }
+        info!("client: {} offline, queue transfer {}", recipient, msg.id);
+        metrics().transfers_offline.inc();
+        self.store.push(recipient, msg).map_err(|e| {
+            error!("{}", e);
+            TransferError::StoreFailed
+        })?;
+
+        Ok(Delivery::Queued)
+    }
+
+    /// send everything queued for `email` to its newly logged in session
+    fn flush_queued(&mut self, email: &str, client: Addr<ClientSession>, ctx: &mut Context<Self>) {
+        let queued = match self.store.take(email) {
+            Ok(queued) => queued,
+            Err(e) => {
+                error!("{}", e);
+                return;
+            }
+        };
+
+        info!("client: {} flush {} queued transfers", email, queued.len());
+        let email = email.to_string();
+        // one after another, waiting while the session's queue is full, so the backlog
+        // arrives in order and is not queued again just for being large
+        let flushing = async move {
+            let mut transfers = queued.into_iter();
+            while let Some(transfer) = transfers.next() {
+                loop {
+                    let _queued = Queued::new("client_session");
+                    match client.send(transfer.clone()).await {
+                        Ok(Ok(_)) => break,
+                        Ok(Err(TransferError::DestinationBusy)) => {
+                            tokio::time::sleep(FLUSH_RETRY).await
+                        }
+                        _ => return Some(std::iter::once(transfer).chain(transfers).collect()),
+                    }
+                }
+            }
+            None
+        };
+
+        flushing
+            .into_actor(self)
+            .map(move |left: Option<Vec<Transfer>>, act, _ctx| {
+                let Some(left) = left else {
+                    return;
+                };
+                warn!("client: {} flush failed, queue {} again", email, left.len());
+                for transfer in left {
+                    let _ = act.queue(&email, transfer);
+                }
+            })
+            .spawn(ctx);
+    }
+
+    /// hand the transfer to every device `recipient` is logged in on, queue it if none took it,
+    /// devices not keeping up are dealt with as `Limits::overflow` says
+    fn deliver(
+        &mut self,
+        recipient: String,
+        msg: Transfer,
+    ) -> ResponseActFuture<Self, Result<Delivery, TransferError>> {
+        let devices = self.clients.get(&recipient).cloned().unwrap_or_default();
+        if devices.is_empty() {
+            return Box::pin(fut::ready(self.queue(&recipient, msg)));
+        }
+
+        let sending = devices
+            .iter()
+            .map(|device| device.send(msg.clone()))
+            .collect::<Vec<_>>();
+        let queued = devices
+            .iter()
+            .map(|_| Queued::new("client_session"))
+            .collect::<Vec<_>>();
+
+        Box::pin(futures::future::join_all(sending).into_actor(self).map(
+            move |results, act, _ctx| {
+                drop(queued);
+                let mut overflowed = false;
+                for (device, res) in devices.iter().zip(&results) {
+                    if matches!(res, Ok(Err(TransferError::DestinationBusy))) {
+                        act.overflowed(&recipient, device);
+                        overflowed = true;
+                    }
+                }
+
+                if results.iter().any(|res| matches!(res, Ok(Ok(_)))) {
+                    Ok(Delivery::Delivered)
+                } else if overflowed && act.limits.overflow == Overflow::Drop {
+                    Err(TransferError::DestinationBusy)
+                } else {
+                    // the sessions went away meanwhile, or do not keep up
+                    act.queue(&recipient, msg)
+                }
+            },
+        ))
+    }
+
+    /// send a room transfer to every other member, queueing it for those offline
+    fn broadcast(&mut self, members: Vec<String>, msg: Transfer, ctx: &mut Context<Self>) {
+        for member in members.into_iter().filter(|m| *m != msg.from) {
+            let delivering = self.deliver(member, msg.clone());
+            ctx.spawn(delivering.map(|_, _, _| ()));
         }
     }
 }
@@ -38,79 +408,1129 @@ impl Actor for ServerSession {
             }
         };
         info!("listening incoming connections");
-        ctx.add_stream(incoming);
+        self.incoming = Some(ctx.add_stream(incoming));
+
+        ctx.run_interval(LIMITER_PRUNE_INTERVAL, |act, _ctx| {
+            act.limiter.lock().prune()
+        });
+        ctx.run_interval(UPLOAD_EXPIRE_INTERVAL, |act, _ctx| {
+            act.uploads.expire();
+        });
     }
 
     fn stopping(&mut self, _ctx: &mut Self::Context) -> Running {
         Running::Stop
     }
+
+    fn stopped(&mut self, _ctx: &mut Self::Context) {
+        info!("server session stopped");
+        if let Some(stopped) = self.stopped.take() {
+            let _ = stopped.send(());
+        }
+    }
 }
 
 impl StreamHandler<Option<Connection>> for ServerSession {
     fn handle(&mut self, item: Option<Connection>, ctx: &mut Self::Context) {
         info!("handle received connection");
 
-        if item.is_none() {
+        let Some(connection) = item else {
             info!("none connection, server stop");
             ctx.stop();
+            return;
+        };
+        if self.shutting_down {
+            connection.close(SHUTDOWN_CLOSE_CODE.into());
+            return;
+        }
+        if !self.admit(&connection) {
+            connection.close(REFUSED_CLOSE_CODE.into());
+            return;
         }
 
         info!("generate client session");
-        let connection = item.unwrap();
         let tempoparily_id = ulid::Ulid::new().to_string();
+        let connected = Connected {
+            remote: connection.remote_addr().ok(),
+            since: SystemTime::now(),
+        };
 
-        let client_addr =
-            ClientSession::new(connection, tempoparily_id.clone(), ctx.address()).start();
+        let client_addr = ClientSession::new(
+            connection,
+            tempoparily_id.clone(),
+            ctx.address(),
+            self.accounts.clone(),
+            self.rooms.clone(),
+            self.limiter.clone(),
+            SessionPolicy {
+                password_login: self.password_login,
+                outbound_queue: self.limits.outbound_queue,
+            },
+        )
+        .start();
 
         info!("temporarily client id is: {}", tempoparily_id);
-        self.clients.insert(tempoparily_id, client_addr);
+        self.connections.insert(client_addr.clone(), connected);
+        metrics().connections.set(self.connections.len() as i64);
+        self.connecting.insert(tempoparily_id, client_addr);
     }
 }
 
 impl Handler<ClientChange> for ServerSession {
-    type Result = Result<(), ClientChangeError>;
+    type Result = Result<SessionIdentity, ClientChangeError>;
 
-    fn handle(&mut self, msg: ClientChange, _ctx: &mut Self::Context) -> Self::Result {
-        match msg {
+    fn handle(&mut self, msg: ClientChange, ctx: &mut Self::Context) -> Self::Result {
+        let (old_email, email, stale) = match msg {
             ClientChange::UpdateEmail(old_email, new_email) => {
                 info!(
                     "server change client email, old: {}, new: {}",
                     old_email, new_email
                 );
-                if self.clients.contains_key(&new_email) {
-                    return Err(ClientChangeError::NewEmailAlreadyExisted);
-                }
-                if let Some(client_session) = self.clients.remove(&old_email) {
-                    self.clients.insert(new_email, client_session);
-                }
+                (old_email, new_email, None)
+            }
+            ClientChange::Resume(old_email, token) => {
+                let (email, stale) = match self.resume_tokens.remove(&token) {
+                    Some((email, stale, expires)) if expires > Instant::now() => (email, stale),
+                    _ => return Err(ClientChangeError::InvalidResumeToken),
+                };
+                info!("client: {} resumed as {}", old_email, email);
+                (old_email, email, Some(stale))
+            }
+        };
+
+        if self.banned(&email) {
+            warn!("client: {} is banned, refused", email);
+            return Err(ClientChangeError::Banned);
+        }
+
+        // a session only ever changes away from its temporary id
+        let session = self
+            .connecting
+            .remove(&old_email)
+            .ok_or(ClientChangeError::UnknownSession)?;
+
+        let before = self.presence(&email);
+        // the lost connection may not have timed out yet
+        if let Some(stale) = stale {
+            if self.unregister(&email, &stale) {
+                stale.do_send(Stop);
             }
         }
+        self.register(&email, session.clone());
+        self.announce(&email, before);
+
+        self.flush_queued(&email, session.clone(), ctx);
+        for offer in self.uploads.pending(&email) {
+            session.do_send(offer);
+        }
+
+        let token = self.issue_token(&email, session);
+        Ok(SessionIdentity { email, token })
+    }
+}
+
+/// a `ClientSession` stopped, `session` tells it from the other devices of the email
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct Disconnected {
+    pub email: String,
+    pub session: Addr<ClientSession>,
+}
+
+impl Handler<Disconnected> for ServerSession {
+    type Result = ();
+
+    fn handle(&mut self, msg: Disconnected, _ctx: &mut Self::Context) -> Self::Result {
+        self.connections.remove(&msg.session);
+        metrics().connections.set(self.connections.len() as i64);
+        if self.connecting.get(&msg.email) == Some(&msg.session) {
+            self.connecting.remove(&msg.email);
+            return;
+        }
+
+        let before = self.presence(&msg.email);
+        if self.unregister(&msg.email, &msg.session) {
+            info!("client: {} disconnected", msg.email);
+            self.announce(&msg.email, before);
+        }
+    }
+}
 
-        Ok(())
+/// `email` logged out, the tokens issued to it resume nothing anymore
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct LoggedOut {
+    pub email: String,
+}
+
+impl Handler<LoggedOut> for ServerSession {
+    type Result = ();
+
+    fn handle(&mut self, msg: LoggedOut, _ctx: &mut Self::Context) -> Self::Result {
+        self.drop_tokens(&msg.email);
+    }
+}
+
+/// a transfer `origin` sent, for the sender's other devices to show it too
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct Echo {
+    pub transfer: Transfer,
+    pub origin: Addr<ClientSession>,
+}
+
+impl Handler<Echo> for ServerSession {
+    type Result = ();
+
+    fn handle(&mut self, msg: Echo, ctx: &mut Self::Context) -> Self::Result {
+        let Some(devices) = self.clients.get(&msg.transfer.from) else {
+            return;
+        };
+
+        // the sending device has it, an echo not taken is never queued
+        for device in devices.iter().filter(|device| **device != msg.origin) {
+            let device = device.clone();
+            let email = msg.transfer.from.clone();
+            device
+                .send(msg.transfer.clone())
+                .into_actor(self)
+                .map(move |res, act, _ctx| {
+                    if matches!(res, Ok(Err(TransferError::DestinationBusy))) {
+                        act.overflowed(&email, &device);
+                    }
+                })
+                .spawn(ctx);
+        }
     }
 }
 
 impl Handler<Transfer> for ServerSession {
-    type Result = Result<(), TransferError>;
+    type Result = ResponseActFuture<Self, Result<Delivery, TransferError>>;
 
     fn handle(&mut self, msg: Transfer, _ctx: &mut Self::Context) -> Self::Result {
-        let des = self.clients.get_mut(&msg.to);
-        if des.is_none() {
-            return Err(TransferError::DestinationClientOffline);
+        metrics().transfers_routed.inc();
+        if let Some(room) = msg.room() {
+            let room = room.to_string();
+            return Box::pin(self.rooms.send(ListMembers { room }).into_actor(self).map(
+                move |res, act, ctx| {
+                    let members = res.map_err(|e| {
+                        error!("{}", e);
+                        TransferError::DestinationUnavailable
+                    })?;
+                    if !members.contains(&msg.from) {
+                        return Err(TransferError::NotRoomMember);
+                    }
+
+                    act.record(&msg);
+                    act.broadcast(members, msg, ctx);
+                    Ok(Delivery::Delivered)
+                },
+            ));
+        }
+
+        let to = msg.to.clone();
+        if self.clients.contains_key(&to) {
+            self.record(&msg);
+            return self.deliver(to, msg);
         }
 
-        let des = des.unwrap();
+        // nothing is recorded nor queued for an email without an account
+        let registered = self.accounts.send(Registered { email: to.clone() });
+        Box::pin(
+            registered
+                .into_actor(self)
+                .then(move |res, act, _ctx| match res {
+                    Ok(Ok(true)) => {
+                        act.record(&msg);
+                        act.deliver(to, msg)
+                    }
+                    Ok(Ok(false)) => {
+                        info!("transfer {} to {} refused, no such account", msg.id, to);
+                        Box::pin(fut::ready(Err(TransferError::UnknownRecipient)))
+                    }
+                    res => {
+                        error!("look up the account of {} failed: {:?}", to, res);
+                        Box::pin(fut::ready(Err(TransferError::DestinationUnavailable)))
+                    }
+                }),
+        )
+    }
+}
 
-        des.do_send(msg);
+impl Handler<Signal> for ServerSession {
+    type Result = ();
 
-        Ok(())
+    fn handle(&mut self, msg: Signal, ctx: &mut Self::Context) -> Self::Result {
+        let Some(room) = msg.room() else {
+            for device in self.clients.get(&msg.to).into_iter().flatten() {
+                device.do_send(msg.clone());
+            }
+            return;
+        };
+
+        let room = room.to_string();
+        self.rooms
+            .send(ListMembers { room })
+            .into_actor(self)
+            .map(move |res, act, _ctx| {
+                let members = res.unwrap_or_default();
+                if !members.contains(&msg.from) {
+                    return;
+                }
+
+                for device in members
+                    .iter()
+                    .filter(|member| **member != msg.from)
+                    .filter_map(|member| act.clients.get(member))
+                    .flatten()
+                {
+                    device.do_send(msg.clone());
+                }
+            })
+            .spawn(ctx);
     }
 }
 
-impl Handler<Stop> for ServerSession {
+/// a client offers a file, results in the offset to upload it from
+#[derive(Message)]
+#[rtype(result = "Result<u64, UploadError>")]
+pub struct OfferFile(pub FileOffer);
+
+impl Handler<OfferFile> for ServerSession {
+    type Result = ResponseActFuture<Self, Result<u64, UploadError>>;
+
+    fn handle(&mut self, msg: OfferFile, _ctx: &mut Self::Context) -> Self::Result {
+        let offer = msg.0;
+        info!(
+            "client: {} offers {} ({} bytes) to {}",
+            offer.from, offer.id, offer.size, offer.to
+        );
+        if !is_room(&offer.to) {
+            return Box::pin(fut::ready(self.uploads.offer(offer)));
+        }
+
+        // only members may send a file to a room
+        let room = offer.to.clone();
+        Box::pin(self.rooms.send(ListMembers { room }).into_actor(self).map(
+            move |res, act, _ctx| match res {
+                Ok(members) if members.contains(&offer.from) => act.uploads.offer(offer),
+                _ => Err(UploadError::NotRoomMember),
+            },
+        ))
+    }
+}
+
+/// `from` starts streaming the file `id` from `offset`,
+/// results in the file to write and the size it must end at
+#[derive(Message)]
+#[rtype(result = "Result<(PathBuf, u64), UploadError>")]
+pub struct StartUpload {
+    pub id: Ulid,
+    pub from: String,
+    pub offset: u64,
+}
+
+impl Handler<StartUpload> for ServerSession {
+    type Result = Result<(PathBuf, u64), UploadError>;
+
+    fn handle(&mut self, msg: StartUpload, _ctx: &mut Self::Context) -> Self::Result {
+        self.uploads.upload(msg.id, &msg.from, msg.offset)
+    }
+}
+
+/// the file `id` was uploaded whole with the checksum `sha256`, offer it to the recipients
+#[derive(Message)]
+#[rtype(result = "Result<Delivery, UploadError>")]
+pub struct FinishUpload {
+    pub id: Ulid,
+    pub sha256: [u8; 32],
+}
+
+impl Handler<FinishUpload> for ServerSession {
+    type Result = ResponseActFuture<Self, Result<Delivery, UploadError>>;
+
+    fn handle(&mut self, msg: FinishUpload, _ctx: &mut Self::Context) -> Self::Result {
+        let Some(offer) = self.uploads.offer_of(msg.id) else {
+            return Box::pin(fut::ready(Err(UploadError::Unknown)));
+        };
+
+        let recipients: ResponseActFuture<Self, Vec<String>> = match offer.to.clone() {
+            room if is_room(&room) => Box::pin(
+                self.rooms
+                    .send(ListMembers { room })
+                    .into_actor(self)
+                    .map(|members, _act, _ctx| members.unwrap_or_default()),
+            ),
+            to => Box::pin(fut::ready(vec![to])),
+        };
+
+        Box::pin(recipients.map(move |recipients, act, _ctx| {
+            // the sender left the room while uploading
+            if is_room(&offer.to) && !recipients.contains(&offer.from) {
+                act.uploads.cancel(msg.id)?;
+                return Err(UploadError::NotRoomMember);
+            }
+            let recipients = recipients
+                .into_iter()
+                .filter(|r| *r != offer.from)
+                .collect::<Vec<_>>();
+            let offer = act
+                .uploads
+                .complete(msg.id, msg.sha256, recipients.clone())?;
+
+            let mut delivery = Delivery::Queued;
+            for device in recipients
+                .iter()
+                .filter_map(|r| act.clients.get(r))
+                .flatten()
+            {
+                device.do_send(offer.clone());
+                delivery = Delivery::Delivered;
+            }
+            Ok(delivery)
+        }))
+    }
+}
+
+/// `email` wants the file `id`, results in the file to stream and its offer
+#[derive(Message)]
+#[rtype(result = "Result<(PathBuf, FileOffer), UploadError>")]
+pub struct StartDownload {
+    pub id: Ulid,
+    pub email: String,
+}
+
+impl Handler<StartDownload> for ServerSession {
+    type Result = Result<(PathBuf, FileOffer), UploadError>;
+
+    fn handle(&mut self, msg: StartDownload, _ctx: &mut Self::Context) -> Self::Result {
+        self.uploads.download(msg.id, &msg.email)
+    }
+}
+
+/// `email` received the whole file `id`
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct Downloaded {
+    pub id: Ulid,
+    pub email: String,
+}
+
+impl Handler<Downloaded> for ServerSession {
     type Result = ();
 
-    fn handle(&mut self, _msg: Stop, _ctx: &mut Self::Context) -> Self::Result {
-        todo!()
+    fn handle(&mut self, msg: Downloaded, _ctx: &mut Self::Context) -> Self::Result {
+        info!("client: {} downloaded {}", msg.email, msg.id);
+        self.uploads.downloaded(msg.id, &msg.email);
+    }
+}
+
+/// the user of `session` is around or not
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct SetPresence {
+    pub email: String,
+    pub session: Addr<ClientSession>,
+    pub away: bool,
+}
+
+impl Handler<SetPresence> for ServerSession {
+    type Result = ();
+
+    fn handle(&mut self, msg: SetPresence, _ctx: &mut Self::Context) -> Self::Result {
+        let before = self.presence(&msg.email);
+        if msg.away {
+            self.away.insert(msg.session);
+        } else {
+            self.away.remove(&msg.session);
+        }
+        self.announce(&msg.email, before);
+    }
+}
+
+/// `session` follows the presence of `contacts` from now on, instead of whoever it followed,
+/// results in their presence now
+#[derive(Message)]
+#[rtype(result = "Vec<(String, Presence)>")]
+pub struct SubscribePresence {
+    pub session: Addr<ClientSession>,
+    pub contacts: Vec<String>,
+}
+
+impl Handler<SubscribePresence> for ServerSession {
+    type Result = MessageResult<SubscribePresence>;
+
+    fn handle(&mut self, msg: SubscribePresence, _ctx: &mut Self::Context) -> Self::Result {
+        self.unwatch(&msg.session);
+        for contact in &msg.contacts {
+            self.watchers
+                .entry(contact.clone())
+                .or_default()
+                .insert(msg.session.clone());
+        }
+
+        MessageResult(
+            msg.contacts
+                .into_iter()
+                .map(|contact| {
+                    let presence = self.presence(&contact);
+                    (contact, presence)
+                })
+                .collect(),
+        )
+    }
+}
+
+/// everyone logged in and their presence, sorted by email
+#[derive(Message)]
+#[rtype(result = "Vec<(String, Presence)>")]
+pub struct WhoOnline;
+
+impl Handler<WhoOnline> for ServerSession {
+    type Result = MessageResult<WhoOnline>;
+
+    fn handle(&mut self, _msg: WhoOnline, _ctx: &mut Self::Context) -> Self::Result {
+        let mut online = self
+            .clients
+            .keys()
+            .map(|email| (email.clone(), self.presence(email)))
+            .collect::<Vec<_>>();
+        online.sort_by(|a, b| a.0.cmp(&b.0));
+
+        MessageResult(online)
+    }
+}
+
+/// a device of `email` is sealed to with the keys of `bundle` from now on
+#[derive(Message)]
+#[rtype(result = "Result<(), StoreError>")]
+pub struct PublishKeys {
+    pub email: String,
+    pub bundle: KeyBundle,
+}
+
+impl Handler<PublishKeys> for ServerSession {
+    type Result = Result<(), StoreError>;
+
+    fn handle(&mut self, msg: PublishKeys, _ctx: &mut Self::Context) -> Self::Result {
+        info!(
+            "client: {} published identity {}",
+            msg.email,
+            crypto::fingerprint(&msg.bundle.identity)
+        );
+        self.keys.publish(msg.email, msg.bundle)
+    }
+}
+
+/// the key bundles of every device of `email`
+#[derive(Message)]
+#[rtype(result = "Vec<KeyBundle>")]
+pub struct LookupKeys {
+    pub email: String,
+}
+
+impl Handler<LookupKeys> for ServerSession {
+    type Result = MessageResult<LookupKeys>;
+
+    fn handle(&mut self, msg: LookupKeys, _ctx: &mut Self::Context) -> Self::Result {
+        MessageResult(self.keys.bundles(&msg.email))
+    }
+}
+
+impl Handler<HistoryQuery> for ServerSession {
+    type Result = ResponseActFuture<Self, Vec<HistoryEntry>>;
+
+    fn handle(&mut self, mut msg: HistoryQuery, _ctx: &mut Self::Context) -> Self::Result {
+        msg.limit = msg.limit.min(self.limits.max_history);
+        if !is_room(&msg.peer) {
+            let entries = self
+                .history
+                .history(&msg.of, &msg.peer, msg.before, msg.limit);
+            return Box::pin(fut::ready(fit_in_frame(entries)));
+        }
+
+        // only members may read what was said in a room
+        let room = msg.peer.clone();
+        Box::pin(self.rooms.send(ListMembers { room }).into_actor(self).map(
+            move |res, act, _ctx| {
+                match res {
+                    Ok(members) if members.contains(&msg.of) => fit_in_frame(
+                        act.history
+                            .history(&msg.of, &msg.peer, msg.before, msg.limit),
+                    ),
+                    _ => vec![],
+                }
+            },
+        ))
+    }
+}
+
+/// the newest of `entries` a `Frame::History` can carry without its payload growing past
+/// `MAX_FRAME_LEN`, an entry too large to ever fit is left out, it stays in the history store
+fn fit_in_frame(entries: Vec<HistoryEntry>) -> Vec<HistoryEntry> {
+    // the tag and the id, the header is not bounded by `MAX_FRAME_LEN`
+    const FRAME: usize = 4 + 1 + 4 + 16;
+    // the time and the nested chat frame up to its content
+    const ENTRY: usize = 4 + 8 + 4 + codec::HEADER_LEN + 4 * 5 + 1 + 16;
+
+    let entry_len = |entry: &HistoryEntry| {
+        let transfer = &entry.transfer;
+        ENTRY + transfer.from.len() + transfer.to.len() + transfer.content.len()
+    };
+
+    let mut len = FRAME;
+    let mut fitting: Vec<HistoryEntry> = entries
+        .into_iter()
+        .rev()
+        .filter(|entry| FRAME + entry_len(entry) <= codec::MAX_FRAME_LEN)
+        .take_while(|entry| {
+            len += entry_len(entry);
+            len <= codec::MAX_FRAME_LEN
+        })
+        .collect();
+    fitting.reverse();
+
+    fitting
+}
+
+/// settings read again, see `config::Policy`
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct Reload(pub Policy);
+
+impl Handler<Reload> for ServerSession {
+    type Result = ();
+
+    fn handle(&mut self, msg: Reload, _ctx: &mut Self::Context) -> Self::Result {
+        let Policy {
+            limits,
+            rate_limit,
+            bans,
+            ..
+        } = msg.0;
+        self.limiter.lock().set_limits(rate_limit);
+        if limits != self.limits {
+            info!("limits changed: {:?}", limits);
+            self.uploads.set_limits(limits.uploads());
+            self.limits = limits;
+        }
+
+        let banned: Vec<String> = bans.difference(&self.bans).cloned().collect();
+        for email in banned {
+            info!("client: {} banned", email);
+            self.kick(&email, "banned");
+        }
+        for email in self.bans.difference(&bans) {
+            info!("client: {} no longer banned", email);
+        }
+        self.bans = bans;
+    }
+}
+
+/// where and when a session connected from
+#[derive(Debug, Clone)]
+pub struct Connected {
+    pub remote: Option<SocketAddr>,
+    pub since: SystemTime,
+}
+
+/// a connected session as the admin interface lists it
+#[derive(Debug)]
+pub struct ClientInfo {
+    /// `None` while logging in
+    pub email: Option<String>,
+    pub connected: Connected,
+}
+
+/// every connected session, logged in ones first
+#[derive(Message)]
+#[rtype(result = "Vec<ClientInfo>")]
+pub struct ListClients;
+
+impl Handler<ListClients> for ServerSession {
+    type Result = MessageResult<ListClients>;
+
+    fn handle(&mut self, _msg: ListClients, _ctx: &mut Self::Context) -> Self::Result {
+        let logged_in = self.clients.iter().flat_map(|(email, devices)| {
+            devices
+                .iter()
+                .map(move |device| (Some(email.clone()), device))
+        });
+        let connecting = self.connecting.values().map(|session| (None, session));
+
+        let clients = logged_in
+            .chain(connecting)
+            .filter_map(|(email, session)| {
+                let connected = self.connections.get(session)?.clone();
+                Some(ClientInfo { email, connected })
+            })
+            .collect();
+        MessageResult(clients)
+    }
+}
+
+/// disconnect every session of `email`, results in how many there were
+#[derive(Message)]
+#[rtype(result = "usize")]
+pub struct Kick {
+    pub email: String,
+}
+
+impl Handler<Kick> for ServerSession {
+    type Result = usize;
+
+    fn handle(&mut self, msg: Kick, _ctx: &mut Self::Context) -> Self::Result {
+        info!("client: {} kicked", msg.email);
+        self.kick(&msg.email, "kicked by an admin")
+    }
+}
+
+/// refuse `email` until `Unban`, results in how many sessions were disconnected
+#[derive(Message)]
+#[rtype(result = "usize")]
+pub struct Ban {
+    pub email: String,
+}
+
+impl Handler<Ban> for ServerSession {
+    type Result = usize;
+
+    fn handle(&mut self, msg: Ban, _ctx: &mut Self::Context) -> Self::Result {
+        info!("client: {} banned by an admin", msg.email);
+        let kicked = self.kick(&msg.email, "banned");
+        self.admin_bans.insert(msg.email);
+        kicked
+    }
+}
+
+/// lift a `Ban`, results in whether there was one, the configured bans stay
+#[derive(Message)]
+#[rtype(result = "bool")]
+pub struct Unban {
+    pub email: String,
+}
+
+impl Handler<Unban> for ServerSession {
+    type Result = bool;
+
+    fn handle(&mut self, msg: Unban, _ctx: &mut Self::Context) -> Self::Result {
+        info!("client: {} unbanned by an admin", msg.email);
+        self.admin_bans.remove(&msg.email)
+    }
+}
+
+/// send `text` to every logged in session, results in how many
+#[derive(Message)]
+#[rtype(result = "usize")]
+pub struct Broadcast {
+    pub text: String,
+}
+
+impl Handler<Broadcast> for ServerSession {
+    type Result = usize;
+
+    fn handle(&mut self, msg: Broadcast, _ctx: &mut Self::Context) -> Self::Result {
+        info!("notice to everyone: {}", msg.text);
+        let devices = self.clients.values().flatten().collect::<Vec<_>>();
+        for device in &devices {
+            device.do_send(Notice(msg.text.clone()));
+        }
+        devices.len()
+    }
+}
+
+impl Handler<Stop> for ServerSession {
+    type Result = ResponseActFuture<Self, ()>;
+
+    fn handle(&mut self, _msg: Stop, ctx: &mut Self::Context) -> Self::Result {
+        if self.shutting_down {
+            return Box::pin(fut::ready(()));
+        }
+        self.shutting_down = true;
+        info!("server shutting down, {} sessions", self.clients.len());
+
+        if let Some(incoming) = self.incoming.take() {
+            ctx.cancel_future(incoming);
+        }
+
+        let deadline = Instant::now() + SHUTDOWN_DEADLINE;
+        let closing = self
+            .clients
+            .values()
+            .flatten()
+            .chain(self.connecting.values())
+            .map(|client| {
+                client.send(Shutdown {
+                    reason: "server going down".to_string(),
+                    deadline,
+                })
+            })
+            .collect::<Vec<_>>();
+
+        // a little longer than the sessions get, so they can close their connections
+        let wait_until = deadline + Duration::from_secs(1);
+        Box::pin(
+            async move {
+                let closed =
+                    tokio::time::timeout_at(wait_until.into(), futures::future::join_all(closing))
+                        .await;
+                if closed.is_err() {
+                    warn!("sessions not closed before the shutdown deadline");
+                }
+            }
+            .into_actor(self)
+            .map(|_, _act, ctx| ctx.stop()),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bytes::Bytes;
+    use common::{
+        codec::FrameDecoder,
+        protocol::{ErrorCode, Frame},
+    };
+
+    use std::path::Path;
+
+    use super::*;
+    use crate::{
+        config::{Config, RateLimits},
+        sessions::SharedUsers,
+        store::{MemoryHistory, MemoryKeys, MemoryStore, MemoryUsers},
+    };
+
+    /// a session on memory stores and `users` serving `quic_server` with `policy`,
+    /// and its room registry
+    fn serve(
+        quic_server: s2n_quic::Server,
+        users: SharedUsers,
+        uploads: &Path,
+        policy: Policy,
+    ) -> (Addr<ServerSession>, Addr<RoomRegistry>) {
+        let stores = Stores {
+            messages: Box::new(MemoryStore::new()),
+            history: Box::new(MemoryHistory::new()),
+            keys: Box::new(MemoryKeys::new()),
+        };
+        let accounts = SyncArbiter::start(1, move || Accounts::new(Arc::clone(&users)));
+        let rooms = RoomRegistry::new().start();
+
+        let session = ServerSession::new(
+            quic_server,
+            stores,
+            Uploads::open(uploads, Config::default().limits.uploads()).unwrap(),
+            accounts,
+            rooms.clone(),
+            policy,
+            oneshot::channel().0,
+        );
+        (session.start(), rooms)
+    }
+
+    /// a session on memory stores with `policy`, listening on a port of its own,
+    /// and its room registry
+    fn start_session_with(
+        users: SharedUsers,
+        uploads: &Path,
+        policy: Policy,
+    ) -> (Addr<ServerSession>, Addr<RoomRegistry>) {
+        let quic_server = s2n_quic::Server::builder()
+            .with_io("127.0.0.1:0")
+            .unwrap()
+            .start()
+            .unwrap();
+        serve(quic_server, users, uploads, policy)
+    }
+
+    fn start_session(uploads: &Path) -> (Addr<ServerSession>, Addr<RoomRegistry>) {
+        start_session_with(users(), uploads, Config::default().policy())
+    }
+
+    fn users() -> SharedUsers {
+        Arc::new(std::sync::Mutex::new(Box::new(MemoryUsers::new())))
+    }
+
+    const CERTIFICATE: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/testdata/cert.pem");
+    const KEY: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/testdata/key.pem");
+
+    /// send `sent` on the control stream of a session with `rate_limit`, results in the frames
+    /// received until the server closed the connection, and the code it closed it with
+    async fn closed_after(rate_limit: RateLimits, sent: &[Bytes]) -> (Vec<Frame>, u64) {
+        let uploads = std::env::temp_dir().join(format!("uploads-test-{}", Ulid::new()));
+        let quic_server = s2n_quic::Server::builder()
+            .with_tls((Path::new(CERTIFICATE), Path::new(KEY)))
+            .unwrap()
+            .with_io("127.0.0.1:0")
+            .unwrap()
+            .start()
+            .unwrap();
+        let addr = quic_server.local_addr().unwrap();
+        let policy = Policy {
+            rate_limit,
+            ..Config::default().policy()
+        };
+        let (_session, _rooms) = serve(quic_server, users(), &uploads, policy);
+
+        let client = s2n_quic::Client::builder()
+            .with_tls(Path::new(CERTIFICATE))
+            .unwrap()
+            .with_io("127.0.0.1:0")
+            .unwrap()
+            .start()
+            .unwrap();
+        let mut conn = client
+            .connect(s2n_quic::client::Connect::new(addr).with_server_name("localhost"))
+            .await
+            .unwrap();
+        let mut stream = conn.open_bidirectional_stream().await.unwrap();
+        for bytes in sent {
+            stream.send(bytes.clone()).await.unwrap();
+        }
+
+        let mut decoder = FrameDecoder::new();
+        let mut frames = vec![];
+        let deadline = SHUTDOWN_DEADLINE + Duration::from_secs(5);
+        while let Ok(Ok(Some(bytes))) = tokio::time::timeout(deadline, stream.receive()).await {
+            decoder.extend(&bytes);
+            while let Some(frame) = decoder.next_frame().unwrap() {
+                frames.push(Frame::try_from(frame).unwrap());
+            }
+        }
+
+        let closed = tokio::time::timeout(deadline, conn.accept_bidirectional_stream())
+            .await
+            .expect("the server closed the connection");
+        let _ = std::fs::remove_dir_all(uploads);
+        match closed {
+            Err(s2n_quic::connection::Error::Application { error, .. }) => (frames, error.into()),
+            other => panic!("connection not closed by the server: {:?}", other.err()),
+        }
+    }
+
+    #[actix_rt::test]
+    async fn rate_limit_offenders_are_refused() {
+        let rate_limit = RateLimits {
+            message_burst: 1,
+            strikes: 1,
+            ..RateLimits::default()
+        };
+        let ping = Frame::Ping.to_bytes().unwrap();
+        let (frames, code) = closed_after(rate_limit, &[ping.clone(), ping]).await;
+
+        assert_eq!(code, u64::from(REFUSED_CLOSE_CODE));
+        assert!(matches!(frames[0], Frame::Pong), "{:?}", frames);
+        assert!(
+            matches!(
+                frames.last(),
+                Some(Frame::Error {
+                    code: ErrorCode::RateLimited,
+                    ..
+                })
+            ),
+            "{:?}",
+            frames
+        );
+        assert!(
+            !frames
+                .iter()
+                .any(|frame| matches!(frame, Frame::Shutdown { .. })),
+            "{:?}",
+            frames
+        );
+    }
+
+    #[actix_rt::test]
+    async fn protocol_violations_are_refused() {
+        // a frame of a protocol version nobody speaks
+        let garbage = Bytes::from_static(&[0xff, 0, 0, 0, 0]);
+        let (frames, code) = closed_after(RateLimits::default(), &[garbage]).await;
+
+        assert_eq!(code, u64::from(REFUSED_CLOSE_CODE));
+        assert!(
+            matches!(
+                frames[..],
+                [Frame::Error {
+                    code: ErrorCode::InvalidFrame,
+                    ..
+                }]
+            ),
+            "{:?}",
+            frames
+        );
+    }
+
+    #[actix_rt::test]
+    async fn only_accounts_are_queued_for_and_as_much_as_they_may() {
+        let uploads = std::env::temp_dir().join(format!("uploads-test-{}", Ulid::new()));
+        let users = users();
+        users
+            .lock()
+            .unwrap()
+            .insert("bob@x".into(), "hash".into())
+            .unwrap();
+        let mut policy = Config::default().policy();
+        policy.limits.max_queued = 2;
+        let (session, _rooms) = start_session_with(users, &uploads, policy);
+
+        let chat = |to: &str| Transfer {
+            id: Ulid::new(),
+            from: "alice@x".into(),
+            to: to.into(),
+            content: Bytes::from_static(b"hi"),
+        };
+        // the expected error by the name of its variant
+        let cases = [
+            ("no account", chat("mallory@x"), Some("UnknownRecipient")),
+            ("account offline", chat("bob@x"), None),
+            ("up to the limit", chat("bob@x"), None),
+            ("over the limit", chat("bob@x"), Some("QueueFull")),
+        ];
+
+        for (name, transfer, expected) in cases {
+            let result = session.send(transfer).await.unwrap();
+            match (result, expected) {
+                (Ok(Delivery::Queued), None) => {}
+                (Err(e), Some(expected)) => {
+                    assert!(
+                        format!("{:?}", e).starts_with(expected),
+                        "{}: {:?}",
+                        name,
+                        e
+                    )
+                }
+                (res, _) => panic!("{}: {:?}", name, res),
+            }
+        }
+        let _ = std::fs::remove_dir_all(uploads);
+    }
+
+    fn offer(from: &str, to: &str) -> FileOffer {
+        FileOffer {
+            id: Ulid::new(),
+            from: from.into(),
+            to: to.into(),
+            name: "a.txt".into(),
+            size: 3,
+            mime: "text/plain".into(),
+            sha256: [0; 32],
+        }
+    }
+
+    #[actix_rt::test]
+    async fn only_room_members_offer_files_to_the_room() {
+        let uploads = std::env::temp_dir().join(format!("uploads-test-{}", Ulid::new()));
+        let (session, rooms) = start_session(&uploads);
+        rooms
+            .send(JoinRoom {
+                room: "#room".into(),
+                email: "alice@x".into(),
+            })
+            .await
+            .unwrap();
+
+        let cases = [
+            ("member", offer("alice@x", "#room"), true),
+            ("not a member", offer("bob@x", "#room"), false),
+            ("room nobody is in", offer("alice@x", "#empty"), false),
+            ("direct", offer("bob@x", "alice@x"), true),
+        ];
+
+        for (name, offer, accepted) in cases {
+            let result = session.send(OfferFile(offer)).await.unwrap();
+            match accepted {
+                true => assert_eq!(result.ok(), Some(0), "{}", name),
+                false => assert!(
+                    matches!(result, Err(UploadError::NotRoomMember)),
+                    "{}: {:?}",
+                    name,
+                    result
+                ),
+            }
+        }
+        let _ = std::fs::remove_dir_all(uploads);
+    }
+
+    fn entry(content_len: usize) -> HistoryEntry {
+        HistoryEntry {
+            at: 0,
+            transfer: Transfer {
+                id: Ulid::new(),
+                from: "alice@x".into(),
+                to: "bob@x".into(),
+                content: Bytes::from(vec![0; content_len]),
+            },
+        }
+    }
+
+    /// the payload of a `Frame::History` of `entries`, what `MAX_FRAME_LEN` bounds
+    fn payload_len(entries: &[HistoryEntry]) -> usize {
+        Frame::History {
+            id: Ulid::new(),
+            entries: entries.to_vec(),
+        }
+        .to_bytes()
+        .map(|bytes| bytes.len() - codec::HEADER_LEN)
+        .unwrap_or(usize::MAX)
+    }
+
+    #[test]
+    fn history_reply_fits_in_a_frame() {
+        let third = codec::MAX_FRAME_LEN / 3;
+        // the entries, and which of them are kept
+        let cases: [(&str, Vec<HistoryEntry>, &[usize]); 4] = [
+            ("small", vec![entry(10), entry(20)], &[0, 1]),
+            (
+                "newest kept",
+                vec![entry(third), entry(third), entry(third), entry(10)],
+                &[1, 2, 3],
+            ),
+            (
+                "too large for any frame",
+                vec![entry(10), entry(codec::MAX_FRAME_LEN)],
+                &[0],
+            ),
+            ("none", vec![], &[]),
+        ];
+
+        for (name, entries, kept) in cases {
+            let fitting = fit_in_frame(entries.clone());
+            let ids = |entries: Vec<&HistoryEntry>| -> Vec<Ulid> {
+                entries.iter().map(|entry| entry.transfer.id).collect()
+            };
+            assert_eq!(
+                ids(fitting.iter().collect()),
+                ids(kept.iter().map(|&i| &entries[i]).collect()),
+                "{}",
+                name
+            );
+            assert!(payload_len(&fitting) <= codec::MAX_FRAME_LEN, "{}", name);
+        }
+    }
+
+    #[test]
+    fn history_reply_is_as_large_as_a_frame_can_be() {
+        // one entry that fills a payload to the byte, then one byte too many
+        let one = codec::MAX_FRAME_LEN - payload_len(&[entry(0)]);
+        let exact = vec![entry(one)];
+        assert_eq!(payload_len(&exact), codec::MAX_FRAME_LEN);
+        assert!(Frame::History {
+            id: Ulid::new(),
+            entries: exact.clone()
+        }
+        .to_bytes()
+        .is_ok());
+        assert_eq!(fit_in_frame(exact).len(), 1);
+        assert_eq!(fit_in_frame(vec![entry(one + 1)]).len(), 0);
+
+        // two entries that together fill a payload to the byte, then one byte too many
+        let both = codec::MAX_FRAME_LEN - payload_len(&[entry(0), entry(0)]);
+        let exact = vec![entry(both / 2), entry(both - both / 2)];
+        assert_eq!(payload_len(&exact), codec::MAX_FRAME_LEN);
+        assert_eq!(fit_in_frame(exact).len(), 2);
+
+        let over = vec![entry(both / 2), entry(both - both / 2 + 1)];
+        assert_eq!(fit_in_frame(over).len(), 1);
     }
 }
@@ -0,0 +1,549 @@
+use std::{
+    fs::{self, File, OpenOptions},
+    io::Write,
+    path::Path,
+};
+
+use bytes::Bytes;
+use common::{
+    codec::{self, CodecError, FrameDecoder},
+    crypto::KeyBundle,
+    protocol::{Frame, ProtocolError},
+    HistoryEntry, Transfer,
+};
+use log::{info, warn};
+use ulid::Ulid;
+
+use super::{
+    HistoryStore, KeyStore, MemoryHistory, MemoryKeys, MemoryStore, MemoryUsers, MessageStore,
+    StoreError, UserStore,
+};
+
+/// a push whose chat frame is the next frame of the log, as it was received
+const OP_PUSH: u8 = 1;
+const OP_TAKE: u8 = 2;
+
+/// offline queues kept in an append-only log, so they survive a restart
+///
+/// every record is a codec frame of `<op><payload>`, a push carries the recipient
+/// and is followed by the chat frame, a take carries the email whose queue was flushed.
+/// the chat frame is never nested, it may already be as large as a frame can be.
+/// the log is replayed and compacted when opened
+pub struct FileStore {
+    log: File,
+    queues: MemoryStore,
+}
+
+impl FileStore {
+    pub fn open(path: impl AsRef<Path>) -> Result<Self, StoreError> {
+        let path = path.as_ref();
+        let mut queues = MemoryStore::new();
+        let mut pending = vec![];
+
+        if path.exists() {
+            let mut decoder = FrameDecoder::new();
+            decoder.extend(&fs::read(path)?);
+
+            while let Some(record) = decoder.next_frame().map_err(corrupt)? {
+                let fields = codec::decode_fields(record).map_err(corrupt)?;
+                match fields.as_slice() {
+                    [op, recipient] if op.as_ref() == [OP_PUSH] => {
+                        // a push torn by a crash, the compaction below leaves it out
+                        let Some(chat) = decoder.next_frame().map_err(corrupt)? else {
+                            break;
+                        };
+                        queues.push(&utf8(recipient)?, transfer(chat)?)?;
+                    }
+                    [op, email] if op.as_ref() == [OP_TAKE] => {
+                        queues.take(&utf8(email)?)?;
+                    }
+                    _ => return Err(StoreError::Corrupt(ProtocolError::InvalidField)),
+                }
+            }
+
+            pending = queues.drain();
+        }
+
+        // compact: rewrite only what is still queued
+        let tmp = path.with_extension("compact");
+        {
+            let mut file = File::create(&tmp)?;
+            for (recipient, transfer) in &pending {
+                file.write_all(&push_record(recipient, transfer)?)?;
+            }
+            file.sync_all()?;
+        }
+        fs::rename(&tmp, path)?;
+        info!(
+            "offline store opened: {}, {} queued",
+            path.display(),
+            pending.len()
+        );
+
+        for (recipient, transfer) in pending {
+            queues.push(&recipient, transfer)?;
+        }
+
+        Ok(Self {
+            log: OpenOptions::new().append(true).open(path)?,
+            queues,
+        })
+    }
+}
+
+fn push_record(recipient: &str, transfer: &Transfer) -> Result<Vec<u8>, CodecError> {
+    let mut record = codec::encode_fields(&[&[OP_PUSH], recipient.as_bytes()])?.to_vec();
+    record.extend_from_slice(&Frame::Chat(transfer.clone()).to_bytes()?);
+
+    Ok(record)
+}
+
+/// the transfer of a chat frame read back from a log
+fn transfer(chat: Bytes) -> Result<Transfer, StoreError> {
+    match Frame::try_from(chat).map_err(StoreError::Corrupt)? {
+        Frame::Chat(transfer) => Ok(transfer),
+        _ => Err(StoreError::Corrupt(ProtocolError::InvalidField)),
+    }
+}
+
+fn utf8(field: &Bytes) -> Result<String, StoreError> {
+    String::from_utf8(field.to_vec()).map_err(|_| StoreError::Corrupt(ProtocolError::NotUTF8))
+}
+
+/// the frames of an append-only log, and where its last whole record ends
+struct LogReader {
+    decoder: FrameDecoder,
+    len: usize,
+    read: usize,
+    complete: usize,
+}
+
+impl LogReader {
+    fn open(path: &Path) -> Result<Self, StoreError> {
+        let bytes = fs::read(path)?;
+        let mut decoder = FrameDecoder::new();
+        decoder.extend(&bytes);
+
+        Ok(Self {
+            decoder,
+            len: bytes.len(),
+            read: 0,
+            complete: 0,
+        })
+    }
+
+    /// the next whole frame, `None` at the end of the log or before a frame cut short
+    fn next_frame(&mut self) -> Result<Option<Bytes>, StoreError> {
+        let frame = self.decoder.next_frame().map_err(corrupt)?;
+        if let Some(frame) = &frame {
+            self.read += frame.len();
+        }
+
+        Ok(frame)
+    }
+
+    /// the frames read so far make whole records
+    fn record_end(&mut self) {
+        self.complete = self.read;
+    }
+
+    /// cut off what follows the last whole record, a write torn by a crash,
+    /// so the records appended next are not read as part of it
+    fn truncate(self, path: &Path) -> Result<(), StoreError> {
+        if self.complete < self.len {
+            warn!(
+                "{}: cutting off {} bytes of a torn record",
+                path.display(),
+                self.len - self.complete
+            );
+            OpenOptions::new()
+                .write(true)
+                .open(path)?
+                .set_len(self.complete as u64)?;
+        }
+
+        Ok(())
+    }
+}
+
+fn corrupt(e: CodecError) -> StoreError {
+    StoreError::Corrupt(ProtocolError::Codec(e))
+}
+
+impl MessageStore for FileStore {
+    fn push(&mut self, recipient: &str, transfer: Transfer) -> Result<(), StoreError> {
+        self.log.write_all(&push_record(recipient, &transfer)?)?;
+        self.queues.push(recipient, transfer)
+    }
+
+    fn take(&mut self, email: &str) -> Result<Vec<Transfer>, StoreError> {
+        let queued = self.queues.take(email)?;
+        if !queued.is_empty() {
+            self.log
+                .write_all(&codec::encode_fields(&[&[OP_TAKE], email.as_bytes()])?)?;
+        }
+
+        Ok(queued)
+    }
+
+    fn queued(&self, email: &str) -> usize {
+        self.queues.queued(email)
+    }
+}
+
+/// conversations kept in an append-only log, replayed into memory when opened
+///
+/// every record is a codec frame of `<routed at>` followed by the chat frame, never nested
+/// so the largest chat fits. a record torn by a crash is cut off when opened
+pub struct FileHistory {
+    log: File,
+    conversations: MemoryHistory,
+}
+
+impl FileHistory {
+    pub fn open(path: impl AsRef<Path>) -> Result<Self, StoreError> {
+        let path = path.as_ref();
+        let mut conversations = MemoryHistory::new();
+        let mut count = 0;
+
+        if path.exists() {
+            let mut log = LogReader::open(path)?;
+
+            while let Some(record) = log.next_frame()? {
+                let fields = codec::decode_fields(record).map_err(corrupt)?;
+                let (at, chat) = match fields.as_slice() {
+                    [at] => match log.next_frame()? {
+                        Some(chat) => (at, chat),
+                        None => break,
+                    },
+                    _ => return Err(StoreError::Corrupt(ProtocolError::InvalidField)),
+                };
+                let at = <[u8; 8]>::try_from(at.as_ref())
+                    .map(u64::from_be_bytes)
+                    .map_err(|_| StoreError::Corrupt(ProtocolError::InvalidField))?;
+
+                conversations.record(HistoryEntry {
+                    at,
+                    transfer: transfer(chat)?,
+                })?;
+                log.record_end();
+                count += 1;
+            }
+            log.truncate(path)?;
+        }
+        info!("history opened: {}, {} entries", path.display(), count);
+
+        Ok(Self {
+            log: OpenOptions::new().create(true).append(true).open(path)?,
+            conversations,
+        })
+    }
+}
+
+impl HistoryStore for FileHistory {
+    fn record(&mut self, entry: HistoryEntry) -> Result<(), StoreError> {
+        let mut record = codec::encode_fields(&[&entry.at.to_be_bytes()])?.to_vec();
+        record.extend_from_slice(&Frame::Chat(entry.transfer.clone()).to_bytes()?);
+        self.log.write_all(&record)?;
+        self.conversations.record(entry)
+    }
+
+    fn history(&self, a: &str, b: &str, before: Option<Ulid>, limit: usize) -> Vec<HistoryEntry> {
+        self.conversations.history(a, b, before, limit)
+    }
+}
+
+/// accounts kept in an append-only log of `<email><password hash>` records,
+/// replayed into memory when opened, a record torn by a crash is cut off
+pub struct FileUsers {
+    log: File,
+    users: MemoryUsers,
+}
+
+impl FileUsers {
+    pub fn open(path: impl AsRef<Path>) -> Result<Self, StoreError> {
+        let path = path.as_ref();
+        let mut users = MemoryUsers::new();
+
+        if path.exists() {
+            let mut log = LogReader::open(path)?;
+
+            while let Some(record) = log.next_frame()? {
+                let fields = codec::decode_fields(record).map_err(corrupt)?;
+                let [email, hash] = fields.as_slice() else {
+                    return Err(StoreError::Corrupt(ProtocolError::InvalidField));
+                };
+                users.insert(utf8(email)?, utf8(hash)?)?;
+                log.record_end();
+            }
+            log.truncate(path)?;
+        }
+        info!("users opened: {}", path.display());
+
+        Ok(Self {
+            log: OpenOptions::new().create(true).append(true).open(path)?,
+            users,
+        })
+    }
+}
+
+impl UserStore for FileUsers {
+    fn password_hash(&self, email: &str) -> Option<String> {
+        self.users.password_hash(email)
+    }
+
+    fn insert(&mut self, email: String, password_hash: String) -> Result<(), StoreError> {
+        self.log.write_all(&codec::encode_fields(&[
+            email.as_bytes(),
+            password_hash.as_bytes(),
+        ])?)?;
+        self.log.sync_data()?;
+        self.users.insert(email, password_hash)
+    }
+}
+
+/// key bundles kept in an append-only log of `<email><identity><prekey>` records,
+/// replayed into memory when opened, the last record of a device wins.
+/// a record torn by a crash is cut off
+pub struct FileKeys {
+    log: File,
+    bundles: MemoryKeys,
+}
+
+impl FileKeys {
+    pub fn open(path: impl AsRef<Path>) -> Result<Self, StoreError> {
+        let path = path.as_ref();
+        let mut bundles = MemoryKeys::new();
+
+        if path.exists() {
+            let mut log = LogReader::open(path)?;
+
+            while let Some(record) = log.next_frame()? {
+                let fields = codec::decode_fields(record).map_err(corrupt)?;
+                let [email, identity, prekey] = fields.as_slice() else {
+                    return Err(StoreError::Corrupt(ProtocolError::InvalidField));
+                };
+                let (Ok(identity), Ok(prekey)) = (
+                    <[u8; 32]>::try_from(identity.as_ref()),
+                    <[u8; 32]>::try_from(prekey.as_ref()),
+                ) else {
+                    return Err(StoreError::Corrupt(ProtocolError::InvalidField));
+                };
+                bundles.publish(utf8(email)?, KeyBundle { identity, prekey })?;
+                log.record_end();
+            }
+            log.truncate(path)?;
+        }
+        info!("keys opened: {}", path.display());
+
+        Ok(Self {
+            log: OpenOptions::new().create(true).append(true).open(path)?,
+            bundles,
+        })
+    }
+}
+
+impl KeyStore for FileKeys {
+    fn bundles(&self, email: &str) -> Vec<KeyBundle> {
+        self.bundles.bundles(email)
+    }
+
+    fn publish(&mut self, email: String, bundle: KeyBundle) -> Result<(), StoreError> {
+        self.log.write_all(&codec::encode_fields(&[
+            email.as_bytes(),
+            &bundle.identity,
+            &bundle.prekey,
+        ])?)?;
+        self.log.sync_data()?;
+        self.bundles.publish(email, bundle)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use common::codec::{HEADER_LEN, MAX_FRAME_LEN};
+
+    use super::*;
+
+    /// a log path of its own in the temp directory, removed when dropped
+    struct TempLog(PathBuf);
+
+    impl TempLog {
+        fn new() -> Self {
+            Self(std::env::temp_dir().join(format!("store-test-{}.log", Ulid::new())))
+        }
+    }
+
+    impl Drop for TempLog {
+        fn drop(&mut self) {
+            let _ = fs::remove_file(&self.0);
+        }
+    }
+
+    fn transfer(to: &str, content: Vec<u8>) -> Transfer {
+        Transfer {
+            id: Ulid::new(),
+            from: "alice@x".into(),
+            to: to.into(),
+            content: Bytes::from(content),
+        }
+    }
+
+    #[test]
+    fn largest_chat_is_queued_across_a_restart() {
+        let log = TempLog::new();
+        // every field of a chat frame but the content, with their lengths
+        let overhead = 4 * 5 + 1 + 16 + "alice@x".len() + "bob@x".len();
+        let largest = transfer("bob@x", vec![7; MAX_FRAME_LEN - HEADER_LEN - overhead]);
+        assert_eq!(
+            Frame::Chat(largest.clone()).to_bytes().unwrap().len(),
+            MAX_FRAME_LEN
+        );
+
+        let mut store = FileStore::open(&log.0).unwrap();
+        store.push("bob@x", largest.clone()).unwrap();
+        store
+            .push("#room", transfer("#room", b"hi".to_vec()))
+            .unwrap();
+        drop(store);
+
+        let mut store = FileStore::open(&log.0).unwrap();
+        let queued = store.take("bob@x").unwrap();
+        assert_eq!(queued.len(), 1);
+        assert_eq!(queued[0].id, largest.id);
+        assert_eq!(queued[0].content, largest.content);
+        assert_eq!(store.take("#room").unwrap().len(), 1);
+    }
+
+    /// cut the last `len` bytes off the log, as a crash in the middle of a write would
+    fn tear(log: &TempLog, len: u64) {
+        let file = OpenOptions::new().write(true).open(&log.0).unwrap();
+        file.set_len(file.metadata().unwrap().len() - len).unwrap();
+    }
+
+    fn contents(entries: Vec<HistoryEntry>) -> Vec<Bytes> {
+        entries
+            .into_iter()
+            .map(|entry| entry.transfer.content)
+            .collect()
+    }
+
+    fn entry(content: &[u8]) -> HistoryEntry {
+        HistoryEntry {
+            at: 1,
+            transfer: transfer("bob@x", content.to_vec()),
+        }
+    }
+
+    #[test]
+    fn torn_history_record_is_cut_off() {
+        // inside the chat frame, between the two frames, inside the frame of the time
+        for len in [2, 58, 63] {
+            let log = TempLog::new();
+            let mut history = FileHistory::open(&log.0).unwrap();
+            history.record(entry(b"hi")).unwrap();
+            history.record(entry(b"torn")).unwrap();
+            drop(history);
+            tear(&log, len);
+
+            let mut history = FileHistory::open(&log.0).unwrap();
+            history.record(entry(b"after")).unwrap();
+            drop(history);
+
+            let history = FileHistory::open(&log.0).unwrap();
+            assert_eq!(
+                contents(history.history("alice@x", "bob@x", None, 10)),
+                [&b"hi"[..], &b"after"[..]],
+                "torn {} bytes",
+                len
+            );
+        }
+    }
+
+    #[test]
+    fn history_record_with_the_chat_inside_is_corrupt() {
+        let log = TempLog::new();
+        let chat = Frame::Chat(entry(b"hi").transfer).to_bytes().unwrap();
+        let record = codec::encode_fields(&[&1u64.to_be_bytes(), &chat]).unwrap();
+        fs::write(&log.0, record).unwrap();
+
+        assert!(matches!(
+            FileHistory::open(&log.0),
+            Err(StoreError::Corrupt(ProtocolError::InvalidField))
+        ));
+    }
+
+    #[test]
+    fn torn_user_record_is_cut_off() {
+        let log = TempLog::new();
+        let mut users = FileUsers::open(&log.0).unwrap();
+        users.insert("alice@x".into(), "hash".into()).unwrap();
+        users.insert("bob@x".into(), "hash".into()).unwrap();
+        drop(users);
+        tear(&log, 2);
+
+        let mut users = FileUsers::open(&log.0).unwrap();
+        assert_eq!(users.password_hash("bob@x"), None);
+        users.insert("carol@x".into(), "hash".into()).unwrap();
+        drop(users);
+
+        let users = FileUsers::open(&log.0).unwrap();
+        assert!(users.password_hash("alice@x").is_some());
+        assert!(users.password_hash("carol@x").is_some());
+    }
+
+    #[test]
+    fn torn_key_record_is_cut_off() {
+        let device = |identity: u8| KeyBundle {
+            identity: [identity; 32],
+            prekey: [0; 32],
+        };
+        let log = TempLog::new();
+        let mut keys = FileKeys::open(&log.0).unwrap();
+        keys.publish("alice@x".into(), device(1)).unwrap();
+        keys.publish("alice@x".into(), device(2)).unwrap();
+        drop(keys);
+        tear(&log, 2);
+
+        let mut keys = FileKeys::open(&log.0).unwrap();
+        assert_eq!(keys.bundles("alice@x"), [device(1)]);
+        keys.publish("alice@x".into(), device(3)).unwrap();
+        drop(keys);
+
+        let keys = FileKeys::open(&log.0).unwrap();
+        assert_eq!(keys.bundles("alice@x"), [device(1), device(3)]);
+    }
+
+    #[test]
+    fn torn_push_is_dropped() {
+        let log = TempLog::new();
+        let mut store = FileStore::open(&log.0).unwrap();
+        store
+            .push("bob@x", transfer("bob@x", b"hi".to_vec()))
+            .unwrap();
+        store
+            .push("bob@x", transfer("bob@x", b"torn".to_vec()))
+            .unwrap();
+        drop(store);
+
+        // cut inside the chat frame of the second push
+        tear(&log, 2);
+
+        let mut store = FileStore::open(&log.0).unwrap();
+        store
+            .push("bob@x", transfer("bob@x", b"after".to_vec()))
+            .unwrap();
+        drop(store);
+
+        let mut store = FileStore::open(&log.0).unwrap();
+        let contents: Vec<Bytes> = store
+            .take("bob@x")
+            .unwrap()
+            .into_iter()
+            .map(|transfer| transfer.content)
+            .collect();
+        assert_eq!(contents, [&b"hi"[..], &b"after"[..]]);
+    }
+}
@@ -0,0 +1,174 @@
+use std::collections::HashMap;
+
+use common::{
+    crypto::{self, KeyBundle},
+    HistoryEntry, Transfer,
+};
+use ulid::Ulid;
+
+use super::{conversation_key, HistoryStore, KeyStore, MessageStore, StoreError, UserStore};
+
+/// offline queues that live as long as the server process
+#[derive(Default)]
+pub struct MemoryStore {
+    queues: HashMap<String, Vec<Transfer>>,
+}
+
+impl MemoryStore {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// remove every queue at once, with their recipients
+    pub fn drain(&mut self) -> Vec<(String, Transfer)> {
+        self.queues
+            .drain()
+            .flat_map(|(recipient, queue)| queue.into_iter().map(move |t| (recipient.clone(), t)))
+            .collect()
+    }
+}
+
+impl MessageStore for MemoryStore {
+    fn push(&mut self, recipient: &str, transfer: Transfer) -> Result<(), StoreError> {
+        self.queues
+            .entry(recipient.to_string())
+            .or_default()
+            .push(transfer);
+
+        Ok(())
+    }
+
+    fn take(&mut self, email: &str) -> Result<Vec<Transfer>, StoreError> {
+        Ok(self.queues.remove(email).unwrap_or_default())
+    }
+
+    fn queued(&self, email: &str) -> usize {
+        self.queues.get(email).map_or(0, Vec::len)
+    }
+}
+
+/// conversations that live as long as the server process
+#[derive(Default)]
+pub struct MemoryHistory {
+    conversations: HashMap<(String, String), Vec<HistoryEntry>>,
+}
+
+impl MemoryHistory {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl HistoryStore for MemoryHistory {
+    fn record(&mut self, entry: HistoryEntry) -> Result<(), StoreError> {
+        self.conversations
+            .entry(conversation_key(&entry.transfer.from, &entry.transfer.to))
+            .or_default()
+            .push(entry);
+
+        Ok(())
+    }
+
+    fn history(&self, a: &str, b: &str, before: Option<Ulid>, limit: usize) -> Vec<HistoryEntry> {
+        let Some(entries) = self.conversations.get(&conversation_key(a, b)) else {
+            return vec![];
+        };
+
+        let end = before
+            .and_then(|id| entries.iter().position(|e| e.transfer.id == id))
+            .unwrap_or(entries.len());
+        let start = end.saturating_sub(limit);
+
+        entries[start..end].to_vec()
+    }
+}
+
+/// accounts that live as long as the server process
+#[derive(Default)]
+pub struct MemoryUsers {
+    users: HashMap<String, String>,
+}
+
+impl MemoryUsers {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl UserStore for MemoryUsers {
+    fn password_hash(&self, email: &str) -> Option<String> {
+        self.users.get(email).cloned()
+    }
+
+    fn insert(&mut self, email: String, password_hash: String) -> Result<(), StoreError> {
+        self.users.insert(email, password_hash);
+
+        Ok(())
+    }
+}
+
+/// key bundles that live as long as the server process
+#[derive(Default)]
+pub struct MemoryKeys {
+    /// by email, oldest published first
+    bundles: HashMap<String, Vec<KeyBundle>>,
+}
+
+impl MemoryKeys {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl KeyStore for MemoryKeys {
+    fn bundles(&self, email: &str) -> Vec<KeyBundle> {
+        self.bundles.get(email).cloned().unwrap_or_default()
+    }
+
+    fn publish(&mut self, email: String, bundle: KeyBundle) -> Result<(), StoreError> {
+        let devices = self.bundles.entry(email).or_default();
+        devices.retain(|device| device.identity != bundle.identity);
+        devices.push(bundle);
+        if devices.len() > crypto::MAX_DEVICES {
+            devices.remove(0);
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn device(identity: u8, prekey: u8) -> KeyBundle {
+        KeyBundle {
+            identity: [identity; 32],
+            prekey: [prekey; 32],
+        }
+    }
+
+    #[test]
+    fn keys_are_kept_per_device() {
+        let mut keys = MemoryKeys::new();
+        keys.publish("alice@x".into(), device(1, 1)).unwrap();
+        keys.publish("alice@x".into(), device(2, 1)).unwrap();
+        // a new prekey of the first device
+        keys.publish("alice@x".into(), device(1, 2)).unwrap();
+
+        assert_eq!(keys.bundles("alice@x"), [device(2, 1), device(1, 2)]);
+        assert!(keys.bundles("bob@x").is_empty());
+    }
+
+    #[test]
+    fn device_that_published_longest_ago_is_forgotten() {
+        let mut keys = MemoryKeys::new();
+        for identity in 0..=crypto::MAX_DEVICES as u8 {
+            keys.publish("alice@x".into(), device(identity, 0)).unwrap();
+        }
+
+        let bundles = keys.bundles("alice@x");
+        assert_eq!(bundles.len(), crypto::MAX_DEVICES);
+        assert_eq!(bundles[0], device(1, 0));
+    }
+}
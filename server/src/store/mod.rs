@@ -0,0 +1,107 @@
+mod file;
+mod memory;
+mod uploads;
+
+use std::fmt::Display;
+
+use common::{
+    codec::CodecError, crypto::KeyBundle, is_room, protocol::ProtocolError, HistoryEntry, Transfer,
+};
+use ulid::Ulid;
+
+pub use file::{FileHistory, FileKeys, FileStore, FileUsers};
+pub use memory::{MemoryHistory, MemoryKeys, MemoryStore, MemoryUsers};
+pub use uploads::{UploadError, UploadLimits, Uploads};
+
+/// keeps `Transfer`s for recipients that are offline, until they log in
+pub trait MessageStore {
+    /// queue a transfer for `recipient`, which is `transfer.to` unless it went to a room
+    fn push(&mut self, recipient: &str, transfer: Transfer) -> Result<(), StoreError>;
+
+    /// remove and return everything queued for `email`, oldest first
+    fn take(&mut self, email: &str) -> Result<Vec<Transfer>, StoreError>;
+
+    /// how many transfers are queued for `email`
+    fn queued(&self, email: &str) -> usize;
+}
+
+/// every routed `Transfer`, so conversations can be scrolled back
+pub trait HistoryStore {
+    fn record(&mut self, entry: HistoryEntry) -> Result<(), StoreError>;
+
+    /// up to `limit` entries between `a` and `b` routed before the entry `before`,
+    /// or the latest ones when `before` is `None`, oldest first
+    fn history(&self, a: &str, b: &str, before: Option<Ulid>, limit: usize) -> Vec<HistoryEntry>;
+}
+
+/// registered accounts and their argon2 password hashes (PHC strings)
+pub trait UserStore {
+    fn password_hash(&self, email: &str) -> Option<String>;
+
+    /// add a new account, callers check it does not exist yet
+    fn insert(&mut self, email: String, password_hash: String) -> Result<(), StoreError>;
+}
+
+/// the key bundles of every device of an email, for end-to-end encryption.
+/// a device is told apart by its identity key
+pub trait KeyStore {
+    /// oldest published first
+    fn bundles(&self, email: &str) -> Vec<KeyBundle>;
+
+    /// replaces what the device of `bundle.identity` published before, or adds the device,
+    /// forgetting the one that published longest ago past `crypto::MAX_DEVICES`
+    fn publish(&mut self, email: String, bundle: KeyBundle) -> Result<(), StoreError>;
+}
+
+/// what `ServerSession` keeps, in memory or in the data directory
+pub struct Stores {
+    pub messages: Box<dyn MessageStore>,
+    pub history: Box<dyn HistoryStore>,
+    pub keys: Box<dyn KeyStore>,
+}
+
+/// both participants, in the same order whoever sent,
+/// a room conversation is keyed by the room alone
+pub(crate) fn conversation_key(a: &str, b: &str) -> (String, String) {
+    if is_room(a) {
+        (a.to_string(), String::new())
+    } else if is_room(b) {
+        (b.to_string(), String::new())
+    } else if a <= b {
+        (a.to_string(), b.to_string())
+    } else {
+        (b.to_string(), a.to_string())
+    }
+}
+
+#[derive(Debug)]
+pub enum StoreError {
+    Io(std::io::Error),
+    Corrupt(ProtocolError),
+    /// a record too large to be written
+    Encode(CodecError),
+}
+
+impl From<CodecError> for StoreError {
+    fn from(value: CodecError) -> Self {
+        StoreError::Encode(value)
+    }
+}
+
+impl From<std::io::Error> for StoreError {
+    fn from(value: std::io::Error) -> Self {
+        StoreError::Io(value)
+    }
+}
+
+impl Display for StoreError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            StoreError::Io(e) => write!(f, "store io error: {}", e),
+            StoreError::Corrupt(e) => write!(f, "store corrupt: {}", e),
+            StoreError::Encode(e) => write!(f, "store encode failed: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for StoreError {}
@@ -0,0 +1,423 @@
+use std::{
+    collections::HashMap,
+    fmt::Display,
+    fs, io,
+    path::{Path, PathBuf},
+    time::{Duration, SystemTime},
+};
+
+use common::FileOffer;
+use log::{info, warn};
+use ulid::Ulid;
+
+/// files sent through the server, kept in a directory until every recipient downloaded them
+///
+/// a file is written to `<id>.part` while uploading and renamed to `<id>` once it matched its
+/// offer. offers live in memory only, a part left by an earlier run is resumed when the same
+/// offer comes again. files not written to for `UploadLimits::expiry` are removed by `expire`
+pub struct Uploads {
+    dir: PathBuf,
+    limits: UploadLimits,
+    files: HashMap<Ulid, Upload>,
+}
+
+/// how much `Uploads` keeps, and for how long, see `config::Limits`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct UploadLimits {
+    /// largest file offered
+    pub max_size: u64,
+    /// bytes of the files of one sender, uploading or waiting to be downloaded
+    pub max_per_sender: u64,
+    pub expiry: Duration,
+}
+
+struct Upload {
+    offer: FileOffer,
+    complete: bool,
+    /// who has yet to download it, known once complete
+    recipients: Vec<String>,
+    /// offered, uploaded from or completed, it expires counting from then
+    touched: SystemTime,
+}
+
+impl Uploads {
+    pub fn open(dir: impl AsRef<Path>, limits: UploadLimits) -> io::Result<Self> {
+        let dir = dir.as_ref();
+        fs::create_dir_all(dir)?;
+        info!("uploads kept in {}, {:?}", dir.display(), limits);
+
+        Ok(Self {
+            dir: dir.to_path_buf(),
+            limits,
+            files: HashMap::new(),
+        })
+    }
+
+    /// offers from now on are held to `limits`, uploads in flight go on
+    pub fn set_limits(&mut self, limits: UploadLimits) {
+        self.limits = limits;
+    }
+
+    pub fn part_path(&self, id: Ulid) -> PathBuf {
+        self.dir.join(format!("{}.part", id))
+    }
+
+    fn path(&self, id: Ulid) -> PathBuf {
+        self.dir.join(id.to_string())
+    }
+
+    fn part_len(&self, id: Ulid) -> u64 {
+        fs::metadata(self.part_path(id))
+            .map(|m| m.len())
+            .unwrap_or(0)
+    }
+
+    /// take an offer, or the same one again to resume it, results in the offset to upload from
+    pub fn offer(&mut self, offer: FileOffer) -> Result<u64, UploadError> {
+        if offer.size > self.limits.max_size {
+            return Err(UploadError::TooLarge(self.limits.max_size));
+        }
+
+        match self.files.get(&offer.id) {
+            Some(upload) if upload.offer != offer => Err(UploadError::Conflict),
+            Some(upload) if upload.complete => Ok(offer.size),
+            Some(_) => Ok(self.part_len(offer.id).min(offer.size)),
+            None => {
+                if self.sent_by(&offer.from) + offer.size > self.limits.max_per_sender {
+                    return Err(UploadError::QuotaExceeded(self.limits.max_per_sender));
+                }
+
+                let offset = self.part_len(offer.id).min(offer.size);
+                self.files.insert(
+                    offer.id,
+                    Upload {
+                        offer,
+                        complete: false,
+                        recipients: vec![],
+                        touched: SystemTime::now(),
+                    },
+                );
+                Ok(offset)
+            }
+        }
+    }
+
+    /// bytes of the files `from` offered that are still kept
+    fn sent_by(&self, from: &str) -> u64 {
+        self.files
+            .values()
+            .filter(|upload| upload.offer.from == from)
+            .map(|upload| upload.offer.size)
+            .sum()
+    }
+
+    pub fn offer_of(&self, id: Ulid) -> Option<FileOffer> {
+        self.files.get(&id).map(|upload| upload.offer.clone())
+    }
+
+    /// where `from` writes the upload `id` from `offset` on, and the size it must end at
+    pub fn upload(
+        &mut self,
+        id: Ulid,
+        from: &str,
+        offset: u64,
+    ) -> Result<(PathBuf, u64), UploadError> {
+        let part_len = self.part_len(id);
+        let upload = self.files.get_mut(&id).ok_or(UploadError::Unknown)?;
+        if upload.offer.from != from {
+            return Err(UploadError::NotYours);
+        }
+        if upload.complete || offset > part_len {
+            return Err(UploadError::Conflict);
+        }
+        upload.touched = SystemTime::now();
+        let size = upload.offer.size;
+
+        Ok((self.part_path(id), size))
+    }
+
+    /// the upload `id` was written with the checksum `sha256`,
+    /// if it matches the offer it can be downloaded by `recipients`
+    pub fn complete(
+        &mut self,
+        id: Ulid,
+        sha256: [u8; 32],
+        recipients: Vec<String>,
+    ) -> Result<FileOffer, UploadError> {
+        let part = self.part_path(id);
+        let path = self.path(id);
+        let len = self.part_len(id);
+        let upload = self.files.get_mut(&id).ok_or(UploadError::Unknown)?;
+
+        if len != upload.offer.size || sha256 != upload.offer.sha256 {
+            warn!("upload {} does not match its offer, dropped", id);
+            self.files.remove(&id);
+            fs::remove_file(part)?;
+            return Err(UploadError::Mismatch);
+        }
+
+        fs::rename(part, path)?;
+        upload.complete = true;
+        upload.recipients = recipients;
+        upload.touched = SystemTime::now();
+
+        Ok(upload.offer.clone())
+    }
+
+    /// forget the upload `id` and what was received of it
+    pub fn cancel(&mut self, id: Ulid) -> Result<(), UploadError> {
+        if self.files.remove(&id).is_some() {
+            info!("upload {} cancelled", id);
+            match fs::remove_file(self.part_path(id)) {
+                // nothing of it was received
+                Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+                res => res?,
+            }
+        }
+
+        Ok(())
+    }
+
+    /// the file `email` is to download, and its offer
+    pub fn download(&self, id: Ulid, email: &str) -> Result<(PathBuf, FileOffer), UploadError> {
+        let upload = self.files.get(&id).ok_or(UploadError::Unknown)?;
+        if !upload.complete {
+            return Err(UploadError::Unknown);
+        }
+        if !upload.recipients.iter().any(|r| r == email) {
+            return Err(UploadError::NotYours);
+        }
+
+        Ok((self.path(id), upload.offer.clone()))
+    }
+
+    /// `email` has the whole file, it is removed once every recipient has it
+    pub fn downloaded(&mut self, id: Ulid, email: &str) {
+        let Some(upload) = self.files.get_mut(&id) else {
+            return;
+        };
+        upload.recipients.retain(|r| r != email);
+
+        if upload.complete && upload.recipients.is_empty() {
+            info!("upload {} downloaded by everyone, removed", id);
+            self.files.remove(&id);
+            if let Err(e) = fs::remove_file(self.path(id)) {
+                warn!("remove upload {} failed: {}", id, e);
+            }
+        }
+    }
+
+    /// remove the uploads not written to for `UploadLimits::expiry`, whoever has yet to download
+    /// them, and the files as old an earlier run left, results in how many uploads were removed
+    pub fn expire(&mut self) -> usize {
+        let now = SystemTime::now();
+        let expired = |touched: SystemTime| {
+            now.duration_since(touched).unwrap_or_default() >= self.limits.expiry
+        };
+
+        let stale: Vec<Ulid> = self
+            .files
+            .iter()
+            .filter(|(_, upload)| expired(upload.touched))
+            .map(|(id, _)| *id)
+            .collect();
+        for id in &stale {
+            info!("upload {} expired, removed", id);
+            self.files.remove(id);
+            for path in [self.part_path(*id), self.path(*id)] {
+                if let Err(e) = fs::remove_file(path) {
+                    if e.kind() != io::ErrorKind::NotFound {
+                        warn!("remove upload {} failed: {}", id, e);
+                    }
+                }
+            }
+        }
+
+        let entries = match fs::read_dir(&self.dir) {
+            Ok(entries) => entries,
+            Err(e) => {
+                warn!("read {} failed: {}", self.dir.display(), e);
+                return stale.len();
+            }
+        };
+        for entry in entries.flatten() {
+            let name = entry.file_name();
+            let id = name.to_string_lossy();
+            let known = Ulid::from_string(id.trim_end_matches(".part"))
+                .is_ok_and(|id| self.files.contains_key(&id));
+            let old = entry
+                .metadata()
+                .and_then(|metadata| metadata.modified())
+                .is_ok_and(expired);
+            if !known && old {
+                info!("{} left by an earlier run expired, removed", id);
+                if let Err(e) = fs::remove_file(entry.path()) {
+                    warn!("remove {} failed: {}", id, e);
+                }
+            }
+        }
+
+        stale.len()
+    }
+
+    /// offers of the files `email` has yet to download
+    pub fn pending(&self, email: &str) -> Vec<FileOffer> {
+        self.files
+            .values()
+            .filter(|upload| upload.complete && upload.recipients.iter().any(|r| r == email))
+            .map(|upload| upload.offer.clone())
+            .collect()
+    }
+}
+
+#[derive(Debug)]
+pub enum UploadError {
+    /// carries the limit
+    TooLarge(u64),
+    /// the sender's files kept would be over the limit it carries
+    QuotaExceeded(u64),
+    Unknown,
+    NotYours,
+    /// an offer with the same id but other metadata, or an offset past what was received
+    Conflict,
+    Mismatch,
+    /// offered to a room the sender is not in
+    NotRoomMember,
+    Io(io::Error),
+}
+
+impl From<io::Error> for UploadError {
+    fn from(value: io::Error) -> Self {
+        UploadError::Io(value)
+    }
+}
+
+impl Display for UploadError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            UploadError::TooLarge(max) => write!(f, "file larger than {} bytes", max),
+            UploadError::QuotaExceeded(max) => {
+                write!(f, "files not yet downloaded over {} bytes", max)
+            }
+            UploadError::Unknown => write!(f, "unknown file"),
+            UploadError::NotYours => write!(f, "file not for this account"),
+            UploadError::Conflict => write!(f, "file offered differently before"),
+            UploadError::Mismatch => write!(f, "file does not match its offer"),
+            UploadError::NotRoomMember => write!(f, "not a member of the room"),
+            UploadError::Io(e) => write!(f, "file io: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for UploadError {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// an uploads directory of its own in the temp directory, removed when dropped
+    struct TempUploads(PathBuf);
+
+    impl TempUploads {
+        fn open(limits: UploadLimits) -> (Self, Uploads) {
+            let dir = std::env::temp_dir().join(format!("uploads-test-{}", Ulid::new()));
+            let uploads = Uploads::open(&dir, limits).unwrap();
+            (Self(dir), uploads)
+        }
+    }
+
+    impl Drop for TempUploads {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.0);
+        }
+    }
+
+    fn limits(expiry: Duration) -> UploadLimits {
+        UploadLimits {
+            max_size: 10,
+            max_per_sender: 20,
+            expiry,
+        }
+    }
+
+    fn offer(from: &str, size: u64) -> FileOffer {
+        FileOffer {
+            id: Ulid::new(),
+            from: from.into(),
+            to: "bob@x".into(),
+            name: "a.txt".into(),
+            size,
+            mime: "text/plain".into(),
+            sha256: [0; 32],
+        }
+    }
+
+    #[test]
+    fn senders_keep_no_more_than_their_quota() {
+        let (_dir, mut uploads) = TempUploads::open(limits(Duration::from_secs(60)));
+        let first = offer("alice@x", 10);
+        uploads.offer(first.clone()).unwrap();
+
+        // the expected error by the name of its variant
+        let cases = [
+            ("too large", offer("alice@x", 11), Some("TooLarge")),
+            ("up to the quota", offer("alice@x", 10), None),
+            ("over the quota", offer("alice@x", 1), Some("QuotaExceeded")),
+            ("offered again", first.clone(), None),
+            ("another sender", offer("carol@x", 10), None),
+        ];
+
+        for (name, offer, expected) in cases {
+            match (uploads.offer(offer), expected) {
+                (Ok(_), None) => {}
+                (Err(e), Some(expected)) => {
+                    assert!(
+                        format!("{:?}", e).starts_with(expected),
+                        "{}: {:?}",
+                        name,
+                        e
+                    )
+                }
+                (res, _) => panic!("{}: {:?}", name, res),
+            }
+        }
+
+        // what was kept of a cancelled upload counts no more
+        uploads.cancel(first.id).unwrap();
+        assert!(uploads.offer(offer("alice@x", 10)).is_ok());
+    }
+
+    #[test]
+    fn uploads_expire_when_not_written_to() {
+        let cases = [
+            ("fresh", Duration::from_secs(60), 1),
+            ("stale", Duration::ZERO, 0),
+        ];
+
+        for (name, expiry, kept) in cases {
+            let (dir, mut uploads) = TempUploads::open(limits(expiry));
+            let part = offer("alice@x", 3);
+            uploads.offer(part.clone()).unwrap();
+            fs::write(uploads.part_path(part.id), b"a").unwrap();
+
+            let mut complete = offer("alice@x", 3);
+            let complete_part = uploads.part_path(complete.id);
+            fs::write(&complete_part, b"abc").unwrap();
+            complete.sha256 = common::sha256_file(&complete_part).unwrap();
+            uploads.offer(complete.clone()).unwrap();
+            uploads
+                .complete(complete.id, complete.sha256, vec!["bob@x".into()])
+                .unwrap();
+
+            // left by an earlier run, offers are not kept across restarts
+            let left = dir.0.join(format!("{}.part", Ulid::new()));
+            fs::write(&left, b"a").unwrap();
+
+            assert_eq!(uploads.expire(), 2 - kept * 2, "{}", name);
+            assert_eq!(uploads.pending("bob@x").len(), kept, "{}", name);
+            assert_eq!(uploads.offer_of(part.id).is_some(), kept == 1, "{}", name);
+            let files = fs::read_dir(&dir.0).unwrap().count();
+            assert_eq!(files, kept * 3, "{}", name);
+        }
+    }
+}
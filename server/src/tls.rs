@@ -0,0 +1,87 @@
+use common::is_email;
+use log::{info, warn};
+use s2n_quic::provider::{
+    event::{events, ConnectionInfo, ConnectionMeta, Subscriber},
+    tls::default::callbacks::VerifyHostNameCallback,
+};
+use x509_parser::{certificate::X509Certificate, extensions::GeneralName, prelude::FromDer};
+
+/// the identity a client certificate proved, kept per connection
+///
+/// query it with `Connection::query_event_context(|id: &CertificateIdentity| ...)`
+#[derive(Default, Clone, Debug)]
+pub struct CertificateIdentity(pub Option<String>);
+
+/// reads the email out of the client certificate once the handshake is done
+pub struct ClientCertificates;
+
+impl Subscriber for ClientCertificates {
+    type ConnectionContext = CertificateIdentity;
+
+    fn create_connection_context(
+        &mut self,
+        _meta: &ConnectionMeta,
+        _info: &ConnectionInfo,
+    ) -> Self::ConnectionContext {
+        CertificateIdentity::default()
+    }
+
+    fn on_tls_exporter_ready(
+        &mut self,
+        context: &mut Self::ConnectionContext,
+        _meta: &ConnectionMeta,
+        event: &events::TlsExporterReady,
+    ) {
+        let chain = match event.session.peer_cert_chain_der() {
+            Ok(chain) => chain,
+            Err(_) => return,
+        };
+
+        if let Some(leaf) = chain.first() {
+            context.0 = certificate_email(leaf);
+            info!("client certificate identity: {:?}", context.0);
+        }
+    }
+}
+
+/// the SAN email, or the subject email, or the subject common name if it is an email
+pub fn certificate_email(der: &[u8]) -> Option<String> {
+    let (_, cert) = match X509Certificate::from_der(der) {
+        Ok(cert) => cert,
+        Err(e) => {
+            warn!("parse client certificate failed: {}", e);
+            return None;
+        }
+    };
+
+    if let Ok(Some(san)) = cert.subject_alternative_name() {
+        for name in &san.value.general_names {
+            if let GeneralName::RFC822Name(email) = name {
+                return Some(email.to_string());
+            }
+        }
+    }
+
+    let subject = cert.subject();
+    let email = subject
+        .iter_email()
+        .find_map(|attr| attr.as_str().ok())
+        .or_else(|| {
+            subject
+                .iter_common_name()
+                .filter_map(|attr| attr.as_str().ok())
+                .find(|name| is_email(name))
+        });
+
+    email.map(str::to_string)
+}
+
+/// the chain is checked against the configured CA by s2n-tls,
+/// the identity is taken from the certificate afterwards, so any name is fine here
+pub struct AcceptAnyName;
+
+impl VerifyHostNameCallback for AcceptAnyName {
+    fn verify_host_name(&self, _host_name: &str) -> bool {
+        true
+    }
+}